@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, HashSet};
+
+/// Aliases defined with the `alias` builtin, by name. A command named after an alias is replaced
+/// with the alias' value.
+#[derive(Debug, Default)]
+pub struct Aliases {
+  aliases: BTreeMap<String, String>,
+}
+
+impl Aliases {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn get(&self, name: &str) -> Option<&String> {
+    self.aliases.get(name)
+  }
+
+  pub fn set(&mut self, name: &str, value: &str) {
+    self.aliases.insert(name.to_string(), value.to_string());
+  }
+
+  pub fn remove(&mut self, name: &str) -> Option<String> {
+    self.aliases.remove(name)
+  }
+
+  pub fn clear(&mut self) {
+    self.aliases.clear();
+  }
+
+  /// Every alias as the `alias` command defining it, like `alias ll='ls -la'`, sorted by name
+  pub fn list(&self) -> Vec<String> {
+    self
+      .aliases
+      .iter()
+      .map(|(name, value)| definition(name, value))
+      .collect()
+  }
+
+  /// Replaces the first word of each command of a line (the line's and the ones following a `|`)
+  /// with the value of the alias it names. The value's own first word is expanded in turn, except
+  /// for an alias already being expanded there, so `alias ls='ls -F'` doesn't loop. Quoted or
+  /// escaped words never name an alias.
+  pub fn expand_line(&self, line: &str) -> String {
+    let mut line = line.to_string();
+    if self.aliases.is_empty() {
+      return line;
+    }
+
+    // Aliases expanded in the text up to `expansion_end`, which came from their values
+    let mut expanding: HashSet<String> = HashSet::new();
+    let mut expansion_end = 0;
+    let mut position = 0;
+
+    loop {
+      if position >= expansion_end {
+        expanding.clear();
+      }
+
+      loop {
+        let rest = &line[position..];
+        let start = position + (rest.len() - rest.trim_start().len());
+        let end = line[start..]
+          .find(|c: char| c.is_whitespace() || c == '|')
+          .map_or(line.len(), |length| start + length);
+        let word = &line[start..end];
+
+        let Some(value) = self.aliases.get(word).filter(|_| !expanding.contains(word)) else {
+          break;
+        };
+        expanding.insert(word.to_string());
+        expansion_end = match expansion_end > start {
+          true => (expansion_end + value.len()).saturating_sub(word.len()),
+          false => start + value.len(),
+        };
+        line.replace_range(start..end, value);
+      }
+
+      match next_pipe(&line, position) {
+        // `|&` pipes stderr too, the next command follows the `&`
+        Some(pipe) if line[pipe + 1..].starts_with('&') => position = pipe + 2,
+        Some(pipe) => position = pipe + 1,
+        None => return line,
+      }
+    }
+  }
+}
+
+/// Whether a name can be given to an alias, it must read back as a single unquoted word
+pub fn is_valid_alias_name(name: &str) -> bool {
+  !name.is_empty()
+    && !name
+      .chars()
+      .any(|c| c.is_whitespace() || "/$`=|&;()<>'\"\\".contains(c))
+}
+
+/// `alias` command defining an alias, the value always single quoted as bash prints it
+pub fn definition(name: &str, value: &str) -> String {
+  format!("alias {}='{}'", name, value.replace('\'', "'\\''"))
+}
+
+/// Position of the first unquoted, unescaped `|` from `position` on
+fn next_pipe(line: &str, position: usize) -> Option<usize> {
+  let mut single_quoted = false;
+  let mut double_quoted = false;
+  let mut escaped = false;
+
+  for (index, char) in line[position..].char_indices() {
+    match char {
+      _ if escaped => escaped = false,
+      '\\' if !single_quoted => escaped = true,
+      '\'' if !double_quoted => single_quoted = !single_quoted,
+      '"' if !single_quoted => double_quoted = !double_quoted,
+      '|' if !single_quoted && !double_quoted => return Some(position + index),
+      _ => {}
+    }
+  }
+
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_expand_line() {
+    let mut aliases = Aliases::new();
+    aliases.set("ll", "ls -la");
+    aliases.set("ls", "ls -F");
+    aliases.set("g", "grep");
+    aliases.set("loop", "echo a | loop");
+
+    assert_eq!(aliases.expand_line("ll /tmp"), "ls -F -la /tmp");
+    assert_eq!(
+      aliases.expand_line("  ls | g x |& g y"),
+      "  ls -F | grep x |& grep y"
+    );
+    assert_eq!(
+      aliases.expand_line("echo ll | 'g' \\| g"),
+      "echo ll | 'g' \\| g"
+    );
+    assert_eq!(aliases.expand_line("echo 'a | g'"), "echo 'a | g'");
+    assert_eq!(
+      aliases.expand_line("loop | loop"),
+      "echo a | loop | echo a | loop"
+    );
+  }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy)]
 pub enum AnsiCode {
   /// Clear entire screen
@@ -20,6 +21,10 @@ pub enum AnsiCode {
   MoveCursorLeft,
   MoveCursorRight,
   BEL,
+  /// Bold text, until reset
+  Bold,
+  /// Reset colors and text attributes
+  Reset,
 }
 
 impl AnsiCode {
@@ -38,6 +43,8 @@ impl AnsiCode {
       AnsiCode::MoveCursorRight => "\x1b[C",
       AnsiCode::CRLF => "\r\n",
       AnsiCode::BEL => "\x07",
+      AnsiCode::Bold => "\x1b[1m",
+      AnsiCode::Reset => "\x1b[0m",
     }
   }
 
@@ -46,7 +53,7 @@ impl AnsiCode {
     self.as_str().as_bytes()
   }
 
-  pub fn write(&self) -> () {
+  pub fn write(&self) {
     print!("{}", self.as_str());
   }
 }
@@ -56,3 +63,41 @@ impl std::fmt::Display for AnsiCode {
     write!(f, "{}", self.as_str())
   }
 }
+
+/// Foreground colors
+#[derive(Debug, Clone, Copy)]
+pub enum Color {
+  Red,
+  Green,
+  Yellow,
+  Blue,
+  Magenta,
+  Cyan,
+}
+
+impl Color {
+  /// Get the ANSI escape sequence as a string
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Color::Red => "\x1b[31m",
+      Color::Green => "\x1b[32m",
+      Color::Yellow => "\x1b[33m",
+      Color::Blue => "\x1b[34m",
+      Color::Magenta => "\x1b[35m",
+      Color::Cyan => "\x1b[36m",
+    }
+  }
+
+  /// Wraps the text in this color, resetting it afterwards
+  pub fn paint<T: AsRef<str>>(&self, text: T) -> String {
+    format!("{}{}{}", self.as_str(), text.as_ref(), AnsiCode::Reset)
+  }
+}
+
+/// Whether output written to the file descriptor should be colored: it has to be a terminal, and
+/// colors must not be turned off with a non empty `NO_COLOR` (see https://no-color.org)
+pub fn colors_enabled(fd: i32) -> bool {
+  let no_color = std::env::var("NO_COLOR").is_ok_and(|value| !value.is_empty());
+  // SAFETY: isatty only inspects the file descriptor
+  !no_color && unsafe { libc::isatty(fd) } == 1
+}
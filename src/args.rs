@@ -1,46 +1,149 @@
+use crate::arithmetic;
+use crate::context::ShellContext;
+use crate::error::Error;
 use crate::utils;
-use crate::writer::Redirection;
+use crate::variables::{parse_assignment, Variables};
+use crate::writer::{InputRedirection, Redirection};
+use crate::Result;
+use std::env;
+use std::iter::Peekable;
+use std::mem;
+use std::process::{self, Stdio};
+use std::str::Chars;
 
 const SPACE: char = ' ';
 const SINGLE_QUOTE: char = '\'';
 const DOUBLE_QUOTE: char = '\"';
 const ESCAPE: char = '\\';
+const DOLLAR: char = '$';
+const BACKTICK: char = '`';
+const AMPERSAND: char = '&';
+const COMMENT: char = '#';
 
 pub type CmdArgs = Vec<String>;
 
-fn extract_redirection(args: &Vec<String>) -> (CmdArgs, Redirection) {
-  let mut args_iter = args.iter();
+/// A parsed command line
+#[derive(Debug, Default)]
+pub struct Pipeline {
+  /// Commands chained with `|`
+  pub cmds: Vec<SimpleCommand>,
+  /// Whether the line ended with `&`, i.e. the pipeline runs in the background
+  pub background: bool,
+  /// Whether the line started with the `time` keyword, reporting how long the pipeline took
+  pub timed: bool,
+}
+
+/// A command of a pipeline
+#[derive(Debug)]
+pub struct SimpleCommand {
+  pub args: CmdArgs,
+  pub input: Option<InputRedirection>,
+  pub redirection: Redirection,
+  /// Number of leading args that are `NAME=value` assignments, rather than the command's name
+  pub assignments: usize,
+}
+
+/// A word of the command line, with the length of its leading plain part: not quoted, escaped or
+/// expanded. Only plain text makes an operator or an assignment.
+struct Word {
+  text: String,
+  plain: usize,
+}
+
+impl Word {
+  fn is_plain(&self) -> bool {
+    self.plain == self.text.len()
+  }
+
+  fn plain_text(&self) -> &str {
+    &self.text[..self.plain]
+  }
+}
+
+fn extract_redirection(words: &[Word]) -> Result<SimpleCommand> {
+  let mut args_iter = words.iter();
   let mut final_args: Vec<String> = Vec::new();
+  let mut assignments = 0;
+  let mut input: Option<InputRedirection> = None;
   let mut redirection = Redirection::None;
   loop {
     let current = args_iter.next();
-    if let Some(current) = current {
-      match current.as_str() {
-        "1>" | ">" => {
+    if let Some(word) = current {
+      let current = &word.text;
+      if word.is_plain() && current == "<<<" {
+        input = Some(InputRedirection::HereString {
+          content: redirection_target(args_iter.next())?,
+        });
+        continue;
+      }
+
+      // Here-document, either `<< EOF` or `<<EOF` (`<<-` strips leading tabs). Its body is read
+      // after the command line is parsed
+      if let Some(delimiter) = word.plain_text().starts_with("<<").then(|| &current[2..]) {
+        let (strip_tabs, delimiter) = match delimiter.strip_prefix('-') {
+          Some(delimiter) if word.plain_text().starts_with("<<-") => (true, delimiter),
+          _ => (false, delimiter),
+        };
+        // Any quoting in the delimiter leaves the body as is
+        let (delimiter, expand) = match delimiter {
+          "" => {
+            let target = args_iter.next();
+            (
+              redirection_target(target)?,
+              target.is_some_and(Word::is_plain),
+            )
+          }
+          delimiter => (delimiter.to_string(), word.is_plain()),
+        };
+
+        input = Some(InputRedirection::HereDoc {
+          delimiter,
+          strip_tabs,
+          expand,
+          content: String::new(),
+        });
+        continue;
+      }
+
+      // Both stdout and stderr into the same file, `&>>` to append to it
+      if word.is_plain() && (current == "&>" || current == "&>>") {
+        redirection = Redirection::Both {
+          file_path: redirection_target(args_iter.next())?,
+          append: current == "&>>",
+        };
+        continue;
+      }
+
+      match parse_redirection_operator(current).filter(|_| word.is_plain()) {
+        Some((1, append, force)) => {
           redirection = Redirection::Stdout {
-            file_path: args_iter.next().unwrap().clone(),
-            append: false,
+            file_path: redirection_target(args_iter.next())?,
+            append,
+            force,
           };
         }
-        "2>" => {
+        Some((2, append, force)) => {
           redirection = Redirection::Stderr {
-            file_path: args_iter.next().unwrap().clone(),
-            append: false,
+            file_path: redirection_target(args_iter.next())?,
+            append,
+            force,
           }
         }
-        "1>>" | ">>" => {
-          redirection = Redirection::Stdout {
-            file_path: args_iter.next().unwrap().clone(),
-            append: true,
-          };
-        }
-        "2>>" => {
-          redirection = Redirection::Stderr {
-            file_path: args_iter.next().unwrap().clone(),
-            append: true,
+        Some((fd, append, force)) => {
+          redirection = Redirection::Fd {
+            fd,
+            file_path: redirection_target(args_iter.next())?,
+            append,
+            force,
           }
         }
-        _ => {
+        None => {
+          // Assignments come before anything else, their `=` being unquoted
+          let is_assignment =
+            parse_assignment(current).is_some_and(|(name, _)| name.len() < word.plain);
+          if is_assignment && assignments == final_args.len() {
+            assignments += 1;
+          }
           final_args.push(current.clone());
         }
       }
@@ -49,13 +152,252 @@ fn extract_redirection(args: &Vec<String>) -> (CmdArgs, Redirection) {
     }
   }
 
-  (final_args, redirection)
+  Ok(SimpleCommand {
+    args: final_args,
+    input,
+    redirection,
+    assignments,
+  })
+}
+
+/// Returns the file path following a redirection operator, which must be an actual word (not
+/// missing or another operator)
+fn redirection_target(target: Option<&Word>) -> Result<String> {
+  match target {
+    None => Err(Error::SyntaxError("newline".to_string())),
+    Some(target)
+      if target.plain_text().starts_with("<<")
+        || target.plain_text().starts_with("&>")
+        || (target.is_plain() && parse_redirection_operator(&target.text).is_some()) =>
+    {
+      Err(Error::SyntaxError(target.text.clone()))
+    }
+    Some(target) => Ok(target.text.clone()),
+  }
+}
+
+/// Parses an output redirection operator: `>`, `>>`, `>|` or their forms with an explicit file
+/// descriptor number, like `2>` or `3>>`.
+///
+/// Returns the file descriptor (1 when omitted), whether the file is to be appended to and whether
+/// overwriting it is forced (`>|`, which ignores noclobber).
+fn parse_redirection_operator(token: &str) -> Option<(i32, bool, bool)> {
+  let (fd, append, force) = if let Some(fd) = token.strip_suffix(">>") {
+    (fd, true, false)
+  } else if let Some(fd) = token.strip_suffix(">|") {
+    (fd, false, true)
+  } else {
+    (token.strip_suffix('>')?, false, false)
+  };
+
+  if fd.is_empty() {
+    return Some((1, append, force));
+  }
+
+  if !fd.chars().all(|c| c.is_ascii_digit()) {
+    return None;
+  }
+
+  fd.parse::<i32>().ok().map(|fd| (fd, append, force))
+}
+
+/// Reads a variable name following a `$`, either in the `NAME`, `${NAME}` or special parameter
+/// (like `?`) form.
+///
+/// Returns `None` (consuming nothing) when the chars following `$` don't form a valid name, in which
+/// case the `$` is to be treated literally.
+fn read_variable_name(chars: &mut Peekable<Chars>) -> Option<String> {
+  if chars.peek() == Some(&'{') {
+    let mut lookahead = chars.clone();
+    lookahead.next();
+    let name: String = lookahead.by_ref().take_while(|c| *c != '}').collect();
+    if !is_valid_name(&name) {
+      return None;
+    }
+
+    // Consume `{`, the name and `}`
+    chars.nth(name.chars().count() + 1);
+    return Some(name);
+  }
+
+  if let Some(special) = chars.next_if(|char| is_special_parameter(*char)) {
+    return Some(special.to_string());
+  }
+
+  let mut name = String::new();
+  while let Some(char) = chars.peek() {
+    let is_name_char =
+      char.is_ascii_alphabetic() || *char == '_' || (!name.is_empty() && char.is_ascii_digit());
+    if !is_name_char {
+      break;
+    }
+
+    name.push(*char);
+    chars.next();
+  }
+
+  if name.is_empty() {
+    None
+  } else {
+    Some(name)
+  }
+}
+
+/// Reads the expression of `$((expr))`, after the opening parentheses, up to the closing ones
+fn read_arithmetic_expression(chars: &mut Peekable<Chars>) -> Result<String> {
+  let mut expression = String::new();
+  // Parentheses opened inside the expression
+  let mut depth = 0;
+
+  while let Some(char) = chars.next() {
+    match char {
+      '(' => depth += 1,
+      ')' if depth == 0 && chars.peek() == Some(&')') => {
+        chars.next();
+        return Ok(expression);
+      }
+      ')' => depth -= 1,
+      _ => {}
+    }
+    expression.push(char);
+  }
+
+  Err(Error::ArithmeticError {
+    expression: format!("$(({}", expression),
+    message: "missing `))'".to_string(),
+  })
+}
+
+/// Value of `$((expr))`, or the error evaluating its expression
+fn arithmetic_expansion(expression: &str, variables: &Variables) -> Result<String> {
+  arithmetic::evaluate(expression, variables)
+    .map(|value| value.to_string())
+    .map_err(|message| Error::ArithmeticError {
+      expression: expression.trim().to_string(),
+      message,
+    })
+}
+
+/// Reads the command of `$(command)`, after the opening parenthesis, up to the matching closing one.
+/// Parentheses in quotes don't count.
+fn read_substituted_command(chars: &mut Peekable<Chars>) -> Result<String> {
+  let mut command = String::new();
+  // Parentheses opened inside the command
+  let mut depth = 0;
+  let mut quote: Option<char> = None;
+
+  while let Some(char) = chars.next() {
+    match (char, quote) {
+      (ESCAPE, _) => {
+        command.push(char);
+        command.extend(chars.next());
+        continue;
+      }
+      (SINGLE_QUOTE | DOUBLE_QUOTE, None) => quote = Some(char),
+      (char, Some(open)) if char == open => quote = None,
+      ('(', None) => depth += 1,
+      (')', None) if depth == 0 => return Ok(command),
+      (')', None) => depth -= 1,
+      _ => {}
+    }
+    command.push(char);
+  }
+
+  Err(Error::SyntaxError("newline".to_string()))
+}
+
+/// Output of `$(command)`, run by a new instance of the shell which gets the positional parameters
+/// as args, the shell variables in its environment, and the rest of the shell's state through
+/// `subshell_env`. Trailing newlines are removed, stderr is left to the terminal.
+fn command_output(command: &str, ctx: &ShellContext) -> String {
+  let output = env::current_exe().and_then(|shell| {
+    process::Command::new(shell)
+      .args(["-c", command])
+      .args(&ctx.variables.positional)
+      .envs(ctx.variables.shell_variables())
+      .envs(ctx.subshell_env())
+      .stderr(Stdio::inherit())
+      .output()
+  });
+
+  match output {
+    Ok(output) => {
+      utils::trim_trailing_newlines(&String::from_utf8_lossy(&output.stdout)).to_string()
+    }
+    Err(err) => {
+      eprintln!("shell: {}: {}", command, utils::io_error_message(&err));
+      String::new()
+    }
+  }
+}
+
+/// Interprets the escape sequence following a backslash in `$'...'`, like `\n`, `\'` or `\x41`.
+/// Unknown sequences are kept as is, backslash included.
+fn push_ansi_c_escape(chars: &mut Peekable<Chars>, arg: &mut String) {
+  let Some(char) = chars.next() else {
+    arg.push(ESCAPE);
+    return;
+  };
+
+  match char {
+    'a' => arg.push('\x07'),
+    'b' => arg.push('\x08'),
+    'e' | 'E' => arg.push('\x1b'),
+    'f' => arg.push('\x0c'),
+    'n' => arg.push('\n'),
+    'r' => arg.push('\r'),
+    't' => arg.push('\t'),
+    'v' => arg.push('\x0b'),
+    ESCAPE | SINGLE_QUOTE | DOUBLE_QUOTE | '?' => arg.push(char),
+    // Up to 2 hex digits
+    'x' => {
+      let mut hex = String::new();
+      while let Some(digit) = chars.next_if(|c| c.is_ascii_hexdigit() && hex.len() < 2) {
+        hex.push(digit);
+      }
+
+      match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+        Some(value) => arg.push(value),
+        None => arg.push_str("\\x"),
+      }
+    }
+    char => {
+      arg.push(ESCAPE);
+      arg.push(char);
+    }
+  }
+}
+
+/// Single char parameters, `$?`, `$$` and the positional parameters `$0` to `$9`
+fn is_special_parameter(char: char) -> bool {
+  matches!(char, '?' | '#' | '@' | '*' | '$') || char.is_ascii_digit()
+}
+
+fn is_valid_name(name: &str) -> bool {
+  if name.chars().count() == 1 && name.chars().all(is_special_parameter) {
+    return true;
+  }
+
+  // Positional parameters past `$9` need braces, like `${10}`
+  if !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) {
+    return true;
+  }
+
+  let mut chars = name.chars();
+  match chars.next() {
+    Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+      chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+    _ => false,
+  }
 }
 
 pub enum WaitFor {
   Space,
   SingleQuote,
   DoubleQuote,
+  // ANSI-C quoting, `$'...'`, where backslash escapes are interpreted
+  AnsiCQuote,
 }
 
 /// Parses a command line string into individual arguments, handling single-quoted strings.
@@ -65,17 +407,30 @@ pub enum WaitFor {
 ///
 /// # Arguments
 /// * `full_command` - The complete command line string to parse
+/// * `ctx` - The shell's state, its variables expand `$NAME` references (unset ones expand to
+///   nothing) and command substitutions inherit it
 ///
 /// # Returns
-/// A vector of strings, where each string is a separate command argument
-pub fn parse_args(full_command: String) -> Vec<(CmdArgs, Redirection)> {
+/// The pipeline of commands, each being a vector of strings where each string is a separate
+/// command argument, or a syntax error (like a redirection operator without a target)
+pub fn parse_args(full_command: String, ctx: &ShellContext) -> Result<Pipeline> {
+  let variables = &ctx.variables;
   let mut args: Vec<String> = Vec::new();
   let mut arg = String::new();
   // Wait for this char while appending other characters to arg
   let mut wait_for = WaitFor::Space;
   let mut is_escaping = false;
+  let mut background = false;
+  // Length of the current arg's plain part, set once it has quoted, escaped or expanded parts. Such
+  // an arg is never an operator
+  let mut plain: Option<usize> = None;
+  // Length of each arg's plain part
+  let mut plain_lens: Vec<usize> = Vec::new();
+  // Indices of the args that are operators, `|` or `|&`
+  let mut operators: Vec<usize> = Vec::new();
+  let mut chars = full_command.chars().peekable();
 
-  for char in full_command.chars() {
+  while let Some(char) = chars.next() {
     if is_escaping {
       match wait_for {
         // Escaping outside quotes (non-quoted backlash, preserves the literal value of next char)
@@ -83,12 +438,14 @@ pub fn parse_args(full_command: String) -> Vec<(CmdArgs, Redirection)> {
           arg.push(char);
         }
         // with single quotes, every char is treaded literally and no escaping is performed
-        WaitFor::SingleQuote => {
+        WaitFor::SingleQuote | WaitFor::AnsiCQuote => {
           arg.push(ESCAPE);
           arg.push(char);
         }
         WaitFor::DoubleQuote => match char {
-          ESCAPE | DOUBLE_QUOTE => arg.push(char),
+          ESCAPE | DOUBLE_QUOTE | DOLLAR | BACKTICK => arg.push(char),
+          // Line continuation
+          '\n' => {}
           // with double quotes, every char other the selected few is treaded literally and no escaping is performed
           _ => {
             arg.push(ESCAPE);
@@ -100,20 +457,35 @@ pub fn parse_args(full_command: String) -> Vec<(CmdArgs, Redirection)> {
       continue;
     }
 
+    // Inside `$'...'`, only an unescaped `'` ends the quote
+    if matches!(wait_for, WaitFor::AnsiCQuote) {
+      match char {
+        SINGLE_QUOTE => wait_for = WaitFor::Space,
+        ESCAPE => push_ansi_c_escape(&mut chars, &mut arg),
+        ch => arg.push(ch),
+      }
+      continue;
+    }
+
     match char {
       SPACE => {
         match wait_for {
           WaitFor::Space => {
             // Skip consecutive spaces
             if arg.is_empty() {
+              plain = None;
               continue;
             }
             // End of current argument - add it to the list
+            if plain.is_none() && is_pipe_operator(&arg) {
+              operators.push(args.len());
+            }
+            plain_lens.push(plain.take().unwrap_or(arg.len()));
             args.push(arg.clone());
             arg.clear();
           }
           // If we're inside quotes, treat space as a regular character
-          WaitFor::SingleQuote | WaitFor::DoubleQuote => {
+          WaitFor::SingleQuote | WaitFor::DoubleQuote | WaitFor::AnsiCQuote => {
             arg.push(SPACE);
             continue;
           }
@@ -122,9 +494,12 @@ pub fn parse_args(full_command: String) -> Vec<(CmdArgs, Redirection)> {
       SINGLE_QUOTE => {
         match wait_for {
           // Start of quoted string - begin collecting characters until closing quote
-          WaitFor::Space => wait_for = WaitFor::SingleQuote,
+          WaitFor::Space => {
+            wait_for = WaitFor::SingleQuote;
+            plain.get_or_insert(arg.len());
+          }
           // End of quoted string - change the wait_for to space. push arg to args only on space
-          WaitFor::SingleQuote => wait_for = WaitFor::Space,
+          WaitFor::SingleQuote | WaitFor::AnsiCQuote => wait_for = WaitFor::Space,
           // In between double quotes - add it to the current argument
           WaitFor::DoubleQuote => arg.push(SINGLE_QUOTE),
         }
@@ -132,28 +507,631 @@ pub fn parse_args(full_command: String) -> Vec<(CmdArgs, Redirection)> {
       DOUBLE_QUOTE => {
         match wait_for {
           // Start of quoted string - begin collecting characters until closing quote
-          WaitFor::Space => wait_for = WaitFor::DoubleQuote,
+          WaitFor::Space => {
+            wait_for = WaitFor::DoubleQuote;
+            plain.get_or_insert(arg.len());
+          }
           // End of quoted string - change the wait_for to space. push arg to args only on space
           WaitFor::DoubleQuote => wait_for = WaitFor::Space,
           // In between single quotes - add it to the current argument
-          WaitFor::SingleQuote => arg.push(DOUBLE_QUOTE),
+          WaitFor::SingleQuote | WaitFor::AnsiCQuote => arg.push(DOUBLE_QUOTE),
+        }
+      }
+      ESCAPE => {
+        is_escaping = true;
+        plain.get_or_insert(arg.len());
+      }
+      DOLLAR if matches!(wait_for, WaitFor::Space) && chars.peek() == Some(&SINGLE_QUOTE) => {
+        chars.next();
+        wait_for = WaitFor::AnsiCQuote;
+        plain.get_or_insert(arg.len());
+      }
+      // Arithmetic expansion, `$((expr))`
+      DOLLAR
+        if matches!(wait_for, WaitFor::Space | WaitFor::DoubleQuote)
+          && chars.clone().take(2).eq(['(', '(']) =>
+      {
+        chars.nth(1);
+        plain.get_or_insert(arg.len());
+        let expression = read_arithmetic_expression(&mut chars)?;
+        arg.push_str(&arithmetic_expansion(&expression, variables)?);
+      }
+      // Command substitution, `$(command)`, split into several args when unquoted
+      DOLLAR
+        if matches!(wait_for, WaitFor::Space | WaitFor::DoubleQuote)
+          && chars.peek() == Some(&'(') =>
+      {
+        chars.next();
+        plain.get_or_insert(arg.len());
+        let output = command_output(&read_substituted_command(&mut chars)?, ctx);
+        match wait_for {
+          WaitFor::Space => {
+            utils::split_fields(&output, &variables.ifs(), usize::MAX, &mut arg, &mut args);
+            push_split_plain_lens(&args, &mut plain_lens, &mut plain);
+          }
+          _ => arg.push_str(&output),
         }
       }
-      ESCAPE => is_escaping = true,
+      // Variable expansion, also in double quotes, single quotes preserve the literal value of `$`.
+      // Unquoted values are split into several args
+      DOLLAR if matches!(wait_for, WaitFor::Space | WaitFor::DoubleQuote) => {
+        match read_variable_name(&mut chars) {
+          // Each positional parameter is a separate arg, even in double quotes for `"$@"`
+          Some(name) if name == "@" || (name == "*" && matches!(wait_for, WaitFor::Space)) => {
+            plain.get_or_insert(arg.len());
+            let ifs = variables.ifs();
+            for (index, value) in variables.args().iter().enumerate() {
+              if index > 0 && !arg.is_empty() {
+                args.push(mem::take(&mut arg));
+              }
+              match wait_for {
+                WaitFor::Space => utils::split_fields(value, &ifs, usize::MAX, &mut arg, &mut args),
+                _ => arg.push_str(value),
+              }
+            }
+            push_split_plain_lens(&args, &mut plain_lens, &mut plain);
+          }
+          Some(name) => {
+            plain.get_or_insert(arg.len());
+            let value = variables.get(&name).unwrap_or_default();
+            match wait_for {
+              WaitFor::Space => {
+                utils::split_fields(&value, &variables.ifs(), usize::MAX, &mut arg, &mut args);
+                push_split_plain_lens(&args, &mut plain_lens, &mut plain);
+              }
+              _ => arg.push_str(&value),
+            }
+          }
+          None => arg.push(DOLLAR),
+        }
+      }
+      // An unquoted `#` starting a word comments out the rest of the line
+      COMMENT if matches!(wait_for, WaitFor::Space) && arg.is_empty() => break,
+      // A trailing unquoted `&` sends the pipeline to the background, anywhere else it's literal
+      AMPERSAND
+        if matches!(wait_for, WaitFor::Space) && chars.clone().all(|c| c.is_whitespace()) =>
+      {
+        background = true;
+        break;
+      }
       // Regular character - add it to the current argument
       ch => arg.push(ch),
     }
   }
 
   if !arg.is_empty() {
+    if plain.is_none() && is_pipe_operator(&arg) {
+      operators.push(args.len());
+    }
+    plain_lens.push(plain.unwrap_or(arg.len()));
     args.push(arg);
   }
 
-  // Split by pipe, to extract multiple chainable commands
-  let groups = utils::split_vec_by_delimiter(args, "|");
+  let tee = extract_tee(&mut args, &mut operators);
+  let words = args
+    .into_iter()
+    .zip(plain_lens)
+    .map(|(text, plain)| Word { text, plain })
+    .collect::<Vec<Word>>();
+
+  // Split by pipe, to extract multiple chainable commands. Quoted or escaped `|` are plain args
+  operators.retain(|index| words[*index].text == "|");
+  // A pipe must be between two commands: not first, last, or right after another one
+  let dangling = operators.iter().enumerate().any(|(position, index)| {
+    *index == 0
+      || *index == words.len() - 1
+      || (position > 0 && operators[position - 1] == index - 1)
+  });
+  if dangling {
+    return Err(Error::SyntaxError("|".to_string()));
+  }
+  let mut groups = utils::split_vec_at(words, &operators);
+
+  // `time` prefixes the whole pipeline, on its own it's just a command
+  let timed = groups
+    .first()
+    .is_some_and(|words| words.len() > 1 && words[0].text == "time");
+  if timed {
+    groups[0].remove(0);
+  }
+
+  let mut cmds: Vec<SimpleCommand> = groups
+    .iter()
+    .map(|words| extract_redirection(words))
+    .collect::<Result<_>>()?;
+
+  if let (Some(tee), Some(cmd)) = (tee, cmds.last_mut()) {
+    cmd.redirection = tee;
+  }
+
+  Ok(Pipeline {
+    cmds,
+    background,
+    timed,
+  })
+}
+
+/// Expands the body of a here-document as double quotes would, quotes excepted: variables,
+/// command substitutions and arithmetic expansions. A backslash only escapes `$`, `` ` ``, another
+/// backslash or a newline.
+pub fn expand_here_doc(body: &str, ctx: &ShellContext) -> Result<String> {
+  let variables = &ctx.variables;
+  let mut expanded = String::new();
+  let mut chars = body.chars().peekable();
+
+  while let Some(char) = chars.next() {
+    match char {
+      ESCAPE => match chars.peek() {
+        Some(&(DOLLAR | BACKTICK | ESCAPE)) => expanded.extend(chars.next()),
+        // Line continuation
+        Some('\n') => {
+          chars.next();
+        }
+        _ => expanded.push(ESCAPE),
+      },
+      DOLLAR if chars.clone().take(2).eq(['(', '(']) => {
+        chars.nth(1);
+        let expression = read_arithmetic_expression(&mut chars)?;
+        expanded.push_str(&arithmetic_expansion(&expression, variables)?);
+      }
+      DOLLAR if chars.peek() == Some(&'(') => {
+        chars.next();
+        let command = read_substituted_command(&mut chars)?;
+        expanded.push_str(&command_output(&command, ctx));
+      }
+      DOLLAR => match read_variable_name(&mut chars) {
+        Some(name) => expanded.push_str(&variables.get(&name).unwrap_or_default()),
+        None => expanded.push(DOLLAR),
+      },
+      char => expanded.push(char),
+    }
+  }
+
+  Ok(expanded)
+}
 
-  groups
+/// Removes a trailing `|& tee [-a] file` stage from the args, returning it as a tee redirection for
+/// the command before it, so both of that command's streams reach the terminal and the file.
+fn extract_tee(args: &mut Vec<String>, operators: &mut Vec<usize>) -> Option<Redirection> {
+  let position = *operators.last().filter(|index| args[**index] == "|&")?;
+  let tail = args[position + 1..]
     .iter()
-    .map(|args| extract_redirection(&args))
-    .collect()
+    .map(|arg| arg.as_str())
+    .collect::<Vec<&str>>();
+
+  let redirection = match tail.as_slice() {
+    ["tee", file_path] => Redirection::Tee {
+      file_path: file_path.to_string(),
+      append: false,
+    },
+    ["tee", "-a", file_path] => Redirection::Tee {
+      file_path: file_path.to_string(),
+      append: true,
+    },
+    _ => return None,
+  };
+
+  args.truncate(position);
+  operators.pop();
+  Some(redirection)
+}
+
+/// Records the plain part of the args that an unquoted expansion was split into. Only the first one
+/// has any, what came before the expansion, and the arg continued after it has none.
+fn push_split_plain_lens(args: &[String], plain_lens: &mut Vec<usize>, plain: &mut Option<usize>) {
+  while plain_lens.len() < args.len() {
+    plain_lens.push(plain.replace(0).unwrap_or(0));
+  }
+}
+
+fn is_pipe_operator(arg: &str) -> bool {
+  arg == "|" || arg == "|&"
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(line: &str) -> Result<Pipeline> {
+    parse_args(line.to_string(), &ShellContext::new())
+  }
+
+  #[test]
+  fn test_time_prefix() {
+    let pipeline = parse("time sleep 1 | cat").unwrap();
+    assert!(pipeline.timed);
+    assert_eq!(pipeline.cmds[0].args, vec!["sleep", "1"]);
+    assert_eq!(pipeline.cmds[1].args, vec!["cat"]);
+
+    let pipeline = parse("time").unwrap();
+    assert!(!pipeline.timed);
+    assert_eq!(pipeline.cmds[0].args, vec!["time"]);
+  }
+
+  #[test]
+  fn test_redirection_fd_numbers() {
+    let pipeline = parse("echo hi 2>> err.txt").unwrap();
+    let SimpleCommand {
+      args, redirection, ..
+    } = &pipeline.cmds[0];
+    assert_eq!(args, &vec!["echo", "hi"]);
+    assert!(matches!(
+      redirection,
+      Redirection::Stderr { file_path, append: true, force: false } if file_path == "err.txt"
+    ));
+
+    let pipeline = parse("cmd 3> log.txt").unwrap();
+    assert!(matches!(
+      &pipeline.cmds[0].redirection,
+      Redirection::Fd { fd: 3, file_path, append: false, force: false } if file_path == "log.txt"
+    ));
+  }
+
+  #[test]
+  fn test_redirection_of_both_streams() {
+    let pipeline = parse("cmd &> all.log").unwrap();
+    assert_eq!(pipeline.cmds[0].args, vec!["cmd"]);
+    assert!(matches!(
+      &pipeline.cmds[0].redirection,
+      Redirection::Both { file_path, append: false } if file_path == "all.log"
+    ));
+
+    let pipeline = parse("cmd &>> all.log").unwrap();
+    assert!(matches!(
+      &pipeline.cmds[0].redirection,
+      Redirection::Both { append: true, .. }
+    ));
+    assert!(parse("cmd &>").is_err());
+  }
+
+  #[test]
+  fn test_forced_redirection() {
+    let pipeline = parse("echo hi >| out.txt").unwrap();
+    assert!(matches!(
+      &pipeline.cmds[0].redirection,
+      Redirection::Stdout { file_path, append: false, force: true } if file_path == "out.txt"
+    ));
+
+    let pipeline = parse("cmd 2>| err.txt 3>| log.txt").unwrap();
+    assert!(matches!(
+      &pipeline.cmds[0].redirection,
+      Redirection::Fd {
+        fd: 3,
+        force: true,
+        ..
+      }
+    ));
+    let pipeline = parse("cmd 2>| err.txt").unwrap();
+    assert!(matches!(
+      &pipeline.cmds[0].redirection,
+      Redirection::Stderr { force: true, .. }
+    ));
+  }
+
+  #[test]
+  fn test_all_positional_parameters() {
+    let mut ctx = ShellContext::new();
+    ctx.variables.positional = ["shell", "a b", "c"].map(String::from).to_vec();
+    let args = |line: &str, ctx: &ShellContext| {
+      parse_args(line.to_string(), ctx).unwrap().cmds[0]
+        .args
+        .clone()
+    };
+
+    assert_eq!(args("echo $# $0 $2", &ctx), vec!["echo", "2", "shell", "c"]);
+    // Each parameter is a word of its own in double quotes, joined to the text around them
+    assert_eq!(args("echo x\"$@\"y", &ctx), vec!["echo", "xa b", "cy"]);
+    assert_eq!(args("echo \"$*\"", &ctx), vec!["echo", "a b c"]);
+    assert_eq!(args("echo $@", &ctx), vec!["echo", "a", "b", "c"]);
+    assert_eq!(args("echo $*x", &ctx), vec!["echo", "a", "b", "cx"]);
+
+    ctx.variables.set("IFS", ",");
+    assert_eq!(args("echo \"$*\"", &ctx), vec!["echo", "a b,c"]);
+  }
+
+  #[test]
+  fn test_dangling_pipes() {
+    for line in ["echo hi |", "| grep x", "echo hi | | cat", "|"] {
+      assert!(
+        matches!(parse(line), Err(Error::SyntaxError(token)) if token == "|"),
+        "{}",
+        line
+      );
+    }
+
+    // `||` isn't a pipe, and a quoted `|` is an arg
+    assert_eq!(parse("a || b").unwrap().cmds[0].args, vec!["a", "||", "b"]);
+    assert_eq!(parse("echo '|'").unwrap().cmds[0].args, vec!["echo", "|"]);
+  }
+
+  #[test]
+  fn test_literal_pipes() {
+    let pipeline = parse("echo a \\| b").unwrap();
+    assert_eq!(pipeline.cmds.len(), 1);
+    assert_eq!(pipeline.cmds[0].args, vec!["echo", "a", "|", "b"]);
+
+    let pipeline = parse("echo \"a | b\" '|' | cat").unwrap();
+    assert_eq!(pipeline.cmds.len(), 2);
+    assert_eq!(pipeline.cmds[0].args, vec!["echo", "a | b", "|"]);
+    assert_eq!(pipeline.cmds[1].args, vec!["cat"]);
+
+    let mut ctx = ShellContext::new();
+    ctx.variables.set("p", "|");
+    let pipeline = parse_args("echo $p x".to_string(), &ctx).unwrap();
+    assert_eq!(pipeline.cmds[0].args, vec!["echo", "|", "x"]);
+  }
+
+  #[test]
+  fn test_assignments() {
+    let mut ctx = ShellContext::new();
+    ctx.variables.set("V", "FOO");
+    let assignments = |line: &str| parse_args(line.to_string(), &ctx).unwrap().cmds[0].assignments;
+
+    assert_eq!(assignments("FOO=bar X= env"), 2);
+    assert_eq!(assignments("FOO=\"a b\" env"), 1);
+    assert_eq!(assignments("> out.txt FOO=bar env"), 1);
+    assert_eq!(assignments("env FOO=bar"), 0);
+    // Only an unquoted `=` after an unquoted name
+    assert_eq!(assignments("\"FOO=bar\""), 0);
+    assert_eq!(assignments("FOO\"=bar\""), 0);
+    assert_eq!(assignments("\\FOO=bar"), 0);
+    assert_eq!(assignments("$V=bar"), 0);
+  }
+
+  #[test]
+  fn test_expanded_redirection_operators() {
+    let mut ctx = ShellContext::new();
+    ctx.variables.set("X", "a > b");
+    ctx.variables.set("T", ">");
+    ctx.variables.positional = ["shell", "2>>", "err.txt"].map(String::from).to_vec();
+    let cmd = |line: &str| parse_args(line.to_string(), &ctx).unwrap().cmds.remove(0);
+
+    // Expanded or quoted, operators are plain args
+    for (line, expected) in [
+      ("echo $X", vec!["echo", "a", ">", "b"]),
+      ("echo $*", vec!["echo", "2>>", "err.txt"]),
+      ("echo \"$@\"", vec!["echo", "2>>", "err.txt"]),
+      ("echo '>' x \\&> y", vec!["echo", ">", "x", "&>", "y"]),
+    ] {
+      let cmd = cmd(line);
+      assert_eq!(cmd.args, expected, "{}", line);
+      assert!(matches!(cmd.redirection, Redirection::None), "{}", line);
+    }
+
+    // Nor do they make a redirection's target an operator
+    assert!(matches!(
+      cmd("echo hi > $T").redirection,
+      Redirection::Stdout { file_path, .. } if file_path == ">"
+    ));
+  }
+
+  #[test]
+  fn test_here_string() {
+    let pipeline = parse("grep foo <<< 'foo bar'").unwrap();
+    let SimpleCommand {
+      args,
+      input,
+      redirection,
+      ..
+    } = &pipeline.cmds[0];
+    assert_eq!(args, &vec!["grep", "foo"]);
+    assert!(matches!(
+      input,
+      Some(InputRedirection::HereString { content }) if content == "foo bar"
+    ));
+    assert!(matches!(redirection, Redirection::None));
+  }
+
+  #[test]
+  fn test_here_doc_delimiter() {
+    for (line, expected_strip_tabs, expected_expand) in [
+      ("cat << EOF", false, true),
+      ("cat <<EOF", false, true),
+      ("cat <<-EOF", true, true),
+      // Any quoting in the delimiter leaves the body unexpanded
+      ("cat <<'EOF'", false, false),
+      ("cat << \"EOF\"", false, false),
+      ("cat <<-E\\OF", true, false),
+    ] {
+      let pipeline = parse(line).unwrap();
+      assert!(
+        matches!(
+          &pipeline.cmds[0].input,
+          Some(InputRedirection::HereDoc { delimiter, strip_tabs, expand, .. })
+            if delimiter == "EOF" && *strip_tabs == expected_strip_tabs && *expand == expected_expand
+        ),
+        "{}",
+        line
+      );
+    }
+
+    // A quoted `<<` is an arg
+    let pipeline = parse("echo \"<<x\" '<<-' hi").unwrap();
+    assert_eq!(pipeline.cmds[0].args, vec!["echo", "<<x", "<<-", "hi"]);
+    assert!(pipeline.cmds[0].input.is_none());
+  }
+
+  #[test]
+  fn test_input_and_output_redirections() {
+    let pipeline = parse("cat <<EOF > out.txt").unwrap();
+    let cmd = &pipeline.cmds[0];
+    assert_eq!(cmd.args, vec!["cat"]);
+    assert!(matches!(
+      &cmd.input,
+      Some(InputRedirection::HereDoc { delimiter, .. }) if delimiter == "EOF"
+    ));
+    assert!(matches!(
+      &cmd.redirection,
+      Redirection::Stdout { file_path, .. } if file_path == "out.txt"
+    ));
+  }
+
+  #[test]
+  fn test_expand_here_doc() {
+    let mut ctx = ShellContext::new();
+    ctx.variables.set("name", "world");
+    ctx.variables.positional = ["shell", "a", "b"].map(String::from).to_vec();
+    let expand = |body: &str| expand_here_doc(body, &ctx).unwrap();
+
+    assert_eq!(expand("hi $name\n"), "hi world\n");
+    assert_eq!(expand("${name}s $# $@ $((1 + 2))\n"), "worlds 2 a b 3\n");
+    // Quotes are kept, backslashes only escape a few chars
+    assert_eq!(expand("'$name' \"$name\"\n"), "'world' \"world\"\n");
+    assert_eq!(expand(r"\$name \\ \n \a$"), r"$name \ \n \a$");
+    assert_eq!(expand("a\\\nb"), "ab");
+    assert!(expand_here_doc("$((1 / 0))", &ctx).is_err());
+  }
+
+  #[test]
+  fn test_ansi_c_quoting() {
+    let pipeline = parse(r"echo $'a\tb\n' $'it\'s' $'\x41\q' '$x'").unwrap();
+    assert_eq!(
+      pipeline.cmds[0].args,
+      vec!["echo", "a\tb\n", "it's", "A\\q", "$x"]
+    );
+  }
+
+  #[test]
+  fn test_double_quotes() {
+    let mut ctx = ShellContext::new();
+    ctx.variables.last_status = 3;
+    let parse = |line: &str| {
+      parse_args(line.to_string(), &ctx).unwrap().cmds[0]
+        .args
+        .clone()
+    };
+
+    // Expected values are what bash's echo prints for the same lines
+    assert_eq!(parse(r#"echo "\$x""#), vec!["echo", "$x"]);
+    assert_eq!(parse(r#"echo "a\nb""#), vec!["echo", r"a\nb"]);
+    assert_eq!(parse(r#"echo "a\\b""#), vec!["echo", r"a\b"]);
+    assert_eq!(parse(r#"echo "\"q\" \`""#), vec!["echo", r#""q" `"#]);
+    assert_eq!(parse(r#"echo "status: $?""#), vec!["echo", "status: 3"]);
+    assert_eq!(parse(r#"echo "${?}s" '$?'"#), vec!["echo", "3s", "$?"]);
+    assert_eq!(parse(r#"echo "$""#), vec!["echo", "$"]);
+  }
+
+  #[test]
+  fn test_positional_parameters() {
+    let mut ctx = ShellContext::new();
+    ctx.variables.positional = [
+      "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+    ]
+    .map(String::from)
+    .to_vec();
+    let parse = |line: &str| {
+      parse_args(line.to_string(), &ctx).unwrap().cmds[0]
+        .args
+        .clone()
+    };
+
+    assert_eq!(parse("echo $0 $1"), vec!["echo", "zero", "one"]);
+    // Only a single digit without braces
+    assert_eq!(parse("echo $10 ${10}"), vec!["echo", "one0", "ten"]);
+    assert_eq!(parse("echo \"[$11]\""), vec!["echo", "[one1]"]);
+  }
+
+  #[test]
+  fn test_word_splitting() {
+    let mut ctx = ShellContext::new();
+    ctx.variables.set("FOO", "a b");
+    ctx.variables.set("SPACED", "  x \t y  ");
+    let args = |line: &str, ctx: &ShellContext| {
+      parse_args(line.to_string(), ctx).unwrap().cmds[0]
+        .args
+        .clone()
+    };
+
+    assert_eq!(args("set -- $FOO", &ctx), vec!["set", "--", "a", "b"]);
+    assert_eq!(args("set -- \"$FOO\"", &ctx), vec!["set", "--", "a b"]);
+    assert_eq!(
+      args("echo [$SPACED]", &ctx),
+      vec!["echo", "[", "x", "y", "]"]
+    );
+    assert_eq!(args("echo $UNSET_VAR_X", &ctx), vec!["echo"]);
+
+    ctx.variables.set("PATHS", "a::b: c:");
+    ctx.variables.set("IFS", ": ");
+    assert_eq!(args("echo $PATHS", &ctx), vec!["echo", "a", "", "b", "c"]);
+    ctx.variables.set("IFS", "");
+    assert_eq!(args("echo $FOO", &ctx), vec!["echo", "a b"]);
+  }
+
+  #[test]
+  fn test_random_references() {
+    let mut ctx = ShellContext::new();
+    ctx.variables.set("RANDOM", "7");
+    let args = parse_args("echo $RANDOM ${RANDOM}".to_string(), &ctx)
+      .unwrap()
+      .cmds[0]
+      .args
+      .clone();
+    assert_ne!(args[1], args[2]);
+    assert!(args[1..]
+      .iter()
+      .all(|arg| arg.parse::<u16>().unwrap() <= 32767));
+  }
+
+  #[test]
+  fn test_read_substituted_command() {
+    let read = |text: &str| read_substituted_command(&mut text.chars().peekable());
+
+    assert_eq!(read("pwd)rest").unwrap(), "pwd");
+    assert_eq!(
+      read("echo (a) ')' \\) \")\")x").unwrap(),
+      "echo (a) ')' \\) \")\""
+    );
+    assert!(read("echo (a)").is_err());
+  }
+
+  #[test]
+  fn test_arithmetic_expansion() {
+    let args = |line: &str| parse(line).unwrap().cmds[0].args.clone();
+
+    assert_eq!(args("echo $((2 + 3 * 4))"), vec!["echo", "14"]);
+    assert_eq!(args("echo \"n=$(( (1+2) * (3) ))\"x"), vec!["echo", "n=9x"]);
+    assert_eq!(args("echo '$((1+1))'"), vec!["echo", "$((1+1))"]);
+    assert_eq!(
+      parse("echo $((1 / 0))").unwrap_err().to_string(),
+      "1 / 0: division by 0"
+    );
+    assert!(parse("echo $((1 + 2").is_err());
+  }
+
+  #[test]
+  fn test_comments() {
+    let args = |line: &str| {
+      parse(line)
+        .unwrap()
+        .cmds
+        .into_iter()
+        .map(|cmd| cmd.args)
+        .collect::<Vec<CmdArgs>>()
+    };
+
+    assert!(args("# echo hi").is_empty());
+    assert!(args("   #echo hi").is_empty());
+    assert_eq!(args("echo hi # a comment | cat"), vec![vec!["echo", "hi"]]);
+    assert_eq!(args("echo hi #"), vec![vec!["echo", "hi"]]);
+    // Quoted, escaped or inside a word, `#` is literal
+    assert_eq!(
+      args(r##"echo "# x" '#' \# a#b"##),
+      vec![vec!["echo", "# x", "#", "#", "a#b"]]
+    );
+  }
+
+  #[test]
+  fn test_dangling_redirection_is_a_syntax_error() {
+    let err = parse("echo hi >").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "syntax error near unexpected token `newline'"
+    );
+
+    let err = parse("echo hi > >> out.txt").unwrap_err();
+    assert_eq!(err.to_string(), "syntax error near unexpected token `>>'");
+
+    let err = parse("echo hi 2> | cat").unwrap_err();
+    assert!(matches!(err, Error::SyntaxError(_)));
+  }
 }
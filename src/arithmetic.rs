@@ -0,0 +1,208 @@
+use crate::variables::Variables;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// How many times a variable's value can be evaluated as an expression itself before giving up,
+/// like with `a=b` and `b=a`
+const MAX_VARIABLE_DEPTH: usize = 10;
+
+/// Evaluates the integer expression of an arithmetic expansion (`$((expr))`).
+///
+/// Supports `+ - * / %`, parentheses, unary `-` and `+`, and variables (`x` or `$x`). Unset or
+/// empty variables are 0, and a variable's value can itself name another variable. Arithmetic
+/// wraps around on overflow, as in bash.
+pub fn evaluate(expression: &str, variables: &Variables) -> Result<i64, String> {
+  evaluate_at_depth(expression, variables, 0)
+}
+
+fn evaluate_at_depth(expression: &str, variables: &Variables, depth: usize) -> Result<i64, String> {
+  let mut parser = Parser {
+    chars: expression.chars().peekable(),
+    variables,
+    depth,
+  };
+
+  // An empty expression is 0
+  parser.skip_whitespace();
+  if parser.chars.peek().is_none() {
+    return Ok(0);
+  }
+
+  let value = parser.expression()?;
+  parser.skip_whitespace();
+  match parser.chars.peek() {
+    None => Ok(value),
+    Some(char) => Err(format!(
+      "syntax error in expression (error token is \"{}\")",
+      char
+    )),
+  }
+}
+
+struct Parser<'a> {
+  chars: Peekable<Chars<'a>>,
+  variables: &'a Variables,
+  depth: usize,
+}
+
+impl Parser<'_> {
+  fn skip_whitespace(&mut self) {
+    while self.chars.next_if(|char| char.is_whitespace()).is_some() {}
+  }
+
+  /// Next operator, if it's one of the given ones
+  fn operator(&mut self, operators: &[char]) -> Option<char> {
+    self.skip_whitespace();
+    self.chars.next_if(|char| operators.contains(char))
+  }
+
+  // expression := term (('+' | '-') term)*
+  fn expression(&mut self) -> Result<i64, String> {
+    let mut value = self.term()?;
+    while let Some(operator) = self.operator(&['+', '-']) {
+      let right = self.term()?;
+      value = match operator {
+        '+' => value.wrapping_add(right),
+        _ => value.wrapping_sub(right),
+      };
+    }
+
+    Ok(value)
+  }
+
+  // term := unary (('*' | '/' | '%') unary)*
+  fn term(&mut self) -> Result<i64, String> {
+    let mut value = self.unary()?;
+    while let Some(operator) = self.operator(&['*', '/', '%']) {
+      let right = self.unary()?;
+      if operator != '*' && right == 0 {
+        return Err("division by 0".to_string());
+      }
+
+      value = match operator {
+        '*' => value.wrapping_mul(right),
+        '/' => value.wrapping_div(right),
+        _ => value.wrapping_rem(right),
+      };
+    }
+
+    Ok(value)
+  }
+
+  // unary := ('-' | '+') unary | primary
+  fn unary(&mut self) -> Result<i64, String> {
+    match self.operator(&['-', '+']) {
+      Some('-') => Ok(self.unary()?.wrapping_neg()),
+      Some(_) => self.unary(),
+      None => self.primary(),
+    }
+  }
+
+  // primary := number | variable | '(' expression ')'
+  fn primary(&mut self) -> Result<i64, String> {
+    self.skip_whitespace();
+    match self.chars.peek().copied() {
+      Some('(') => {
+        self.chars.next();
+        let value = self.expression()?;
+        match self.operator(&[')']) {
+          Some(_) => Ok(value),
+          None => Err("missing `)'".to_string()),
+        }
+      }
+      Some(char) if char.is_ascii_digit() => {
+        let digits = self.take_while(|char| char.is_ascii_alphanumeric());
+        digits
+          .parse::<i64>()
+          .map_err(|_| format!("value too great for base (error token is \"{}\")", digits))
+      }
+      Some('$') => {
+        self.chars.next();
+        // Special and positional parameters, like `$?` or `$1`
+        let name = match self
+          .chars
+          .next_if(|char| *char == '?' || char.is_ascii_digit())
+        {
+          Some(char) => char.to_string(),
+          None => self.take_while(|char| char.is_ascii_alphanumeric() || char == '_'),
+        };
+        self.variable(&name)
+      }
+      Some(char) if char.is_ascii_alphabetic() || char == '_' => {
+        let name = self.take_while(|char| char.is_ascii_alphanumeric() || char == '_');
+        self.variable(&name)
+      }
+      Some(char) => Err(format!(
+        "syntax error: operand expected (error token is \"{}\")",
+        char
+      )),
+      None => Err("syntax error: operand expected".to_string()),
+    }
+  }
+
+  fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+    let mut taken = String::new();
+    while let Some(char) = self.chars.next_if(|char| predicate(*char)) {
+      taken.push(char);
+    }
+    taken
+  }
+
+  fn variable(&self, name: &str) -> Result<i64, String> {
+    let value = self.variables.get(name).unwrap_or_default();
+    let value = value.trim();
+    if let Ok(number) = value.parse::<i64>() {
+      return Ok(number);
+    }
+
+    // The value can be another variable's name, or an expression
+    if self.depth >= MAX_VARIABLE_DEPTH {
+      return Err(format!(
+        "expression recursion level exceeded (error token is \"{}\")",
+        name
+      ));
+    }
+    evaluate_at_depth(value, self.variables, self.depth + 1)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn eval(expression: &str) -> Result<i64, String> {
+    evaluate(expression, &Variables::new())
+  }
+
+  #[test]
+  fn test_precedence_and_parentheses() {
+    assert_eq!(eval("2 + 3 * 4"), Ok(14));
+    assert_eq!(eval("(2 + 3) * 4"), Ok(20));
+    assert_eq!(eval("10 - 4 - 3"), Ok(3));
+    assert_eq!(eval("7 / 2 + 7 % 2"), Ok(4));
+    assert_eq!(eval("-3 * -(2 + 1)"), Ok(9));
+    assert_eq!(eval("  "), Ok(0));
+  }
+
+  #[test]
+  fn test_variables() {
+    let mut variables = Variables::new();
+    variables.set("ARITH_X", "4");
+    variables.set("ARITH_Y", "ARITH_X");
+    variables.last_status = 2;
+
+    assert_eq!(evaluate("ARITH_X + 1", &variables), Ok(5));
+    assert_eq!(evaluate("$ARITH_X * $?", &variables), Ok(8));
+    assert_eq!(evaluate("ARITH_Y * 2", &variables), Ok(8));
+    assert_eq!(evaluate("ARITH_UNSET + 1", &variables), Ok(1));
+  }
+
+  #[test]
+  fn test_errors() {
+    assert_eq!(eval("1 / 0"), Err("division by 0".to_string()));
+    assert_eq!(eval("5 % (2 - 2)"), Err("division by 0".to_string()));
+    assert!(eval("(1 + 2").is_err());
+    assert!(eval("1 +").is_err());
+    assert!(eval("2 3").is_err());
+  }
+}
@@ -1,14 +1,36 @@
+use crate::aliases::{self, is_valid_alias_name, Aliases};
+use crate::ansi_codes::AnsiCode;
+use crate::arithmetic;
+use crate::context::ShellContext;
+use crate::hash::{command_hash, find_hashed_command};
 use crate::history::History;
-use crate::writer::CmdOutput;
+use crate::input::read_line;
+use crate::jobs::{set_terminal_foreground, JobState, Jobs, ProcessGroup, WaitStatus};
+use crate::options::{parse_known_options, parse_options};
+use crate::shell_options::ShellOptions;
+use crate::signals;
+use crate::tab_completions::{file_completions, CmdCompletions};
+use crate::traps::Traps;
+use crate::variables::{is_valid_identifier, parse_assignment, Variables};
+use crate::writer::{open_fd_redirection, CmdOutput, Redirection};
 use crate::{
   args::CmdArgs,
-  utils::{expand_tilda, find_command},
+  utils::{
+    canonicalize_logical, canonicalize_physical, expand_tilda, find_all_command_paths,
+    find_command, find_command_in, get_path, interpret_escapes, io_error_message, shell_quote,
+    split_fields, status_code, DEFAULT_PATH,
+  },
 };
-use std::io::Write;
+use std::io::{BufRead, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
 use std::{
   env,
+  fs::{self, File},
   io::{self},
-  process::{self, Stdio},
+  process::{self, ChildStdout, Stdio},
+  thread,
 };
 
 pub struct ExecutableCmd {
@@ -20,7 +42,8 @@ pub struct ExecutableCmd {
 pub enum CmdInput {
   String(String),
   Bytes(Vec<u8>),
-  Pipe(Stdio),
+  /// Output of the previous command's child, read as it's produced
+  Pipe(ChildStdout),
 }
 
 pub enum Cmd {
@@ -31,9 +54,43 @@ pub enum Cmd {
   Cd,
   Pwd,
   History,
+  Read,
+  True,
+  False,
+  Which,
+  Jobs,
+  Fg,
+  Bg,
+  Kill,
+  Set,
+  Exec,
+  Trap,
+  Compgen,
+  Export,
+  Let,
+  Clear,
+  Help,
+  Cat,
+  Wait,
+  Command,
+  Hash,
+  Declare,
+  Version,
+  Source,
+  Umask,
+  Alias,
+  Unalias,
+  Shift,
   Unknown,
 }
 
+/// Names of the builtins, in the order `help` lists them
+const BUILTINS: &[&str] = &[
+  "alias", "bg", "cat", "cd", "clear", "command", "compgen", "declare", "echo", "exec", "exit",
+  "export", "false", "fg", "hash", "help", "history", "jobs", "kill", "let", "pwd", "read", "set",
+  "shift", "source", "trap", "true", "type", "umask", "unalias", "version", "wait", "which",
+];
+
 impl From<String> for Cmd {
   fn from(program: String) -> Self {
     match program.as_str() {
@@ -43,8 +100,35 @@ impl From<String> for Cmd {
       "pwd" => Cmd::Pwd,
       "cd" => Cmd::Cd,
       "history" => Cmd::History,
+      "read" => Cmd::Read,
+      "true" => Cmd::True,
+      "false" => Cmd::False,
+      "which" => Cmd::Which,
+      "jobs" => Cmd::Jobs,
+      "fg" => Cmd::Fg,
+      "bg" => Cmd::Bg,
+      "kill" => Cmd::Kill,
+      "set" => Cmd::Set,
+      "exec" => Cmd::Exec,
+      "trap" => Cmd::Trap,
+      "compgen" => Cmd::Compgen,
+      "export" => Cmd::Export,
+      "let" => Cmd::Let,
+      "clear" => Cmd::Clear,
+      "help" => Cmd::Help,
+      "cat" => Cmd::Cat,
+      "wait" => Cmd::Wait,
+      "command" => Cmd::Command,
+      "hash" => Cmd::Hash,
+      "declare" => Cmd::Declare,
+      "version" => Cmd::Version,
+      "source" | "." => Cmd::Source,
+      "umask" => Cmd::Umask,
+      "alias" => Cmd::Alias,
+      "shift" => Cmd::Shift,
+      "unalias" => Cmd::Unalias,
       cmd => {
-        if let Some(executable_path) = find_command(cmd) {
+        if let Some(executable_path) = find_hashed_command(cmd) {
           return Cmd::Executable(ExecutableCmd {
             // avoid conversion from cmd.to_string(), by passing value
             cmd: program,
@@ -58,191 +142,1008 @@ impl From<String> for Cmd {
   }
 }
 
+/// Name and version of the shell's build, as printed by `version` and `--version`
+pub fn version() -> String {
+  format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// Output of an executed command: stdout, stderr and the exit status
 #[derive(Debug)]
-pub struct ExecutionOutput(pub Option<CmdOutput>, pub Option<CmdOutput>);
+pub struct ExecutionOutput(pub Option<CmdOutput>, pub Option<CmdOutput>, pub i32);
 
 impl ExecutionOutput {
   pub fn none() -> Self {
-    Self(None, None)
+    Self(None, None, 0)
   }
 
   pub fn stdout<T: Into<String>>(stdout: T) -> Self {
-    Self(Some(CmdOutput::Stdout(stdout.into())), None)
+    Self(Some(CmdOutput::Stdout(stdout.into())), None, 0)
   }
 
   pub fn stdout_bytes(stdout: Vec<u8>) -> Self {
-    Self(Some(CmdOutput::StdoutBytes(stdout)), None)
+    Self(Some(CmdOutput::StdoutBytes(stdout)), None, 0)
   }
 
   pub fn stderr<T: Into<String>>(stderr: T) -> Self {
-    Self(None, Some(CmdOutput::Stderr(stderr.into())))
+    Self(None, Some(CmdOutput::Stderr(stderr.into())), 1)
   }
 
   pub fn stderr_bytes(stderr: Vec<u8>) -> Self {
-    Self(None, Some(CmdOutput::StderrBytes(stderr)))
+    Self(None, Some(CmdOutput::StderrBytes(stderr)), 1)
+  }
+
+  /// Overrides the exit status, defaults are 0 for success and 1 for stderr outputs
+  pub fn with_status(mut self, status: i32) -> Self {
+    self.2 = status;
+    self
+  }
+
+  pub fn status(&self) -> i32 {
+    self.2
   }
 }
 
 impl Cmd {
+  pub fn is_builtin(&self) -> bool {
+    !matches!(self, Self::Executable(_) | Self::Unknown)
+  }
+
+  /// Whether the command reads its stdin (piped input, here-strings and here-documents). Other
+  /// builtins ignore it: only `read` and `cat` among them consume it, and `command` passes it to
+  /// the command it runs.
+  pub fn reads_stdin(&self) -> bool {
+    matches!(
+      self,
+      Self::Executable(_) | Self::Read | Self::Cat | Self::Command
+    )
+  }
+
+  /// Whether the command runs other commands in the shell itself (only `source` does), their
+  /// output is written straight to the shell's streams rather than returned.
+  pub fn runs_commands(&self) -> bool {
+    matches!(self, Self::Source)
+  }
+
+  /// Usage line and description of a builtin, shown by `help`. None for other commands.
+  fn usage(&self) -> Option<(&'static str, &'static str)> {
+    let usage = match self {
+      Self::Exit => (
+        "exit [n]",
+        "Exit the shell with status n, or the last command's status.",
+      ),
+      Self::Echo => (
+        "echo [-neE] [arg ...]",
+        "Write the args to stdout, separated by spaces. -n omits the trailing newline, -e\n\
+         interprets backslash escapes and -E doesn't (the default).",
+      ),
+      Self::Type => (
+        "type [-at] name [name ...]",
+        "Show how each name would be interpreted as a command. -a shows every match and -t only\n\
+         the kind of command.",
+      ),
+      Self::Cd => (
+        "cd [-L|-P] [dir]",
+        "Change the current directory to dir, HOME by default. -P resolves symlinks, -L (the\n\
+         default) doesn't.",
+      ),
+      Self::Pwd => (
+        "pwd [-LP]",
+        "Print the current directory. -P resolves symlinks, -L (the default) doesn't.",
+      ),
+      Self::History => (
+        "history [n] | history -r|-w|-a file",
+        "Show the last n commands, or all of them. -r reads the history from a file, -w writes\n\
+         it and -a appends it.",
+      ),
+      Self::Read => (
+        "read [-r] [-p prompt] [name ...]",
+        "Read a line from stdin into the names, the last one taking the rest of the line.\n\
+         -r keeps backslashes and -p shows a prompt first.",
+      ),
+      Self::True => ("true", "Return a successful status."),
+      Self::False => ("false", "Return an unsuccessful status."),
+      Self::Which => ("which name [name ...]", "Print the path of each command."),
+      Self::Jobs => ("jobs", "List the background and stopped jobs."),
+      Self::Fg => (
+        "fg [job_spec]",
+        "Move a job to the foreground, the current job by default.",
+      ),
+      Self::Bg => ("bg [job_spec]", "Resume a stopped job in the background."),
+      Self::Declare => (
+        "declare [-ipx] [+ix] [name[=value] ...]",
+        "Set variables and their attributes: -i makes them integers, their values being evaluated\n\
+         arithmetically, and -x exports them. + removes an attribute. -p prints the variables.",
+      ),
+      Self::Version => ("version", "Print the shell's name and version."),
+      Self::Shift => (
+        "shift [n]",
+        "Drop the first n positional parameters, 1 by default, renumbering the next ones from $1.\n\
+         Fails without changing them when there are fewer than n.",
+      ),
+      Self::Alias => (
+        "alias [name[=value] ...]",
+        "Define an alias for each name=value, a command named after it running value instead.\n\
+         Names alone print their alias, no args print every alias.",
+      ),
+      Self::Unalias => (
+        "unalias [-a] name [name ...]",
+        "Remove the aliases, every alias with -a.",
+      ),
+      Self::Umask => (
+        "umask [mode]",
+        "Set the mask of the permissions removed from created files to the octal mode, or print\n\
+         the current one.",
+      ),
+      Self::Source => (
+        "source filename [arg ...]",
+        "Run the commands of a file in the current shell, with args as the positional\n\
+         parameters while it runs. Returns the status of the last command run.",
+      ),
+      Self::Hash => (
+        "hash [-r] [name ...]",
+        "Remember the location of each command, or list the remembered ones without names.\n\
+         -r forgets every location. The table is also emptied when PATH changes.",
+      ),
+      Self::Wait => (
+        "wait [job_spec | pid ...]",
+        "Wait for the jobs, every job by default, to finish and return the last one's status.",
+      ),
+      Self::Kill => (
+        "kill [-s sigspec | -signum | -sigspec] pid | jobspec ...",
+        "Send a signal, TERM by default, to processes or jobs.",
+      ),
+      Self::Set => (
+        "set [-eCx] [+eCx] [-o [option]] [+o option] [--] [arg ...]",
+        "Set (-) or unset (+) shell options, or list the variables without args. The args are\n\
+         the new positional parameters.",
+      ),
+      Self::Exec => (
+        "exec [command [arg ...]]",
+        "Replace the shell with the command.",
+      ),
+      Self::Trap => (
+        "trap [-lp] [[arg] signal_spec ...]",
+        "Run arg when the shell receives one of the signals. - resets the signals and -l lists\n\
+         the signal names.",
+      ),
+      Self::Compgen => (
+        "compgen [-cf] [word]",
+        "Print the commands (-c) or files (-f) starting with word.",
+      ),
+      Self::Export => (
+        "export [-p] [name[=value] ...]",
+        "Export the variables to the environment of commands, or list the exported ones.",
+      ),
+      Self::Let => (
+        "let arg [arg ...]",
+        "Evaluate each arg as an arithmetic expression, failing if the last one is 0.",
+      ),
+      Self::Clear => ("clear", "Clear the terminal screen."),
+      Self::Command => (
+        "command [-pVv] command [arg ...]",
+        "Run the command, a builtin or an executable, ignoring aliases. -p looks it up in a\n\
+         default PATH, -v prints how it's resolved and -V describes it.",
+      ),
+      Self::Cat => (
+        "cat [file ...]",
+        "Write the files to stdout, one after the other. Without files, or for `-`, stdin is\n\
+         written.",
+      ),
+      Self::Help => (
+        "help [name ...]",
+        "Show the usage of the builtins, or the details of the named ones.",
+      ),
+      Self::Executable(_) | Self::Unknown => return None,
+    };
+
+    Some(usage)
+  }
+
   pub fn exec(
     &self,
     cmd_args: CmdArgs,
     cmd_input: Option<CmdInput>,
-    history: &mut History,
+    redirection: &Redirection,
+    ctx: &mut ShellContext,
+    process_group: ProcessGroup,
   ) -> ExecutionOutput {
+    // Builtins don't use other file descriptors, but the file is still created (or truncated)
+    if let Redirection::Fd {
+      file_path, append, ..
+    } = redirection
+    {
+      if !matches!(self, Self::Executable(_)) {
+        if let Err(err) = open_fd_redirection(file_path, *append) {
+          return ExecutionOutput::stderr(format!("{}: {}", file_path, err));
+        }
+      }
+    }
+
+    // Input that won't be read is closed now, so an upstream command writing to the pipe can't be
+    // left blocked on it: its next write fails (with SIGPIPE), as with bash
+    let cmd_input = cmd_input.filter(|_| self.reads_stdin());
+
     match self {
-      Self::Exit => exec_exit(cmd_args, history),
+      Self::Exit => exec_exit(cmd_args, ctx),
       Self::Echo => exec_echo(cmd_args),
-      Self::Type => exec_type(cmd_args),
-      Self::Executable(cmd) => exec_executable(cmd, cmd_args, cmd_input),
+      Self::Type => exec_type(cmd_args, &ctx.aliases),
+      Self::Executable(cmd) => {
+        command_hash().record_hit(&cmd.cmd, &cmd.path);
+        exec_executable(cmd, cmd_args, cmd_input, redirection, process_group)
+      }
       Self::Cd => exec_cd(cmd_args),
       Self::Pwd => exec_pwd(cmd_args),
-      Self::History => exec_history(cmd_args, history),
+      Self::History => exec_history(cmd_args, &mut ctx.history),
+      Self::Read => exec_read(cmd_args, cmd_input, &mut ctx.variables),
+      Self::True => ExecutionOutput::none(),
+      Self::False => ExecutionOutput::none().with_status(1),
+      Self::Which => exec_which(cmd_args),
+      Self::Jobs => exec_jobs(cmd_args, &mut ctx.jobs),
+      Self::Fg => exec_fg(cmd_args, &mut ctx.jobs),
+      Self::Bg => exec_bg(cmd_args, &mut ctx.jobs),
+      Self::Kill => exec_kill(cmd_args, &mut ctx.jobs),
+      Self::Wait => exec_wait(cmd_args, &mut ctx.jobs),
+      Self::Set => exec_set(cmd_args, &mut ctx.options, &mut ctx.variables),
+      Self::Exec => exec_exec(cmd_args, redirection),
+      Self::Trap => exec_trap(cmd_args, &mut ctx.traps),
+      Self::Compgen => exec_compgen(cmd_args, &mut ctx.completions),
+      Self::Export => exec_export(cmd_args, &mut ctx.variables),
+      Self::Let => exec_let(cmd_args, &mut ctx.variables),
+      Self::Help => exec_help(cmd_args),
+      Self::Hash => exec_hash(cmd_args, &mut ctx.completions),
+      Self::Declare => exec_declare(cmd_args, &mut ctx.variables),
+      Self::Version => ExecutionOutput::stdout(format!("{}\n", version())),
+      Self::Source => exec_source(cmd_args, ctx),
+      Self::Umask => exec_umask(cmd_args),
+      Self::Alias => exec_alias(cmd_args, &mut ctx.aliases),
+      Self::Shift => exec_shift(cmd_args, &mut ctx.variables),
+      Self::Unalias => exec_unalias(cmd_args, &mut ctx.aliases),
+      // With nothing to read, the external cat is run instead: it streams the files, and reading
+      // the terminal it's stopped by Ctrl-C
+      Self::Cat => match (cmd_input, find_hashed_command("cat")) {
+        (None, Some(path)) => Self::Executable(ExecutableCmd {
+          cmd: "cat".to_string(),
+          path,
+        })
+        .exec(cmd_args, None, redirection, ctx, process_group),
+        (cmd_input, _) => exec_cat(cmd_args, cmd_input),
+      },
+      Self::Command => exec_command(cmd_args, cmd_input, redirection, ctx, process_group),
+      // Like the clear program, the codes are written even when redirected: `clear > file` saves
+      // them, to clear the terminal when the file is printed
+      Self::Clear => {
+        ExecutionOutput::stdout(format!("{}{}", AnsiCode::ClearScreen, AnsiCode::CursorHome))
+      }
       Self::Unknown => ExecutionOutput::none(),
     }
   }
 }
 
-fn exec_exit(cmd_args: CmdArgs, history: &mut History) -> ExecutionOutput {
+fn exec_help(cmd_args: CmdArgs) -> ExecutionOutput {
+  let usage = |name: &str| Cmd::from(name.to_string()).usage();
+
+  let names = &cmd_args[1..];
+  if names.is_empty() {
+    let mut output = String::from("Shell builtins, `help name` shows the details of one:\n\n");
+    for (synopsis, _) in BUILTINS.iter().filter_map(|name| usage(name)) {
+      output.push_str(&format!(" {}\n", synopsis));
+    }
+    return ExecutionOutput::stdout(output);
+  }
+
+  let mut found = String::new();
+  let mut not_found: Vec<String> = Vec::new();
+  for name in names {
+    let Some((synopsis, description)) = usage(name) else {
+      not_found.push(format!("help: no help topics match `{}'", name));
+      continue;
+    };
+
+    found.push_str(&format!("{}: {}\n", name, synopsis));
+    for line in description.lines() {
+      found.push_str(&format!("    {}\n", line.trim_start()));
+    }
+  }
+
+  let status = if not_found.is_empty() { 0 } else { 1 };
+  let stdout = (!found.is_empty()).then_some(CmdOutput::Stdout(found));
+  let stderr = (!not_found.is_empty()).then(|| CmdOutput::Stderr(not_found.join("\n")));
+
+  ExecutionOutput(stdout, stderr, status)
+}
+
+fn exec_command(
+  cmd_args: CmdArgs,
+  cmd_input: Option<CmdInput>,
+  redirection: &Redirection,
+  ctx: &mut ShellContext,
+  process_group: ProcessGroup,
+) -> ExecutionOutput {
+  let options = match parse_options(&cmd_args[1..], "pvV") {
+    Ok(options) => options,
+    Err(err) => return ExecutionOutput::stderr(format!("command: {}", err)).with_status(2),
+  };
+  let Some(name) = options.operands.first() else {
+    return ExecutionOutput::none();
+  };
+
+  // Builtins first, executables are looked up in the default PATH with -p
+  let resolve = |name: &str| match Cmd::from(name.to_string()) {
+    cmd if cmd.is_builtin() || !options.has('p') => cmd,
+    _ => match find_command_in(name, DEFAULT_PATH) {
+      Some(path) => Cmd::Executable(ExecutableCmd {
+        cmd: name.to_string(),
+        path,
+      }),
+      None => Cmd::Unknown,
+    },
+  };
+
+  if options.has('v') || options.has('V') {
+    let mut found: Vec<String> = Vec::new();
+    let mut not_found: Vec<String> = Vec::new();
+    let mut status = 0;
+    for name in &options.operands {
+      match (resolve(name), options.has('V')) {
+        // Only -V reports names that aren't found
+        (Cmd::Unknown, verbose) => {
+          status = 1;
+          if verbose {
+            not_found.push(format!("command: {}: not found", name));
+          }
+        }
+        (Cmd::Executable(exe), true) => found.push(format!("{} is {}", name, exe.path)),
+        (Cmd::Executable(exe), false) => found.push(exe.path),
+        (_, true) => found.push(format!("{} is a shell builtin", name)),
+        (_, false) => found.push(name.clone()),
+      }
+    }
+
+    let stdout = (!found.is_empty()).then(|| CmdOutput::Stdout(format!("{}\n", found.join("\n"))));
+    let stderr = (!not_found.is_empty()).then(|| CmdOutput::Stderr(not_found.join("\n")));
+    return ExecutionOutput(stdout, stderr, status);
+  }
+
+  match resolve(name) {
+    Cmd::Unknown => {
+      ExecutionOutput::stderr(format!("{}: command not found", name)).with_status(127)
+    }
+    cmd => cmd.exec(
+      options.operands.clone(),
+      cmd_input,
+      redirection,
+      ctx,
+      process_group,
+    ),
+  }
+}
+
+fn exec_cat(cmd_args: CmdArgs, input: Option<CmdInput>) -> ExecutionOutput {
+  // Only reading a piped input, it's passed through as is, to be read as it's produced (the output
+  // of `yes | cat` never ends). Files and other inputs are read to their end.
+  let stdin_only = cmd_args.len() == 1 || cmd_args[1..] == ["-"];
+  let input = match (stdin_only, input) {
+    (true, Some(CmdInput::Pipe(stdout))) => {
+      return ExecutionOutput(Some(CmdOutput::Pipe(stdout)), None, 0)
+    }
+    (_, input) => input,
+  };
+
+  let mut input = Some(input);
+  // The input is read once, by the first `-` (or when there are no files), later ones are empty
+  let mut read_input = || -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match input.take() {
+      Some(Some(CmdInput::String(string))) => bytes = string.into_bytes(),
+      Some(Some(CmdInput::Bytes(input_bytes))) => bytes = input_bytes,
+      Some(Some(CmdInput::Pipe(mut stdout))) => {
+        stdout.read_to_end(&mut bytes)?;
+      }
+      // Nothing piped or redirected, and no external cat, the shell's own stdin
+      Some(None) => {
+        io::stdin().read_to_end(&mut bytes)?;
+      }
+      None => {}
+    }
+    Ok(bytes)
+  };
+
+  let files = match cmd_args.len() {
+    1 => vec!["-".to_string()],
+    _ => cmd_args[1..].to_vec(),
+  };
+
+  let mut output: Vec<u8> = Vec::new();
+  let mut errors: Vec<String> = Vec::new();
+  for file in files {
+    let content = match file.as_str() {
+      "-" => read_input(),
+      path => fs::read(path),
+    };
+
+    match content {
+      Ok(content) => output.extend(content),
+      Err(err) => errors.push(format!("cat: {}: {}", file, io_error_message(&err))),
+    }
+  }
+
+  let status = if errors.is_empty() { 0 } else { 1 };
+  let stderr = (!errors.is_empty()).then(|| CmdOutput::Stderr(errors.join("\n")));
+
+  ExecutionOutput(Some(CmdOutput::StdoutBytes(output)), stderr, status)
+}
+
+fn exec_exit(cmd_args: CmdArgs, ctx: &mut ShellContext) -> ExecutionOutput {
   let args = cmd_args
     .iter()
     .map(|arg| arg.as_str())
     .collect::<Vec<&str>>();
 
-  let mut write_history = || {
-    if let Ok(histfile) = env::var("HISTFILE") {
-      history.write_to_file(&histfile, true);
-    }
+  let code = match args.as_slice() {
+    // Same as bash, exit with the status of the last command
+    ["exit"] => ctx.variables.last_status,
+    ["exit", code] => match code.parse::<i64>() {
+      // Exit statuses are 8 bits, out of range codes wrap around
+      Ok(code) => code.rem_euclid(256) as i32,
+      Err(_) => {
+        eprintln!("exit: {}: numeric argument required", code);
+        2
+      }
+    },
+    _ => return ExecutionOutput::stderr("exit: expected 1 arg at most"),
   };
 
-  match args.as_slice() {
-    ["exit"] => {
-      write_history();
-      process::exit(255)
-    }
-    ["exit", code] => {
-      if let Ok(code) = code.parse::<u8>() {
-        write_history();
-        process::exit(code.into());
+  crate::exit_shell(code, ctx)
+}
+
+fn exec_shift(cmd_args: CmdArgs, variables: &mut Variables) -> ExecutionOutput {
+  let count = match cmd_args.get(1..).unwrap_or_default() {
+    [] => 1,
+    [count] => match count.parse::<usize>() {
+      Ok(count) => count,
+      Err(_) => {
+        return ExecutionOutput::stderr(format!("shift: {}: numeric argument required", count))
       }
+    },
+    _ => return ExecutionOutput::stderr("shift: usage: shift [n]").with_status(2),
+  };
+
+  // Same as bash, shifting past the end fails silently
+  match variables.shift(count) {
+    true => ExecutionOutput::none(),
+    false => ExecutionOutput::none().with_status(1),
+  }
+}
+
+fn exec_alias(cmd_args: CmdArgs, aliases: &mut Aliases) -> ExecutionOutput {
+  if cmd_args.len() == 1 {
+    let definitions = aliases.list();
+    return ExecutionOutput::stdout(
+      definitions
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect::<String>(),
+    );
+  }
 
-      ExecutionOutput::stderr("exit: invalid code")
+  let mut output = String::new();
+  let mut errors: Vec<String> = Vec::new();
+  for arg in &cmd_args[1..] {
+    match arg.split_once('=') {
+      Some((name, _)) if !is_valid_alias_name(name) => {
+        errors.push(format!("alias: `{}': invalid alias name", name));
+      }
+      Some((name, value)) => aliases.set(name, value),
+      None => match aliases.get(arg) {
+        Some(value) => output.push_str(&format!("{}\n", aliases::definition(arg, value))),
+        None => errors.push(format!("alias: {}: not found", arg)),
+      },
     }
-    _ => ExecutionOutput::stderr("exit: expected 1 arg at most"),
   }
+
+  let stdout = (!output.is_empty()).then_some(CmdOutput::Stdout(output));
+  let stderr = (!errors.is_empty()).then(|| CmdOutput::Stderr(errors.join("\n")));
+  let status = if errors.is_empty() { 0 } else { 1 };
+  ExecutionOutput(stdout, stderr, status)
 }
 
-fn exec_echo(cmd_args: CmdArgs) -> ExecutionOutput {
-  let mut args = cmd_args[1..].join(" ");
-  args.push('\n'); // echo appends new line unless -n flag is passed
-  ExecutionOutput::stdout(args)
+fn exec_unalias(cmd_args: CmdArgs, aliases: &mut Aliases) -> ExecutionOutput {
+  let names = match cmd_args.get(1).map(|arg| arg.as_str()) {
+    None => {
+      return ExecutionOutput::stderr("unalias: usage: unalias [-a] name [name ...]").with_status(2)
+    }
+    Some("-a") => {
+      aliases.clear();
+      return ExecutionOutput::none();
+    }
+    Some(_) => &cmd_args[1..],
+  };
+
+  let errors = names
+    .iter()
+    .filter(|name| aliases.remove(name).is_none())
+    .map(|name| format!("unalias: {}: not found", name))
+    .collect::<Vec<String>>();
+
+  match errors.is_empty() {
+    true => ExecutionOutput::none(),
+    false => ExecutionOutput::stderr(errors.join("\n")),
+  }
 }
 
-fn exec_type(cmd_args: CmdArgs) -> ExecutionOutput {
+fn exec_umask(cmd_args: CmdArgs) -> ExecutionOutput {
   let args = cmd_args
     .iter()
+    .skip(1)
     .map(|arg| arg.as_str())
     .collect::<Vec<&str>>();
 
   match args.as_slice() {
-    ["type", command] => {
-      let builtin = Cmd::from(command.to_string());
-      match builtin {
-        Cmd::Unknown => ExecutionOutput::stderr(format!("{}: not found", command)),
-        Cmd::Executable(exe) => ExecutionOutput::stdout(format!("{} is {}\n", exe.cmd, exe.path)),
-        _ => ExecutionOutput::stdout(format!("{} is a shell builtin\n", command)),
+    [] => {
+      // The mask can only be read by setting it, it's put back right away. SAFETY: umask has no
+      // memory safety requirements
+      let mask = unsafe {
+        let mask = libc::umask(0);
+        libc::umask(mask);
+        mask
+      };
+      ExecutionOutput::stdout(format!("{:04o}\n", mask))
+    }
+    // Only octal digits, `from_str_radix` would also take a leading sign
+    [mode] => match u32::from_str_radix(mode, 8) {
+      Ok(mask) if mask <= 0o777 && mode.chars().all(|c| c.is_digit(8)) => {
+        // SAFETY: umask has no memory safety requirements
+        unsafe { libc::umask(mask as libc::mode_t) };
+        ExecutionOutput::none()
+      }
+      _ => ExecutionOutput::stderr(format!(
+        "umask: {}: invalid octal number\numask: usage: umask [mode]",
+        mode
+      ))
+      .with_status(1),
+    },
+    _ => ExecutionOutput::stderr("umask: usage: umask [mode]").with_status(2),
+  }
+}
+
+fn exec_source(cmd_args: CmdArgs, ctx: &mut ShellContext) -> ExecutionOutput {
+  let Some(file_path) = cmd_args.get(1) else {
+    return ExecutionOutput::stderr(format!("{}: filename argument required", cmd_args[0]))
+      .with_status(2);
+  };
+
+  let content = match fs::read_to_string(file_path) {
+    Ok(content) => content,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+      return ExecutionOutput::stderr(format!("{}: No such file or directory", file_path));
+    }
+    Err(err) => return ExecutionOutput::stderr(format!("{}: {}", file_path, err)),
+  };
+
+  // Args replace the positional parameters until the file is done, `$0` is kept
+  let saved = (cmd_args.len() > 2).then(|| {
+    let saved = ctx.variables.args().to_vec();
+    ctx.variables.set_args(&cmd_args[2..]);
+    saved
+  });
+
+  let status = crate::run_lines(&content, ctx);
+
+  if let Some(saved) = saved {
+    ctx.variables.set_args(&saved);
+  }
+
+  ExecutionOutput::none().with_status(status)
+}
+
+fn exec_echo(cmd_args: CmdArgs) -> ExecutionOutput {
+  // Leading args made up only of known flags are options, anything else (like `-x`) is printed
+  // literally, same as bash
+  let options = match parse_known_options(&cmd_args[1..], "neE") {
+    Ok(options) => options,
+    Err(err) => return ExecutionOutput::stderr(format!("echo: {}", err)).with_status(2),
+  };
+  let trailing_newline = !options.has('n');
+  // The last of `-e` and `-E` wins
+  let interpret = options.flags().filter(|flag| *flag != 'n').last() == Some('e');
+
+  let mut output = options.operands.join(" ");
+
+  if interpret {
+    let (interpreted, stop) = interpret_escapes(&output);
+    // `\c` suppresses all further output, including the trailing newline
+    if stop {
+      return ExecutionOutput::stdout(interpreted);
+    }
+    output = interpreted;
+  }
+
+  if trailing_newline {
+    output.push('\n');
+  }
+
+  ExecutionOutput::stdout(output)
+}
+
+fn exec_type(cmd_args: CmdArgs, aliases: &Aliases) -> ExecutionOutput {
+  let options = match parse_options(&cmd_args[1..], "at") {
+    Ok(options) => options,
+    Err(err) => return ExecutionOutput::stderr(format!("type: {}", err)),
+  };
+  let all = options.has('a');
+  // Print only the kind of command (`builtin`, `file`), handy for scripts
+  let kind_only = options.has('t');
+  let names = &options.operands;
+
+  if names.is_empty() {
+    return ExecutionOutput::stderr("type: expected at least 1 arg");
+  }
+
+  let mut found: Vec<String> = Vec::new();
+  let mut not_found: Vec<String> = Vec::new();
+  let mut status = 0;
+
+  for name in names {
+    // Aliases take precedence over builtins and executables, as they replace the command name
+    let alias = aliases.get(name);
+    if let Some(value) = alias {
+      found.push(match kind_only {
+        true => "alias".to_string(),
+        false => format!("{} is aliased to '{}'", name, value),
+      });
+      if !all {
+        continue;
+      }
+    }
+
+    let cmd = Cmd::from(name.clone());
+    let is_builtin = cmd.is_builtin();
+    if is_builtin {
+      found.push(if kind_only {
+        "builtin".to_string()
+      } else {
+        format!("{} is a shell builtin", name)
+      });
+      // Without -a, the first match is the only one reported
+      if !all {
+        continue;
+      }
+    }
+
+    let paths = match cmd {
+      Cmd::Executable(exe) if !all => vec![exe.path],
+      Cmd::Executable(_) | Cmd::Unknown if !all => Vec::new(),
+      _ => find_all_command_paths(name),
+    };
+
+    if paths.is_empty() && !is_builtin && alias.is_none() {
+      status = 1;
+      // -t reports unknown names only through the exit status
+      if !kind_only {
+        not_found.push(format!("{}: not found", name));
       }
     }
-    _ => ExecutionOutput::stderr("type: expected 1 arg"),
+
+    for path in paths {
+      found.push(if kind_only {
+        "file".to_string()
+      } else {
+        format!("{} is {}", name, path)
+      });
+    }
+  }
+
+  let stdout = (!found.is_empty()).then(|| CmdOutput::Stdout(format!("{}\n", found.join("\n"))));
+  let stderr = (!not_found.is_empty()).then(|| CmdOutput::Stderr(not_found.join("\n")));
+
+  ExecutionOutput(stdout, stderr, status)
+}
+
+fn exec_hash(cmd_args: CmdArgs, completions: &mut CmdCompletions) -> ExecutionOutput {
+  let options = match parse_options(&cmd_args[1..], "r") {
+    Ok(options) => options,
+    Err(err) => return ExecutionOutput::stderr(format!("hash: {}", err)).with_status(2),
+  };
+
+  let mut hash = command_hash();
+  // Forgetting the locations also rescans `PATH` for the commands to complete
+  if options.has('r') {
+    hash.clear();
+    completions.invalidate();
+  }
+
+  if options.operands.is_empty() {
+    if options.has('r') {
+      return ExecutionOutput::none();
+    }
+    if hash.entries().next().is_none() {
+      return ExecutionOutput::stdout("hash: hash table empty\n");
+    }
+
+    let mut output = String::from("hits\tcommand\n");
+    for (_, entry) in hash.entries() {
+      output.push_str(&format!("{:>4}\t{}\n", entry.hits, entry.path));
+    }
+    return ExecutionOutput::stdout(output);
+  }
+
+  let path = get_path().unwrap_or_default();
+  let mut not_found: Vec<String> = Vec::new();
+  for name in &options.operands {
+    // Builtins are never looked up, as they always take precedence
+    let is_builtin = BUILTINS.contains(&name.as_str());
+    if !is_builtin && (name.contains('/') || hash.remember(name, &path).is_none()) {
+      not_found.push(format!("hash: {}: not found", name));
+    }
+  }
+
+  if not_found.is_empty() {
+    ExecutionOutput::none()
+  } else {
+    ExecutionOutput::stderr(not_found.join("\n")).with_status(1)
+  }
+}
+
+fn exec_which(cmd_args: CmdArgs) -> ExecutionOutput {
+  if cmd_args.len() < 2 {
+    return ExecutionOutput::stderr("which: expected at least 1 arg");
+  }
+
+  let mut found: Vec<String> = Vec::new();
+  let mut not_found: Vec<String> = Vec::new();
+
+  for name in cmd_args.iter().skip(1) {
+    match Cmd::from(name.clone()) {
+      Cmd::Unknown => not_found.push(format!("{} not found", name)),
+      Cmd::Executable(exe) => found.push(exe.path),
+      _ => found.push(format!("{}: shell builtin", name)),
+    }
   }
+
+  let stdout = (!found.is_empty()).then(|| CmdOutput::Stdout(format!("{}\n", found.join("\n"))));
+  let stderr = (!not_found.is_empty()).then(|| CmdOutput::Stderr(not_found.join("\n")));
+  let status = if not_found.is_empty() { 0 } else { 1 };
+
+  ExecutionOutput(stdout, stderr, status)
+}
+
+fn exec_exec(cmd_args: CmdArgs, redirection: &Redirection) -> ExecutionOutput {
+  // Without a command, redirections would apply to the shell itself, which isn't supported
+  let Some(name) = cmd_args.get(1) else {
+    return match redirection {
+      Redirection::None => ExecutionOutput::none(),
+      _ => ExecutionOutput::stderr("exec: redirecting the shell's own streams is not supported"),
+    };
+  };
+
+  let Some(path) = find_command(name) else {
+    return ExecutionOutput::stderr(format!("exec: {}: not found", name)).with_status(127);
+  };
+
+  let mut command = process::Command::new(path);
+  // Keep the name the command was called with as its argv[0]
+  command.arg0(name).args(cmd_args.iter().skip(2));
+
+  let redirected = match redirection {
+    Redirection::Stdout {
+      file_path, append, ..
+    } => open_fd_redirection(file_path, *append).map(|file| {
+      command.stdout(file);
+    }),
+    Redirection::Stderr {
+      file_path, append, ..
+    } => open_fd_redirection(file_path, *append).map(|file| {
+      command.stderr(file);
+    }),
+    Redirection::Both { file_path, append } => {
+      open_fd_redirection(file_path, *append).and_then(|file| {
+        command.stderr(file.try_clone()?);
+        command.stdout(file);
+        Ok(())
+      })
+    }
+    _ => Ok(()),
+  };
+  if let Err(err) = redirected {
+    return ExecutionOutput::stderr(format!("exec: {}", err));
+  }
+
+  // Only returns if the process image couldn't be replaced
+  let err = command.exec();
+
+  ExecutionOutput::stderr(format!("exec: {}: {}", name, err)).with_status(126)
 }
 
 fn exec_executable(
   executable_cmd: &ExecutableCmd,
   cmd_args: CmdArgs,
   input: Option<CmdInput>,
+  redirection: &Redirection,
+  process_group: ProcessGroup,
 ) -> ExecutionOutput {
   let args = cmd_args
     .iter()
     .map(|arg| arg.as_str())
     .collect::<Vec<&str>>();
 
-  let (stdin, data) = match input {
-    Some(CmdInput::Pipe(stdio)) => (stdio, None),
+  let (mut stdin, mut data) = match input {
+    Some(CmdInput::Pipe(stdout)) => (Stdio::from(stdout), None),
     Some(CmdInput::String(string)) => (Stdio::piped(), Some(CmdInput::String(string))),
     Some(CmdInput::Bytes(bytes)) => (Stdio::piped(), Some(CmdInput::Bytes(bytes))),
     None => (Stdio::inherit(), None),
   };
 
-  let command = process::Command::new(executable_cmd.cmd.clone())
+  let mut command = process::Command::new(&executable_cmd.path);
+  command
+    .arg0(&executable_cmd.cmd)
     .args(args.iter().skip(1))
     // INFO: Stdio::piped makes the child not write it to stdout & stderr that is inherited from the
     // terminal session
-    .stdin(stdin)
     .stdout(Stdio::piped())
-    .stderr(Stdio::piped())
-    .spawn();
+    .stderr(Stdio::piped());
+
+  command.process_group(process_group.pgid.unwrap_or(0));
+  if process_group.foreground {
+    // Take the terminal before exec, a program reading it right away would otherwise be stopped
+    // SAFETY: only signal masks and the terminal's group are changed, all async-signal-safe
+    unsafe {
+      command.pre_exec(|| {
+        set_terminal_foreground(libc::getpgrp());
+        Ok(())
+      });
+    }
+  }
+
+  // Kept alive until the child is spawned, as its descriptor is duplicated in the child
+  let mut _fd_file: Option<File> = None;
+  if let Redirection::Fd {
+    fd,
+    file_path,
+    append,
+    ..
+  } = redirection
+  {
+    let file = match open_fd_redirection(file_path, *append) {
+      Ok(file) => file,
+      Err(err) => return ExecutionOutput::stderr(format!("{}: {}", file_path, err)),
+    };
+
+    if *fd == 0 {
+      // Redirection takes precedence over piped input
+      stdin = Stdio::from(file);
+      data = None;
+    } else {
+      let (source, target) = (file.as_raw_fd(), *fd);
+      // SAFETY: only dup2 and fcntl are called between fork and exec, both async-signal-safe
+      unsafe {
+        command.pre_exec(move || {
+          // dup2 onto the same descriptor is a no-op that keeps close-on-exec, clear it instead
+          let result = if source == target {
+            libc::fcntl(source, libc::F_SETFD, 0)
+          } else {
+            libc::dup2(source, target)
+          };
+          if result == -1 {
+            return Err(io::Error::last_os_error());
+          }
+          Ok(())
+        });
+      }
+      _fd_file = Some(file);
+    }
+  }
+
+  let command = command.stdin(stdin).spawn();
 
   match command {
     Ok(mut child) => {
-      match data {
-        Some(CmdInput::String(string)) => {
-          if let Some(mut stdin) = child.stdin.take() {
-            stdin.write(string.as_bytes()).unwrap();
-          }
-        }
-        Some(CmdInput::Bytes(bytes)) => {
-          if let Some(mut stdin) = child.stdin.take() {
-            stdin.write(bytes.as_slice()).unwrap();
-          }
-        }
-        _ => {}
+      let data = match data {
+        Some(CmdInput::String(string)) => Some(string.into_bytes()),
+        Some(CmdInput::Bytes(bytes)) => Some(bytes),
+        _ => None,
+      };
+      // Fed from another thread, the child's output has to be read while it's fed (its pipe would
+      // fill up otherwise). Write errors mean the child stopped reading (like `head`), and exited.
+      if let (Some(data), Some(mut stdin)) = (data, child.stdin.take()) {
+        thread::spawn(move || {
+          let _ = stdin.write_all(&data);
+        });
       }
-      ExecutionOutput(Some(CmdOutput::Stream(child)), None)
+      ExecutionOutput(Some(CmdOutput::Stream(child)), None, 0)
     }
     Err(_) => ExecutionOutput::stderr(format!("{}: failed to execute", executable_cmd.cmd)),
   }
 }
 
 fn exec_cd(cmd_args: CmdArgs) -> ExecutionOutput {
-  let args = cmd_args
-    .iter()
-    .map(|arg| arg.as_str())
-    .collect::<Vec<&str>>();
+  let mut args = cmd_args.iter().skip(1).map(|arg| arg.as_str()).peekable();
 
-  let (path, cwd): (&str, io::Result<()>) = match args.as_slice() {
-    ["cd"] => ("~", env::set_current_dir(expand_tilda(&"~"))),
-    ["cd", path] => {
-      if path.starts_with("~") {
-        (path, env::set_current_dir(expand_tilda(path)))
-      } else {
-        (path, env::set_current_dir(path))
-      }
-    }
-    _ => {
-      return ExecutionOutput::stderr("cd: expected 1 arg at most");
+  // `-L` (default) follows `..` textually from `PWD`, `-P` resolves symlinks
+  let mut physical = false;
+  while let Some(flag) = args.next_if(|arg| arg.starts_with('-') && arg.len() > 1) {
+    match flag {
+      "-L" => physical = false,
+      "-P" => physical = true,
+      flag => return ExecutionOutput::stderr(format!("cd: {}: invalid option", flag)),
     }
+  }
+
+  let path = match (args.next(), args.next()) {
+    (None, _) => match env::var("HOME") {
+      Ok(home) => home,
+      Err(_) => return ExecutionOutput::stderr("cd: HOME not set"),
+    },
+    (Some(path), None) => expand_tilda(&path),
+    _ => return ExecutionOutput::stderr("cd: expected 1 arg at most"),
   };
 
-  match cwd {
-    Ok(_) => ExecutionOutput::none(),
-    Err(_) => ExecutionOutput::stderr(format!("cd: {}: No such file or directory", path)),
+  // Read before changing directory, `PWD` no longer matches it afterwards
+  let old_pwd = logical_cwd();
+  // Keep `PWD` logical, unless asked to resolve the physical directory
+  let target = match physical {
+    true => canonicalize_physical(&path, &old_pwd).ok(),
+    false => Some(canonicalize_logical(&path, &old_pwd)),
+  };
+  let Some(target) = target.filter(|target| env::set_current_dir(target).is_ok()) else {
+    return ExecutionOutput::stderr(format!("cd: {}: No such file or directory", path));
+  };
+
+  env::set_var("PWD", target);
+  env::set_var("OLDPWD", old_pwd);
+
+  ExecutionOutput::none()
+}
+
+/// Current directory as the user navigated to it, i.e. `PWD` when it's still valid, symlinks
+/// unresolved
+fn logical_cwd() -> String {
+  let physical = env::current_dir().ok();
+  if let Ok(pwd) = env::var("PWD") {
+    let is_current =
+      Path::new(&pwd).is_absolute() && fs::canonicalize(&pwd).ok().as_ref() == physical.as_ref();
+    if is_current {
+      return pwd;
+    }
   }
+
+  physical
+    .map(|path| path.display().to_string())
+    .unwrap_or_else(|| "/".to_string())
 }
 
 fn exec_pwd(cmd_args: CmdArgs) -> ExecutionOutput {
-  let args = cmd_args
-    .iter()
-    .map(|arg| arg.as_str())
-    .collect::<Vec<&str>>();
+  let mut args = cmd_args.iter().skip(1).map(|arg| arg.as_str()).peekable();
 
-  match args.as_slice() {
-    ["pwd"] => {
-      let current_dir = env::current_dir().unwrap();
-      ExecutionOutput::stdout(format!("{}\n", current_dir.display()))
+  // `-L` (default) prints the logical `PWD`, `-P` the directory with symlinks resolved
+  let mut physical = false;
+  while let Some(flag) = args.next_if(|arg| arg.starts_with('-') && arg.len() > 1) {
+    match flag {
+      "-L" => physical = false,
+      "-P" => physical = true,
+      flag => {
+        return ExecutionOutput::stderr(format!(
+          "pwd: {}: invalid option\npwd: usage: pwd [-LP]",
+          flag
+        ))
+        .with_status(2)
+      }
     }
-    _ => ExecutionOutput::stderr("pwd: expected 0 args"),
   }
+
+  if args.next().is_some() {
+    return ExecutionOutput::stderr("pwd: expected 0 args");
+  }
+
+  let current_dir = if physical {
+    match env::current_dir() {
+      Ok(current_dir) => current_dir.display().to_string(),
+      Err(err) => return ExecutionOutput::stderr(format!("pwd: {}", err)),
+    }
+  } else {
+    logical_cwd()
+  };
+
+  ExecutionOutput::stdout(format!("{}\n", current_dir))
 }
 
 fn exec_history(cmd_args: CmdArgs, history: &mut History) -> ExecutionOutput {
@@ -269,14 +1170,7 @@ fn exec_history(cmd_args: CmdArgs, history: &mut History) -> ExecutionOutput {
       let mut append_write = false;
 
       let mut iter = args.iter();
-      loop {
-        let arg = match iter.next() {
-          Some(arg) => arg,
-          None => {
-            break;
-          }
-        };
-
+      while let Some(arg) = iter.next() {
         match *arg {
           "-r" => {
             if let Some(path) = iter.next() {
@@ -305,12 +1199,16 @@ fn exec_history(cmd_args: CmdArgs, history: &mut History) -> ExecutionOutput {
       }
 
       if let Some(file_path) = read_file_path {
-        history.extend_from_file(file_path);
+        if let Err(err) = history.extend_from_file(file_path) {
+          return ExecutionOutput::stderr(format!("history: {}: {}", file_path, err));
+        }
         return ExecutionOutput::none();
       }
 
       if let Some(file_path) = write_file_path {
-        history.write_to_file(file_path, append_write);
+        if let Err(err) = history.write_to_file(file_path, append_write) {
+          return ExecutionOutput::stderr(format!("history: {}: {}", file_path, err));
+        }
         return ExecutionOutput::none();
       }
 
@@ -327,3 +1225,672 @@ fn exec_history(cmd_args: CmdArgs, history: &mut History) -> ExecutionOutput {
 
   ExecutionOutput::stdout(output)
 }
+
+/// Reads a line into the named variables: it's split into fields on `IFS`, the last variable
+/// getting the rest of the line and missing fields leaving variables empty
+fn exec_read(
+  cmd_args: CmdArgs,
+  input: Option<CmdInput>,
+  variables: &mut Variables,
+) -> ExecutionOutput {
+  let mut prompt: Option<&str> = None;
+  let mut raw = false;
+  let mut names: Vec<&str> = Vec::new();
+
+  let mut iter = cmd_args.iter().skip(1);
+  while let Some(arg) = iter.next() {
+    match arg.as_str() {
+      "-p" => {
+        if let Some(value) = iter.next() {
+          prompt = Some(value);
+        } else {
+          return ExecutionOutput::stderr("read: -p: option requires an argument");
+        }
+      }
+      "-r" => raw = true,
+      name if is_valid_identifier(name) => names.push(name),
+      name => return ExecutionOutput::stderr(format!("read: `{}': not a valid identifier", name)),
+    }
+  }
+
+  let line = match input {
+    Some(CmdInput::String(string)) => string.lines().next().map(String::from),
+    Some(CmdInput::Bytes(bytes)) => String::from_utf8_lossy(&bytes)
+      .lines()
+      .next()
+      .map(String::from),
+    Some(CmdInput::Pipe(stdout)) => io::BufReader::new(stdout)
+      .lines()
+      .next()
+      .and_then(|line| line.ok()),
+    None => {
+      if let Some(prompt) = prompt {
+        // Prompt has to be visible before blocking on stdin, so bypass the output writer
+        eprint!("{}", prompt);
+        let _ = io::stderr().flush();
+      }
+
+      // Terminal is back in cooked mode while commands run, so the line is read as is. Unbuffered,
+      // as the rest of stdin may be the script the shell is reading.
+      read_line()
+        .ok()
+        .flatten()
+        .map(|line| line.trim_end_matches('\r').to_string())
+    }
+  };
+
+  let value = match line {
+    Some(line) if raw => line,
+    // Without -r, backslash escapes the next char
+    Some(line) => {
+      let mut value = String::new();
+      let mut chars = line.chars();
+      while let Some(char) = chars.next() {
+        match char {
+          '\\' => value.extend(chars.next()),
+          char => value.push(char),
+        }
+      }
+      value
+    }
+    None => {
+      for name in names.iter().chain(names.is_empty().then_some(&"REPLY")) {
+        variables.set(name, "");
+      }
+      return ExecutionOutput::none().with_status(1);
+    }
+  };
+
+  // Same as bash, the line is stored as is in REPLY when no name is given
+  if names.is_empty() {
+    variables.set("REPLY", &value);
+    return ExecutionOutput::none();
+  }
+
+  let ifs = variables.ifs();
+  let mut last = String::new();
+  let mut fields = Vec::new();
+  split_fields(&value, &ifs, names.len(), &mut last, &mut fields);
+  // The rest of the line loses its trailing separators
+  let last = last.trim_end_matches(|char: char| char.is_whitespace() && ifs.contains(char));
+  fields.push(last.to_string());
+
+  for (index, name) in names.iter().enumerate() {
+    variables.set(name, fields.get(index).map_or("", |field| field.as_str()));
+  }
+  ExecutionOutput::none()
+}
+
+fn exec_jobs(cmd_args: CmdArgs, jobs: &mut Jobs) -> ExecutionOutput {
+  if cmd_args.len() > 1 {
+    return ExecutionOutput::stderr("jobs: expected 0 args");
+  }
+
+  jobs.reap();
+
+  let mut output = String::new();
+  for line in jobs.list() {
+    output.push_str(format!("{}\n", line).as_str());
+  }
+
+  // Finished jobs are reported once, here instead of before the next prompt
+  jobs.drain_finished();
+
+  ExecutionOutput::stdout(output)
+}
+
+/// Resolves the job spec given to `fg`/`bg`, defaulting to the current job
+fn job_id_from_args(cmd_args: &CmdArgs, jobs: &Jobs) -> Option<usize> {
+  let spec = cmd_args.get(1).map(|arg| arg.as_str()).unwrap_or("%+");
+  // A bare number is a job id too
+  let spec = match spec.starts_with('%') {
+    true => spec.to_string(),
+    false => format!("%{}", spec),
+  };
+
+  jobs.resolve(&spec).map(|job| job.id)
+}
+
+fn exec_fg(cmd_args: CmdArgs, jobs: &mut Jobs) -> ExecutionOutput {
+  jobs.reap();
+  let Some(id) = job_id_from_args(&cmd_args, jobs) else {
+    return ExecutionOutput::stderr("fg: no such job");
+  };
+
+  let job = jobs.iter().find(|job| job.id == id).unwrap();
+  let command = job.command.clone();
+  // The job runs before this builtin returns, announce it right away
+  println!("{}", command);
+  let _ = io::stdout().flush();
+
+  match jobs.foreground(id) {
+    Ok(WaitStatus::Exited(status)) => {
+      // Ctrl-C is echoed as `^C` without a line break, start the next prompt on a new line
+      if status.signal() == Some(libc::SIGINT) {
+        println!();
+      }
+      ExecutionOutput::none().with_status(status_code(status))
+    }
+    Ok(WaitStatus::Stopped) => ExecutionOutput::stdout(format!(
+      "\n[{}]+  Stopped                 {}\n",
+      id, command
+    ))
+    .with_status(128 + libc::SIGTSTP),
+    Ok(WaitStatus::Continued) => ExecutionOutput::none(),
+    Err(err) => ExecutionOutput::stderr(format!("fg: {}", err)),
+  }
+}
+
+fn exec_wait(cmd_args: CmdArgs, jobs: &mut Jobs) -> ExecutionOutput {
+  jobs.reap();
+
+  // Without args, every job that isn't stopped (it would never finish)
+  let mut errors: Vec<String> = Vec::new();
+  let ids: Vec<usize> = match cmd_args.len() {
+    1 => jobs
+      .iter()
+      .filter(|job| job.state != JobState::Stopped)
+      .map(|job| job.id)
+      .collect(),
+    _ => cmd_args[1..]
+      .iter()
+      .filter_map(|spec| {
+        let job = match spec.parse::<u32>() {
+          Ok(pid) => jobs.iter().find(|job| job.pid == pid),
+          Err(_) => jobs.resolve(spec),
+        };
+        if job.is_none() {
+          errors.push(match spec.starts_with('%') {
+            true => format!("wait: {}: no such job", spec),
+            false => format!("wait: pid {} is not a child of this shell", spec),
+          });
+        }
+        job.map(|job| job.id)
+      })
+      .collect(),
+  };
+
+  let mut notices = String::new();
+  // Same as bash, 0 when waiting for every job, the last job's status otherwise
+  let mut status = if errors.is_empty() { 0 } else { 127 };
+  for id in ids {
+    match jobs.wait(id) {
+      Ok(Some((job_status, notice))) => {
+        notices.push_str(&format!("{}\n", notice));
+        if cmd_args.len() > 1 {
+          status = status_code(job_status);
+        }
+      }
+      // Interrupted with Ctrl-C
+      Ok(None) => {
+        println!();
+        status = 128 + libc::SIGINT;
+        break;
+      }
+      Err(err) => errors.push(format!("wait: {}", err)),
+    }
+  }
+
+  let stdout = (!notices.is_empty()).then_some(CmdOutput::Stdout(notices));
+  let stderr = (!errors.is_empty()).then(|| CmdOutput::Stderr(errors.join("\n")));
+
+  ExecutionOutput(stdout, stderr, status)
+}
+
+fn exec_bg(cmd_args: CmdArgs, jobs: &mut Jobs) -> ExecutionOutput {
+  jobs.reap();
+  let Some(id) = job_id_from_args(&cmd_args, jobs) else {
+    return ExecutionOutput::stderr("bg: no such job");
+  };
+
+  match jobs.background(id) {
+    Ok(job) => ExecutionOutput::stdout(format!("[{}]+ {} &\n", job.id, job.command)),
+    Err(err) => ExecutionOutput::stderr(format!("bg: {}", err)),
+  }
+}
+
+fn exec_kill(cmd_args: CmdArgs, jobs: &mut Jobs) -> ExecutionOutput {
+  let mut signal = libc::SIGTERM;
+  let mut targets = cmd_args.iter().skip(1).peekable();
+
+  // Signal is given as `-s NAME`, `-NAME` or `-NUMBER`
+  if let Some(flag) = targets.next_if(|arg| arg.starts_with('-') && arg.len() > 1) {
+    let name = match flag.as_str() {
+      "-s" => match targets.next() {
+        Some(name) => name.as_str(),
+        None => return ExecutionOutput::stderr("kill: -s: option requires an argument"),
+      },
+      flag => &flag[1..],
+    };
+
+    signal = match signals::parse_signal(name) {
+      Some(signal) => signal,
+      None => {
+        return ExecutionOutput::stderr(format!("kill: {}: invalid signal specification", name))
+      }
+    };
+  }
+
+  if targets.peek().is_none() {
+    return ExecutionOutput::stderr(
+      "kill: usage: kill [-s sigspec | -signum | -sigspec] pid | jobspec ...",
+    );
+  }
+
+  let mut errors: Vec<String> = Vec::new();
+  for target in targets {
+    let pid = if target.starts_with('%') {
+      match jobs.resolve(target) {
+        Some(job) => job.pid as i32,
+        None => {
+          errors.push(format!("kill: {}: no such job", target));
+          continue;
+        }
+      }
+    } else {
+      match target.parse::<i32>() {
+        Ok(pid) => pid,
+        Err(_) => {
+          errors.push(format!(
+            "kill: {}: arguments must be process or job IDs",
+            target
+          ));
+          continue;
+        }
+      }
+    };
+
+    if signals::send_signal(pid, signal).is_err() {
+      errors.push(format!("kill: ({}) - No such process", pid));
+    }
+  }
+
+  if errors.is_empty() {
+    ExecutionOutput::none()
+  } else {
+    ExecutionOutput::stderr(errors.join("\n"))
+  }
+}
+
+fn exec_trap(cmd_args: CmdArgs, traps: &mut Traps) -> ExecutionOutput {
+  let args = cmd_args
+    .iter()
+    .skip(1)
+    .map(|arg| arg.as_str())
+    .collect::<Vec<&str>>();
+
+  let (command, events) = match args.as_slice() {
+    [] | ["-p"] => {
+      let handlers = traps.list();
+      return ExecutionOutput::stdout(
+        handlers
+          .iter()
+          .map(|line| format!("{}\n", line))
+          .collect::<String>(),
+      );
+    }
+    ["-l"] => {
+      let signals = signals::SIGNALS
+        .iter()
+        .map(|(name, number)| format!("{}) SIG{}\n", number, name))
+        .collect::<String>();
+      return ExecutionOutput::stdout(signals);
+    }
+    [_] => return ExecutionOutput::stderr("trap: usage: trap [-lp] [[arg] signal_spec ...]"),
+    [command, events @ ..] => (*command, events),
+  };
+
+  let mut errors: Vec<String> = Vec::new();
+  for event in events {
+    // `EXIT` (or 0) is the shell exiting, anything else is a signal
+    let name = match *event {
+      "EXIT" | "0" => Some("EXIT"),
+      event => signals::parse_signal(event).and_then(signals::signal_name),
+    };
+
+    match name {
+      // `-` resets the handler
+      Some(name) if command == "-" => {
+        traps.remove(name);
+      }
+      Some(name) => traps.set(name, command),
+      None => errors.push(format!("trap: {}: invalid signal specification", event)),
+    }
+  }
+
+  if errors.is_empty() {
+    ExecutionOutput::none()
+  } else {
+    ExecutionOutput::stderr(errors.join("\n"))
+  }
+}
+
+/// Lists the possible completions of a word, one per line: commands with `-c`, files with `-f`
+fn exec_compgen(cmd_args: CmdArgs, completions: &mut CmdCompletions) -> ExecutionOutput {
+  let usage = "compgen: usage: compgen [-cf] [word]";
+  let mut commands = false;
+  let mut files = false;
+  let mut word = None;
+
+  for arg in cmd_args.iter().skip(1) {
+    match arg.strip_prefix('-') {
+      Some(flags) if !flags.is_empty() && word.is_none() => {
+        for flag in flags.chars() {
+          match flag {
+            'c' => commands = true,
+            'f' => files = true,
+            _ => {
+              return ExecutionOutput::stderr(format!(
+                "compgen: -{}: invalid option\n{}",
+                flag, usage
+              ))
+              .with_status(2)
+            }
+          }
+        }
+      }
+      _ if word.is_none() => word = Some(arg.as_str()),
+      _ => return ExecutionOutput::stderr(usage).with_status(2),
+    }
+  }
+  let word = word.unwrap_or_default();
+
+  let mut matches: Vec<String> = Vec::new();
+  if commands {
+    // Shared with tab completion, only rebuilt when `PATH` changed
+    let trie = completions.get();
+    // Completions need a prefix, without one every command matches
+    match word.is_empty() {
+      true => matches.extend(trie.words()),
+      false => matches.extend(trie.get_completions(word)),
+    }
+  }
+  if files {
+    matches.extend(file_completions(word));
+  }
+  matches.sort();
+  matches.dedup();
+
+  if matches.is_empty() {
+    return ExecutionOutput::none().with_status(1);
+  }
+
+  ExecutionOutput::stdout(
+    matches
+      .iter()
+      .map(|line| format!("{}\n", line))
+      .collect::<String>(),
+  )
+}
+
+/// Exports variables to the environment of the commands run afterwards, `NAME=value` sets the
+/// value too. Without names, lists the exported variables.
+fn exec_export(cmd_args: CmdArgs, variables: &mut Variables) -> ExecutionOutput {
+  let names = match cmd_args.get(1).map(|arg| arg.as_str()) {
+    None | Some("-p") if cmd_args.len() <= 2 => {
+      let mut exported = env::vars()
+        .map(|(name, value)| declaration("x", &name, Some(&value)))
+        .collect::<Vec<String>>();
+      exported.sort();
+      return ExecutionOutput::stdout(exported.concat());
+    }
+    _ => &cmd_args[1..],
+  };
+
+  let mut errors: Vec<String> = Vec::new();
+  for arg in names {
+    match parse_assignment(arg) {
+      Some((name, value)) => variables.export(name, Some(value)),
+      None if is_valid_identifier(arg) => variables.export(arg, None),
+      None => errors.push(format!("export: `{}': not a valid identifier", arg)),
+    }
+  }
+
+  if errors.is_empty() {
+    ExecutionOutput::none()
+  } else {
+    ExecutionOutput::stderr(errors.join("\n"))
+  }
+}
+
+/// A variable as the `declare` command setting it, like `declare -x NAME="value"`, with a line break
+fn declaration(attributes: &str, name: &str, value: Option<&str>) -> String {
+  let attributes = match attributes {
+    "" => "-",
+    attributes => attributes,
+  };
+  let Some(value) = value else {
+    return format!("declare -{} {}\n", attributes, name);
+  };
+
+  let value = value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('$', "\\$")
+    .replace('`', "\\`");
+  format!("declare -{} {}=\"{}\"\n", attributes, name, value)
+}
+
+/// Sets variables with attributes: `-i` makes them integers, whose assigned values are evaluated
+/// arithmetically, and `-x` exports them (`+` removes the attributes). `-p`, or no args, prints the
+/// variables as `declare` commands.
+fn exec_declare(cmd_args: CmdArgs, variables: &mut Variables) -> ExecutionOutput {
+  let mut args = cmd_args.iter().skip(1).peekable();
+  let mut attributes: Vec<(char, bool)> = Vec::new();
+  let mut print = false;
+
+  while let Some(arg) = args.next_if(|arg| arg.len() > 1 && arg.starts_with(['-', '+'])) {
+    if arg == "--" {
+      break;
+    }
+
+    let enable = arg.starts_with('-');
+    for flag in arg.chars().skip(1) {
+      match flag {
+        'p' => print = true,
+        'i' | 'x' => attributes.push((flag, enable)),
+        _ => {
+          return ExecutionOutput::stderr(format!("declare: {}{}: invalid option", &arg[..1], flag))
+            .with_status(2)
+        }
+      }
+    }
+  }
+  let operands = args.collect::<Vec<&String>>();
+
+  let describe = |name: &str, variables: &Variables| {
+    let value = variables.get(name)?;
+    let mut attributes = String::new();
+    if variables.is_integer(name) {
+      attributes.push('i');
+    }
+    if variables.is_exported(name) {
+      attributes.push('x');
+    }
+    Some(declaration(&attributes, name, Some(&value)))
+  };
+
+  if print || (operands.is_empty() && attributes.is_empty()) {
+    if operands.is_empty() {
+      let mut names = env::vars()
+        .map(|(name, _)| name)
+        .chain(variables.shell_variables().map(|(name, _)| name.clone()))
+        .chain(variables.integer_variables().cloned())
+        .collect::<Vec<String>>();
+      names.sort();
+      names.dedup();
+
+      let output = names
+        .iter()
+        .map(|name| describe(name, variables).unwrap_or_else(|| declaration("i", name, None)))
+        .collect::<String>();
+      return ExecutionOutput::stdout(output);
+    }
+
+    let mut found = String::new();
+    let mut not_found: Vec<String> = Vec::new();
+    for name in operands {
+      match describe(name, variables) {
+        Some(declaration) => found.push_str(&declaration),
+        None if variables.is_integer(name) => found.push_str(&declaration("i", name, None)),
+        None => not_found.push(format!("declare: {}: not found", name)),
+      }
+    }
+
+    let status = if not_found.is_empty() { 0 } else { 1 };
+    let stdout = (!found.is_empty()).then_some(CmdOutput::Stdout(found));
+    let stderr = (!not_found.is_empty()).then(|| CmdOutput::Stderr(not_found.join("\n")));
+    return ExecutionOutput(stdout, stderr, status);
+  }
+
+  let mut errors: Vec<String> = Vec::new();
+  for operand in operands {
+    let (name, value) = match parse_assignment(operand) {
+      Some((name, value)) => (name, Some(value)),
+      None if is_valid_identifier(operand) => (operand.as_str(), None),
+      None => {
+        errors.push(format!("declare: `{}': not a valid identifier", operand));
+        continue;
+      }
+    };
+
+    // The integer attribute applies to the value given along with it
+    for (_, enable) in attributes.iter().filter(|(flag, _)| *flag == 'i') {
+      variables.set_integer(name, *enable);
+    }
+    if let Some(value) = value {
+      if let Err(message) = variables.assign(name, value) {
+        errors.push(format!("declare: {}: {}", value, message));
+        continue;
+      }
+    }
+    for (_, enable) in attributes.iter().filter(|(flag, _)| *flag == 'x') {
+      match enable {
+        true => variables.export(name, None),
+        false => variables.unexport(name),
+      }
+    }
+  }
+
+  if errors.is_empty() {
+    ExecutionOutput::none()
+  } else {
+    ExecutionOutput::stderr(errors.join("\n"))
+  }
+}
+
+/// Evaluates each arg as an arithmetic expression, left to right. `name = expr` (or `name op= expr`
+/// for `+ - * / %`) stores the result in a variable. The status is 1 if the last value is 0.
+fn exec_let(cmd_args: CmdArgs, variables: &mut Variables) -> ExecutionOutput {
+  if cmd_args.len() < 2 {
+    return ExecutionOutput::stderr("let: expression expected");
+  }
+
+  let mut last_value = 0;
+  for expression in cmd_args.iter().skip(1) {
+    let assignment = parse_let_assignment(expression);
+
+    let result = match &assignment {
+      Some((_, value)) => arithmetic::evaluate(value, variables),
+      None => arithmetic::evaluate(expression, variables),
+    };
+    last_value = match result {
+      Ok(value) => value,
+      Err(message) => {
+        return ExecutionOutput::stderr(format!("let: {}: {}", expression.trim(), message))
+      }
+    };
+
+    if let Some((name, _)) = assignment {
+      variables.set(name, &last_value.to_string());
+    }
+  }
+
+  match last_value {
+    0 => ExecutionOutput::none().with_status(1),
+    _ => ExecutionOutput::none(),
+  }
+}
+
+/// Splits a `let` assignment into the variable name and the expression of its new value
+fn parse_let_assignment(expression: &str) -> Option<(&str, String)> {
+  let (target, value) = expression.split_once('=')?;
+  let target = target.trim();
+
+  // A compound assignment applies its operator to the variable's current value
+  let (name, value) = match target.strip_suffix(['+', '-', '*', '/', '%']) {
+    Some(name) => {
+      let operator = &target[name.len()..];
+      (name.trim(), format!("{} {} ({})", name, operator, value))
+    }
+    None => (target, value.to_string()),
+  };
+
+  is_valid_identifier(name).then_some((name, value))
+}
+
+fn exec_set(
+  cmd_args: CmdArgs,
+  options: &mut ShellOptions,
+  variables: &mut Variables,
+) -> ExecutionOutput {
+  // Without args, list the shell and environment variables
+  if cmd_args.len() == 1 {
+    let mut listed = env::vars()
+      .chain(
+        variables
+          .shell_variables()
+          .map(|(name, value)| (name.clone(), value.clone())),
+      )
+      .map(|(name, value)| format!("{}={}\n", name, shell_quote(&value)))
+      .collect::<Vec<String>>();
+    listed.sort();
+
+    return ExecutionOutput::stdout(listed.concat());
+  }
+
+  let mut args = cmd_args.iter().enumerate().skip(1);
+
+  while let Some((index, arg)) = args.next() {
+    // `--` ends the options, the args after it (or starting with the first non option) are the
+    // new positional parameters
+    if arg == "--" || !(arg.starts_with('-') || arg.starts_with('+')) {
+      let start = if arg == "--" { index + 1 } else { index };
+      variables.set_args(&cmd_args[start..]);
+      break;
+    }
+
+    // `-` enables an option, `+` disables it
+    let (value, flags) = match (arg.strip_prefix('-'), arg.strip_prefix('+')) {
+      (Some(flags), _) if !flags.is_empty() => (true, flags),
+      (_, Some(flags)) if !flags.is_empty() => (false, flags),
+      _ => return ExecutionOutput::stderr(format!("set: {}: invalid option", arg)),
+    };
+
+    if flags == "o" {
+      let Some((_, name)) = args.next() else {
+        // Without a name, list the options
+        let mut output = options.list().join("\n");
+        output.push('\n');
+        return ExecutionOutput::stdout(output);
+      };
+
+      if !options.set(name, value) {
+        return ExecutionOutput::stderr(format!("set: {}: invalid option name", name));
+      }
+      continue;
+    }
+
+    // Single letter flags can be combined, like `set -ex`
+    for flag in flags.chars() {
+      match ShellOptions::name_of_flag(flag) {
+        Some(name) => {
+          options.set(name, value);
+        }
+        None => return ExecutionOutput::stderr(format!("set: -{}: invalid option", flag)),
+      }
+    }
+  }
+
+  ExecutionOutput::none()
+}
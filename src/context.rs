@@ -0,0 +1,116 @@
+use crate::aliases::Aliases;
+use crate::history::History;
+use crate::input::LineSource;
+use crate::jobs::Jobs;
+use crate::shell_options::ShellOptions;
+use crate::tab_completions::CmdCompletions;
+use crate::traps::Traps;
+use crate::variables::Variables;
+use std::env;
+
+/// Environment variables passing a shell's `$$` and the commands recreating its state to the shells
+/// running its command substitutions
+const SUBSHELL_PID: &str = "SHELL_SUBSHELL_PID";
+const SUBSHELL_SETUP: &str = "SHELL_SUBSHELL_SETUP";
+
+/// State of the shell shared by the command lines it runs, created once at startup and passed
+/// along to the builtins reading or changing it
+#[derive(Default)]
+pub struct ShellContext {
+  pub history: History,
+  pub variables: Variables,
+  pub jobs: Jobs,
+  pub options: ShellOptions,
+  pub traps: Traps,
+  pub aliases: Aliases,
+  pub completions: CmdCompletions,
+  /// Where the lines being run come from, here-documents' bodies are read from it too
+  pub source: LineSource,
+  /// Whether the shell reads its commands from the terminal, rather than running a script
+  pub interactive: bool,
+}
+
+impl ShellContext {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Environment of a shell running a command substitution, which inherits the shell's `$$`, its
+  /// aliases, integer attributes and options. Shell variables and positional parameters are passed
+  /// apart.
+  pub fn subshell_env(&self) -> [(&'static str, String); 2] {
+    let mut setup = self.aliases.list();
+    setup.extend(
+      self
+        .variables
+        .integer_variables()
+        .map(|name| format!("declare -i {}", name)),
+    );
+    // Last, so xtrace doesn't print the setup commands
+    let options = [
+      ("errexit", self.options.errexit),
+      ("noclobber", self.options.noclobber),
+      ("xtrace", self.options.xtrace),
+    ];
+    setup.extend(
+      options
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| format!("set -o {}", name)),
+    );
+
+    // Escaped, so aliases named after the commands don't replace them
+    let setup = setup
+      .iter()
+      .map(|command| format!("\\{}", command))
+      .collect::<Vec<String>>();
+
+    [
+      (SUBSHELL_PID, self.variables.get("$").unwrap_or_default()),
+      (SUBSHELL_SETUP, setup.join("\n")),
+    ]
+  }
+
+  /// Takes over the state passed by `subshell_env`, when the shell runs a command substitution. The
+  /// variables are removed, so the commands it runs don't inherit them.
+  pub fn inherit_subshell_state(&mut self) {
+    if let Some(pid) = env::var(SUBSHELL_PID).ok().and_then(|pid| pid.parse().ok()) {
+      self.variables.inherit_pid(pid);
+    }
+    if let Ok(setup) = env::var(SUBSHELL_SETUP) {
+      crate::run_lines(&setup, self);
+    }
+
+    env::remove_var(SUBSHELL_PID);
+    env::remove_var(SUBSHELL_SETUP);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_subshell_state() {
+    let mut ctx = ShellContext::new();
+    ctx.aliases.set("ll", "ls -l 'x'");
+    ctx.aliases.set("set", "echo");
+    ctx.variables.set_integer("SUBSHELL_TEST_N", true);
+    ctx.options.noclobber = true;
+    ctx.options.xtrace = true;
+
+    for (name, value) in ctx.subshell_env() {
+      env::set_var(name, value);
+    }
+    let mut subshell = ShellContext::new();
+    subshell.variables.inherit_pid(0);
+    subshell.inherit_subshell_state();
+
+    assert_eq!(subshell.variables.get("$"), ctx.variables.get("$"));
+    assert_eq!(subshell.aliases.get("ll").unwrap(), "ls -l 'x'");
+    assert!(subshell.variables.is_integer("SUBSHELL_TEST_N"));
+    assert!(subshell.options.noclobber && subshell.options.xtrace);
+    assert!(!subshell.options.errexit);
+    assert!(env::var(SUBSHELL_SETUP).is_err());
+  }
+}
@@ -1,5 +1,6 @@
 use std::{io, result};
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
   #[error("IO Error: {0}")]
@@ -7,6 +8,12 @@ pub enum Error {
 
   #[error("UTF8 Error: {0}")]
   Utf8Error(#[from] std::string::FromUtf8Error),
+
+  #[error("syntax error near unexpected token `{0}'")]
+  SyntaxError(String),
+
+  #[error("{expression}: {message}")]
+  ArithmeticError { expression: String, message: String },
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -0,0 +1,161 @@
+use crate::utils::{find_command_in, get_path};
+use is_executable::IsExecutable;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+static COMMAND_HASH: Mutex<CommandHash> = Mutex::new(CommandHash::new());
+
+/// Locations of the commands found in `PATH`, so running a command again doesn't scan every
+/// directory. Entries are only valid for the `PATH` they were found with, the table is emptied when
+/// it changes.
+#[derive(Debug, Default)]
+pub struct CommandHash {
+  path: Option<String>,
+  entries: BTreeMap<String, HashEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashEntry {
+  pub path: String,
+  /// Times the command was run from the remembered location, lookups alone (like `type`'s) don't
+  /// count
+  pub hits: usize,
+}
+
+impl CommandHash {
+  pub const fn new() -> Self {
+    Self {
+      path: None,
+      entries: BTreeMap::new(),
+    }
+  }
+
+  /// Finds a command in the given `PATH`, from the table when it was already found. A remembered
+  /// location that's no longer executable is looked up again.
+  pub fn lookup(&mut self, command: &str, path: &str) -> Option<String> {
+    self.sync_path(path);
+
+    if let Some(entry) = self.entries.get(command) {
+      if Path::new(&entry.path).is_executable() {
+        return Some(entry.path.clone());
+      }
+    }
+
+    self.remember(command, path)
+  }
+
+  /// Counts a run of a command, when it was run from its remembered location
+  pub fn record_hit(&mut self, command: &str, location: &str) {
+    if let Some(entry) = self.entries.get_mut(command) {
+      if entry.path == location {
+        entry.hits += 1;
+      }
+    }
+  }
+
+  /// Finds a command in the given `PATH` and adds it to the table, without counting a hit, as
+  /// `hash name` does
+  pub fn remember(&mut self, command: &str, path: &str) -> Option<String> {
+    self.sync_path(path);
+
+    let Some(location) = find_command_in(command, path) else {
+      self.entries.remove(command);
+      return None;
+    };
+    self.entries.insert(
+      command.to_string(),
+      HashEntry {
+        path: location.clone(),
+        hits: 0,
+      },
+    );
+    Some(location)
+  }
+
+  pub fn clear(&mut self) {
+    self.entries.clear();
+  }
+
+  /// Entries by command name
+  pub fn entries(&self) -> impl Iterator<Item = (&String, &HashEntry)> {
+    self.entries.iter()
+  }
+
+  fn sync_path(&mut self, path: &str) {
+    if self.path.as_deref() != Some(path) {
+      self.entries.clear();
+      self.path = Some(path.to_string());
+    }
+  }
+}
+
+/// The table shared by the whole shell
+pub fn command_hash() -> MutexGuard<'static, CommandHash> {
+  // A panic while holding the lock can't leave the table half updated, keep using it
+  COMMAND_HASH.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Same as `find_command`, going through the shared table
+pub fn find_hashed_command(command: &str) -> Option<String> {
+  // Names with a slash are paths, never looked up in PATH
+  if command.contains('/') {
+    return None;
+  }
+
+  command_hash().lookup(command, &get_path()?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_temp_dir;
+  use std::fs;
+  use std::os::unix::fs::PermissionsExt;
+
+  #[test]
+  fn test_lookup_caches_until_path_changes() {
+    let first = test_temp_dir("hash-first");
+    let second = test_temp_dir("hash-second");
+    for dir in [&first, &second] {
+      let file_path = dir.join("tool");
+      fs::write(&file_path, "#!/bin/sh\n").unwrap();
+      fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let first_path = first.display().to_string();
+    let first_tool = format!("{}/tool", first_path);
+
+    let mut hash = CommandHash::new();
+    assert_eq!(hash.remember("tool", &first_path), Some(first_tool.clone()));
+    assert_eq!(hash.lookup("tool", &first_path), Some(first_tool.clone()));
+    assert_eq!(hash.lookup("tool", &first_path), Some(first_tool.clone()));
+    let (_, entry) = hash.entries().next().unwrap();
+    assert_eq!(entry.hits, 0);
+
+    // Only runs count, from the remembered location
+    hash.record_hit("tool", &first_tool);
+    hash.record_hit("tool", "/elsewhere/tool");
+    hash.record_hit("other", &first_tool);
+    let (_, entry) = hash.entries().next().unwrap();
+    assert_eq!(entry.hits, 1);
+
+    // PATH changed, the second dir now comes first
+    let both = format!("{}:{}", second.display(), first_path);
+    assert_eq!(
+      hash.lookup("tool", &both),
+      Some(format!("{}/tool", second.display()))
+    );
+
+    // A removed executable is looked up again
+    fs::remove_file(second.join("tool")).unwrap();
+    assert_eq!(hash.lookup("tool", &both), Some(first_tool));
+
+    hash.clear();
+    assert_eq!(hash.entries().count(), 0);
+    assert_eq!(hash.lookup("missing", &both), None);
+    assert_eq!(hash.entries().count(), 0);
+
+    fs::remove_dir_all(first).unwrap();
+    fs::remove_dir_all(second).unwrap();
+  }
+}
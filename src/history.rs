@@ -1,5 +1,6 @@
 use std::collections::HashMap;
-use std::fs::OpenOptions;
+use std::env;
+use std::fs::{self, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
 
 #[derive(Default)]
@@ -13,16 +14,16 @@ impl History {
     Self::default()
   }
 
-  pub fn from_file(file_path: &str) -> Self {
-    let stack = load_file(file_path);
+  pub fn from_file(file_path: &str) -> crate::Result<Self> {
+    let stack = load_file(file_path)?;
     let mut file_append_state = HashMap::new();
 
     file_append_state.insert(file_path.to_string(), stack.len());
 
-    Self {
+    Ok(Self {
       stack,
       file_append_state,
-    }
+    })
   }
 
   pub fn push(&mut self, command_str: &str) -> &mut Self {
@@ -31,9 +32,72 @@ impl History {
     self
   }
 
-  // TODO: return a result
-  pub fn set_from_file(&mut self, file_path: &str) -> &mut Self {
-    let content = load_file(file_path);
+  /// Expands the history references of a line, before it's run and added to the history:
+  /// - `!!` the previous command
+  /// - `!n` command number n, as listed by `history`, and `!-n` the nth previous one
+  /// - `!string` the most recent command starting with string
+  /// - `!?string?` the most recent command containing string (the closing `?` is optional at the
+  ///   end of the line)
+  ///
+  /// `!` isn't expanded in single quotes, when escaped with a backslash, or when followed by a
+  /// blank, `=` or `(`. Returns None when the line has no reference, and the unknown event (like
+  /// `!git: event not found`) as an error.
+  pub fn expand(&self, line: &str) -> Result<Option<String>, String> {
+    let mut expanded = String::new();
+    let mut found = false;
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((index, char)) = chars.next() {
+      match char {
+        '\\' if !in_single_quotes => {
+          expanded.push(char);
+          expanded.extend(chars.next().map(|(_, escaped)| escaped));
+          continue;
+        }
+        '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+        '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+        '!' if !in_single_quotes => {
+          let rest = &line[index + 1..];
+          if let Some((event, length)) = parse_event(rest) {
+            let command = self
+              .find_event(&event)
+              .ok_or_else(|| format!("!{}: event not found", &rest[..length]))?;
+            expanded.push_str(command);
+            found = true;
+            // Skip the event, by chars as the length is in bytes
+            while chars.next_if(|(next, _)| *next <= index + length).is_some() {}
+            continue;
+          }
+        }
+        _ => {}
+      }
+      expanded.push(char);
+    }
+
+    Ok(found.then_some(expanded))
+  }
+
+  fn find_event(&self, event: &Event) -> Option<&String> {
+    match event {
+      Event::Number(number) => self.stack.get(number.checked_sub(1)?),
+      Event::Relative(offset) => self.stack.get(self.stack.len().checked_sub(*offset)?),
+      Event::Prefix(prefix) => self
+        .stack
+        .iter()
+        .rev()
+        .find(|c| c.starts_with(prefix.as_str())),
+      Event::Substring(substring) => self
+        .stack
+        .iter()
+        .rev()
+        .find(|c| c.contains(substring.as_str())),
+    }
+  }
+
+  pub fn set_from_file(&mut self, file_path: &str) -> crate::Result<()> {
+    let content = load_file(file_path)?;
     self.stack.clear();
     self.stack.extend(content);
 
@@ -41,58 +105,132 @@ impl History {
       .file_append_state
       .insert(file_path.to_string(), self.stack.len());
 
-    self
+    Ok(())
   }
 
-  // TODO: return a result
-  pub fn extend_from_file(&mut self, file_path: &str) -> &mut Self {
-    let content = load_file(file_path);
+  pub fn extend_from_file(&mut self, file_path: &str) -> crate::Result<()> {
+    let content = load_file(file_path)?;
     self.stack.extend(content);
 
     self
       .file_append_state
       .insert(file_path.to_string(), self.stack.len());
 
-    self
+    Ok(())
   }
 
-  // TODO: return a result
-  pub fn write_to_file(&mut self, file_path: &str, append: bool) {
+  /// Writes the history to a file, keeping at most `HISTFILESIZE` of the most recent lines in it
+  pub fn write_to_file(&mut self, file_path: &str, append: bool) -> crate::Result<()> {
+    self.write_to_file_capped(file_path, append, file_size_limit())
+  }
+
+  fn write_to_file_capped(
+    &mut self,
+    file_path: &str,
+    append: bool,
+    limit: Option<usize>,
+  ) -> crate::Result<()> {
     let mut skip = 0_usize;
     if append {
       skip = *self.file_append_state.entry(file_path.into()).or_insert(0);
     }
+    // Lines that would be trimmed right away aren't written at all
+    if let Some(limit) = limit {
+      skip = skip.max(self.stack.len().saturating_sub(limit));
+    }
 
-    let file = OpenOptions::new()
+    let mut file = OpenOptions::new()
       .write(true)
       .create(true)
       .append(append)
       .truncate(!append)
-      .open(file_path);
+      .open(file_path)?;
 
-    let mut file = match file {
-      Ok(f) => f,
-      Err(_) => {
-        return ();
-      }
-    };
-
-    let mut output = String::new();
-    for line in self.stack.iter().skip(skip) {
-      output.push_str(format!("{}\n", line).as_str());
-    }
+    let output = file_lines(self.stack.iter().skip(skip));
 
     self
       .file_append_state
       .insert(file_path.into(), self.stack.len());
 
-    write!(file, "{}", output).unwrap();
+    write!(file, "{}", output)?;
+    drop(file);
+
+    // Appended lines add up to the ones already in the file
+    if let (true, Some(limit)) = (append, limit) {
+      let lines = load_file(file_path)?;
+      if lines.len() > limit {
+        fs::write(file_path, file_lines(&lines[lines.len() - limit..]))?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// Command referenced by a history expansion
+#[derive(Debug, PartialEq)]
+enum Event {
+  /// `!n`
+  Number(usize),
+  /// `!-n`, and `!!` being `!-1`
+  Relative(usize),
+  /// `!string`
+  Prefix(String),
+  /// `!?string?`
+  Substring(String),
+}
+
+/// Parses the event following a `!`, returning it with its length in bytes. None when the `!` isn't
+/// a history expansion.
+fn parse_event(rest: &str) -> Option<(Event, usize)> {
+  let word_end = |text: &str| {
+    text
+      .find(|char: char| char.is_whitespace() || "'\";|&<>()".contains(char))
+      .unwrap_or(text.len())
+  };
+
+  match rest.chars().next()? {
+    '!' => Some((Event::Relative(1), 1)),
+    '?' => {
+      let pattern = &rest[1..];
+      let (substring, length) = match pattern.find('?') {
+        Some(end) => (&pattern[..end], end + 2),
+        None => (pattern, rest.len()),
+      };
+      (!substring.is_empty()).then(|| (Event::Substring(substring.to_string()), length))
+    }
+    char if char.is_whitespace() || char == '=' || char == '(' => None,
+    _ => {
+      let word = &rest[..word_end(rest)];
+      if word.is_empty() {
+        return None;
+      }
+
+      let event = match (word.strip_prefix('-'), word.parse::<usize>()) {
+        (_, Ok(number)) => Event::Number(number),
+        (Some(offset), _) if offset.parse::<usize>().is_ok() => {
+          Event::Relative(offset.parse().ok()?)
+        }
+        _ => Event::Prefix(word.to_string()),
+      };
+      Some((event, word.len()))
+    }
   }
 }
 
+/// Maximum number of lines in the history file, from `HISTFILESIZE`. Unset or non-numeric values
+/// don't limit it.
+fn file_size_limit() -> Option<usize> {
+  env::var("HISTFILESIZE").ok()?.parse().ok()
+}
+
+/// Position while browsing the history with the arrow keys. The pointer goes from `size` (the line
+/// being typed, below the newest entry) down to 0 (the oldest entry).
 pub struct HistoryNavigation {
   pointer: usize,
   size: usize,
+  // Line being typed when browsing started, given back when returning to it
+  scratch: Option<String>,
 }
 
 impl HistoryNavigation {
@@ -100,39 +238,225 @@ impl HistoryNavigation {
     Self {
       pointer: size,
       size,
+      scratch: None,
     }
   }
 
-  pub fn next<'a>(&mut self, stack: &'a Vec<String>) -> Option<&'a String> {
-    if self.pointer == self.size {
+  /// Whether an entry is recalled, rather than being on the line being typed
+  pub fn is_browsing(&self) -> bool {
+    self.pointer < self.size
+  }
+
+  /// Moves to the next newer entry, or past the newest one back to the line that was being typed.
+  /// Returns None when already on that line.
+  pub fn next(&mut self, stack: &[String]) -> Option<String> {
+    if !self.is_browsing() {
       return None;
     };
 
     self.pointer += 1;
 
-    stack.get(self.pointer)
+    match stack.get(self.pointer) {
+      Some(entry) => Some(entry.clone()),
+      None => Some(self.scratch.take().unwrap_or_default()),
+    }
   }
 
-  pub fn previous<'a>(&mut self, stack: &'a Vec<String>) -> Option<&'a String> {
-    if self.pointer != 0 {
-      self.pointer -= 1;
+  /// Moves to the next older entry. Returns None, without moving, when already on the oldest one.
+  /// Leaving the line being typed (`input`) keeps it, for `next` to come back to.
+  pub fn previous<'a>(&mut self, stack: &'a [String], input: &str) -> Option<&'a String> {
+    if self.pointer == 0 {
+      return None;
+    }
+
+    if !self.is_browsing() {
+      self.scratch = Some(input.to_string());
     }
+    self.pointer -= 1;
 
     stack.get(self.pointer)
   }
 }
 
-fn load_file(file_path: &str) -> Vec<String> {
-  let file = OpenOptions::new().read(true).open(file_path);
-  let file = match file {
-    Ok(f) => f,
-    Err(_) => return Vec::new(),
-  };
+/// Reads the lines of a history file, lines that aren't valid UTF-8 are decoded lossily. CRLF line
+/// endings, as in files written on Windows, are read as plain newlines.
+fn load_file(file_path: &str) -> crate::Result<Vec<String>> {
+  let file = OpenOptions::new().read(true).open(file_path)?;
 
-  let reader = BufReader::new(file);
-  reader.lines().collect::<Result<Vec<String>, _>>().unwrap()
+  let mut lines = Vec::new();
+  for line in BufReader::new(file).split(b'\n') {
+    let mut line = line?;
+    if line.last() == Some(&b'\r') {
+      line.pop();
+    }
+    lines.push(String::from_utf8_lossy(&line).into_owned());
+  }
+
+  Ok(lines)
+}
+
+/// Content of a history file holding the lines, each ending with a plain newline
+fn file_lines<'a>(lines: impl IntoIterator<Item = &'a String>) -> String {
+  lines
+    .into_iter()
+    .map(|line| format!("{}\n", line.strip_suffix('\r').unwrap_or(line)))
+    .collect()
 }
 
 // TODO:
 // [ ] accept completions and set_completions method
 // [ ] given a prefix autocomplete based on the completions from prefix tree
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_temp_dir;
+
+  #[test]
+  fn test_write_to_file_respects_size_limit() {
+    let file_path = test_temp_dir("histfilesize").join("history");
+    let file_path = file_path.to_str().unwrap();
+
+    let mut history = History::new();
+    for i in 1..=5 {
+      history.push(&format!("cmd {}", i));
+    }
+
+    history
+      .write_to_file_capped(file_path, false, Some(3))
+      .unwrap();
+    assert_eq!(
+      load_file(file_path).unwrap(),
+      vec!["cmd 3", "cmd 4", "cmd 5"]
+    );
+
+    // Appending trims the older lines already in the file
+    history.push("cmd 6").push("cmd 7");
+    history
+      .write_to_file_capped(file_path, true, Some(3))
+      .unwrap();
+    assert_eq!(
+      load_file(file_path).unwrap(),
+      vec!["cmd 5", "cmd 6", "cmd 7"]
+    );
+
+    history
+      .write_to_file_capped(file_path, false, None)
+      .unwrap();
+    assert_eq!(load_file(file_path).unwrap().len(), 7);
+  }
+
+  #[test]
+  fn test_expand() {
+    let mut history = History::new();
+    history
+      .push("git status")
+      .push("ls -la")
+      .push("echo foo bar")
+      .push("git log");
+
+    let expand = |line: &str| history.expand(line);
+    assert_eq!(expand("!!").unwrap().unwrap(), "git log");
+    assert_eq!(
+      expand("sudo !! | less").unwrap().unwrap(),
+      "sudo git log | less"
+    );
+    assert_eq!(expand("!2").unwrap().unwrap(), "ls -la");
+    assert_eq!(expand("!-2").unwrap().unwrap(), "echo foo bar");
+    assert_eq!(expand("!git").unwrap().unwrap(), "git log");
+    assert_eq!(expand("!ls;").unwrap().unwrap(), "ls -la;");
+    assert_eq!(expand("!?foo?").unwrap().unwrap(), "echo foo bar");
+    assert_eq!(expand("!?stat").unwrap().unwrap(), "git status");
+
+    // Not history references
+    assert_eq!(expand("echo hi"), Ok(None));
+    assert_eq!(expand("[ a != b ] ! x"), Ok(None));
+    assert_eq!(expand("echo '!!' \\!!"), Ok(None));
+    assert_eq!(
+      expand("echo \"it's !-1\"").unwrap().unwrap(),
+      "echo \"it's git log\""
+    );
+
+    assert_eq!(expand("!nope"), Err("!nope: event not found".to_string()));
+    assert_eq!(expand("!9"), Err("!9: event not found".to_string()));
+    assert_eq!(expand("!?x?"), Err("!?x?: event not found".to_string()));
+    assert_eq!(expand("!0"), Err("!0: event not found".to_string()));
+  }
+
+  #[test]
+  fn test_navigation_boundaries() {
+    let stack = vec!["first".to_string(), "second".to_string()];
+    let mut nav = HistoryNavigation::from_size(stack.len());
+
+    // Down on the line being typed doesn't move
+    assert!(!nav.is_browsing());
+    assert_eq!(nav.next(&stack), None);
+    assert!(!nav.is_browsing());
+
+    // Up walks from the newest entry to the oldest, then stays there
+    assert_eq!(nav.previous(&stack, "").unwrap(), "second");
+    assert_eq!(nav.previous(&stack, "second").unwrap(), "first");
+    assert_eq!(nav.previous(&stack, "first"), None);
+    assert!(nav.is_browsing());
+
+    // Down walks back, past the newest entry to the line being typed
+    assert_eq!(nav.next(&stack).unwrap(), "second");
+    assert_eq!(nav.next(&stack).unwrap(), "");
+    assert!(!nav.is_browsing());
+    assert_eq!(nav.next(&stack), None);
+    assert_eq!(nav.previous(&stack, "").unwrap(), "second");
+  }
+
+  #[test]
+  fn test_navigation_restores_typed_line() {
+    let stack = vec!["first".to_string(), "second".to_string()];
+    let mut nav = HistoryNavigation::from_size(stack.len());
+
+    assert_eq!(nav.previous(&stack, "ech").unwrap(), "second");
+    // Editing the recalled entry and moving on doesn't replace the stashed line
+    assert_eq!(nav.previous(&stack, "second edited").unwrap(), "first");
+    assert_eq!(nav.next(&stack).unwrap(), "second");
+    assert_eq!(nav.next(&stack).unwrap(), "ech");
+    assert_eq!(stack, vec!["first", "second"]);
+  }
+
+  #[test]
+  fn test_navigation_empty_history() {
+    let mut nav = HistoryNavigation::from_size(0);
+    assert_eq!(nav.previous(&[], "typed"), None);
+    assert_eq!(nav.next(&[]), None);
+    assert!(!nav.is_browsing());
+  }
+
+  #[test]
+  fn test_load_file_decodes_invalid_utf8_lossily() {
+    let file_path = test_temp_dir("history-utf8").join("history");
+    fs::write(&file_path, b"echo \xff\nls\n").unwrap();
+
+    let mut history = History::new();
+    history.set_from_file(file_path.to_str().unwrap()).unwrap();
+    assert_eq!(history.stack, vec!["echo \u{FFFD}", "ls"]);
+
+    assert!(history.extend_from_file("/nonexistent/history").is_err());
+  }
+
+  #[test]
+  fn test_load_file_strips_carriage_returns() {
+    let file_path = test_temp_dir("history-crlf").join("history");
+    fs::write(&file_path, "ls -la\r\necho 'a\rb'\r\npwd").unwrap();
+    let file_path = file_path.to_str().unwrap();
+
+    let mut history = History::new();
+    history.set_from_file(file_path).unwrap();
+    assert_eq!(history.stack, vec!["ls -la", "echo 'a\rb'", "pwd"]);
+
+    history.push("cd /\r");
+    history
+      .write_to_file_capped(file_path, false, None)
+      .unwrap();
+    assert_eq!(
+      fs::read_to_string(file_path).unwrap(),
+      "ls -la\necho 'a\rb'\npwd\ncd /\n"
+    );
+  }
+}
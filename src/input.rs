@@ -1,51 +1,190 @@
 use crate::ansi_codes::AnsiCode;
 use crate::history::{History, HistoryNavigation};
-use crate::tab_completions::TabCompletionsCtx;
+use crate::shell_options::{EditingMode, ShellOptions};
+use crate::tab_completions::{
+  common_prefix, dir_completions, file_completions, format_columns, partial_word, quote_completion,
+  PartialWord, TabCompletionsCtx,
+};
 use crate::trie::Trie;
+use crate::utils::display_width;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::File;
 use std::io;
 use std::io::{Read, Write};
-use std::process::Command;
+use std::mem::{self, ManuallyDrop};
+use std::os::fd::FromRawFd;
+use std::path::Path;
+use std::sync::OnceLock;
+
+const BRACKETED_PASTE_ON: &str = "\x1b[?2004h";
+const BRACKETED_PASTE_OFF: &str = "\x1b[?2004l";
+/// Sent by the terminal after pasted text, which starts with "ESC [ 200 ~"
+const PASTE_END: &[u8] = b"\x1b[201~";
 
 enum SequenceState {
   Normal,
   ESCReceived,
   BracketReceived,
+  /// Numeric parameter of a sequence ending with `~`, like 3 in Delete's "ESC [ 3 ~"
+  ParameterReceived(u16),
 }
 
-pub fn read_input(cmd_completions: &mut Trie, history: &History) -> crate::Result<Option<String>> {
+/// Submode of the vi editing mode
+#[derive(Clone, Copy, PartialEq)]
+enum ViState {
+  /// Typed keys are inserted, as in emacs mode
+  Insert,
+  /// Typed keys are commands, entered with Esc
+  Normal,
+}
+
+/// Keys moving the cursor or editing at the cursor, sent as escape sequences
+enum EditingKey {
+  Home,
+  End,
+  Left,
+  Right,
+  WordLeft,
+  WordRight,
+  Delete,
+}
+
+/// Reads a line of input in raw mode after displaying the prompt, with tab completion and history
+/// navigation, as set up by the shell options: the editing mode, fuzzy completion of commands and
+/// completion hints. Returns None if reading was interrupted with Ctrl-C.
+pub fn read_input(
+  prompt: &str,
+  cmd_completions: &mut Trie,
+  history: &History,
+  options: &ShellOptions,
+) -> crate::Result<Option<String>> {
   let mut buf = [0u8; 1];
   let mut input: Vec<u8> = Vec::new();
-  let mut stdin = io::stdin();
+  // Position in `input` where typed chars are inserted
+  let mut cursor = 0;
+  // Not `io::stdin()`, which reads ahead into its buffer: keys typed after the line is submitted
+  // are left to the command it runs. SAFETY: stdin stays open, and isn't closed when the file is
+  // dropped.
+  let terminal = ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDIN_FILENO) });
+  let mut stdin = &*terminal;
   let mut stdout = io::stdout();
   let mut tab_completions_ctx = TabCompletionsCtx::new();
   let mut sequence_state = SequenceState::Normal;
+  // None in emacs mode
+  let mut vi_state = (options.editing_mode == EditingMode::Vi).then_some(ViState::Insert);
+  // Cursor when Esc left vi's insert mode, to go back to it if the Esc started a key's sequence
+  let mut vi_insert_cursor: Option<usize> = None;
 
   let mut history_nav = HistoryNavigation::from_size(history.stack.len());
 
-  enable_raw_mode()?;
+  print!("{}", prompt);
+  stdout.flush()?;
+  io::stderr().flush()?;
+
+  // Raw mode is undone when the guard is dropped, however reading ends
+  let _raw_mode = RawModeGuard::new()?;
 
   loop {
     stdin.read_exact(&mut buf)?;
 
-    if tab_completions_ctx.is_enabled() && buf[0] != b'\t' {
+    if buf[0] != b'\t' {
       tab_completions_ctx.reset();
     }
 
     // print!("{:?}-", buf[0]);
     match buf[0] {
+      b'\n' | b'\r' => {
+        AnsiCode::CRLF.write();
+        break;
+      }
+      b'\x03' => {
+        AnsiCode::CRLF.write();
+        return Ok(None);
+      }
+      // ESC [, arrows and other editing keys work in both vi submodes
+      91 if matches!(sequence_state, SequenceState::ESCReceived) => {
+        sequence_state = SequenceState::BracketReceived;
+        // The Esc was the start of a key, not a switch to vi's normal mode
+        if let Some(insert_cursor) = vi_insert_cursor.take() {
+          vi_state = Some(ViState::Insert);
+          cursor = insert_cursor;
+          redraw_line(prompt, &input, cursor)?;
+        }
+      }
+      // ESC, also leaving vi's insert mode for its normal mode, on the last typed char
+      27 if matches!(sequence_state, SequenceState::Normal) => {
+        sequence_state = SequenceState::ESCReceived;
+        if vi_state == Some(ViState::Insert) {
+          vi_state = Some(ViState::Normal);
+          vi_insert_cursor = Some(cursor);
+          edit_line(EditingKey::Left, prompt, &mut input, &mut cursor)?;
+        }
+      }
+      key
+        if vi_state == Some(ViState::Normal)
+          && matches!(
+            sequence_state,
+            SequenceState::Normal | SequenceState::ESCReceived
+          ) =>
+      {
+        sequence_state = SequenceState::Normal;
+        vi_insert_cursor = None;
+        vi_state = Some(vi_command(key, prompt, &mut input, &mut cursor)?);
+      }
       b'\t' if tab_completions_ctx.is_enabled() => {
         sequence_state = SequenceState::Normal;
+        let rows = format_columns(&tab_completions_ctx.completions, terminal_width());
         print!(
-          "\r\n{}\n\r$ {}",
-          tab_completions_ctx.completions.join("  "),
+          "\r\n{}\r\n{}{}",
+          rows.join("\r\n"),
+          prompt,
           String::from_utf8_lossy(&input)
         );
         stdout.flush()?;
+        cursor = input.len();
       }
       b'\t' => {
         sequence_state = SequenceState::Normal;
-        let mut c = cmd_completions.get_completions(String::from_utf8(input.clone())?);
+        let prefix = String::from_utf8(input.clone())?;
+
+        // Args are completed with file names
+        let word = partial_word(&prefix);
+        if !word.is_command {
+          complete_argument(
+            prompt,
+            &word,
+            &mut input,
+            &mut tab_completions_ctx,
+            options.completionhint,
+          )?;
+          cursor = input.len();
+          continue;
+        }
+
+        // Nothing typed yet, only the start every command shares can be completed
+        if prefix.is_empty() {
+          match common_prefix_extension(cmd_completions, &prefix) {
+            Some(added) => {
+              print!("{}", added);
+              input.extend(added.as_bytes());
+              cursor = input.len();
+            }
+            None => AnsiCode::BEL.write(),
+          }
+          stdout.flush()?;
+          continue;
+        }
+
+        let mut c = cmd_completions.get_completions(&prefix);
         c.sort();
+
+        // Fuzzy matches are ranked, best first, so they aren't sorted
+        let fuzzy_matched = options.fuzzycomplete && c.is_empty();
+        if fuzzy_matched {
+          c = cmd_completions.fuzzy_completions(&prefix);
+        }
+
         match c.len() {
           0 => {
             AnsiCode::BEL.write();
@@ -55,125 +194,859 @@ pub fn read_input(cmd_completions: &mut Trie, history: &History) -> crate::Resul
             let first_completion = c.first().unwrap();
             let bytes = first_completion.as_bytes();
             print!("\r\x1b[K"); // Clear line and move cursor to start
-            print!("$ {} ", first_completion);
+            print!("{}{} ", prompt, first_completion);
             stdout.flush()?; // Push all changes to stdout immediately
 
             input.clear();
             input.extend(bytes);
             input.extend(b" ");
+            cursor = input.len();
+          }
+          // Fuzzy matches share no common prefix with the input, the next tab lists them
+          _ if fuzzy_matched => {
+            tab_completions_ctx.enable();
+            tab_completions_ctx.set_completions(c);
+            AnsiCode::BEL.write();
+            stdout.flush()?;
           }
           _ => {
             tab_completions_ctx.enable();
             tab_completions_ctx.set_completions(c);
-            let input_clone = input.clone();
-            let prefix = String::from_utf8_lossy(input_clone.as_slice());
-            let lcp = cmd_completions.longest_common_prefix(&prefix);
 
-            if prefix == lcp {
+            let Some(added) = common_prefix_extension(cmd_completions, &prefix) else {
               AnsiCode::BEL.write();
               stdout.flush()?;
               continue;
-            }
+            };
 
-            print!("\r\x1b[K"); // Clear line and move cursor to start
-            print!("$ {}", lcp); // No space in the end as multiple completions is available
+            // Clear line and move cursor to start
+            print!("\r\x1b[K");
+            // No space in the end as multiple completions is available
+            print!("{}{}{}", prompt, prefix, added);
             stdout.flush()?; // Push all changes to stdout immediately
 
-            input.clear();
-            input.extend(lcp.as_bytes());
-
-            stdout.flush()?;
+            // The prefix is the whole input wherever the cursor is, the added chars follow it
+            input.extend(added.as_bytes());
+            cursor = input.len();
           }
         }
       }
-      b'\n' | b'\r' => {
-        AnsiCode::CRLF.write();
-        break;
-      }
-      b'\x03' => {
-        AnsiCode::CRLF.write();
-        return Ok(None);
-      }
+      // Ctrl-Z only suspends running commands, there is nothing to suspend at the prompt
+      b'\x1a' => {}
       // Handle backspace (ASCII 8) and delete (ASCII 127)
       b'\x08' | b'\x7F' => {
         sequence_state = SequenceState::Normal;
-        if !input.is_empty() {
-          input.pop(); // Remove the last character from input
-                       // Move cursor back, erase the character, and move cursor back again
-          print!(
-            "{}{}{}",
-            AnsiCode::MoveCursorLeft,
-            " ",
-            AnsiCode::MoveCursorLeft
-          );
+        if cursor == 0 {
+          continue;
+        }
+
+        // Remove the character before the cursor, all of its bytes
+        let start = previous_char_start(&input, cursor);
+        let removed = input.drain(start..cursor).collect::<Vec<u8>>();
+        cursor = start;
+        let width = display_width(&String::from_utf8_lossy(&removed));
+        if cursor == input.len() && width > 0 {
+          print!("{}", erase_columns(width));
           stdout.flush()?;
+        } else {
+          redraw_line(prompt, &input, cursor)?;
         }
       }
-      // ESC
-      27 if matches!(sequence_state, SequenceState::Normal) => {
-        sequence_state = SequenceState::ESCReceived;
-      }
-      // ESC [
-      91 if matches!(sequence_state, SequenceState::ESCReceived) => {
-        sequence_state = SequenceState::BracketReceived;
+      // Alt-B "ESC b" and Alt-F "ESC f", move by a word
+      b'b' | b'f' if matches!(sequence_state, SequenceState::ESCReceived) => {
+        sequence_state = SequenceState::Normal;
+        let key = match buf[0] {
+          b'b' => EditingKey::WordLeft,
+          _ => EditingKey::WordRight,
+        };
+        edit_line(key, prompt, &mut input, &mut cursor)?;
       }
       // Up arrow [27, 91, 65] or "ESC [ 65"
       65 if matches!(sequence_state, SequenceState::BracketReceived) => {
         sequence_state = SequenceState::Normal;
-        if let Some(completion) = history_nav.previous(&history.stack) {
+        let typed = String::from_utf8_lossy(&input).into_owned();
+        if let Some(completion) = history_nav.previous(&history.stack, &typed) {
           print!("\r\x1b[K"); // Clear line and move cursor to start
-          print!("$ {}", completion);
+          print!("{}{}", prompt, completion);
           stdout.flush()?; // Push all changes to stdout immediately
           input = completion.as_bytes().to_vec();
+          cursor = input.len();
         }
       }
       // Down arrow [27, 91, 66] or "ESC [ 66"
       66 if matches!(sequence_state, SequenceState::BracketReceived) => {
         sequence_state = SequenceState::Normal;
-        if let Some(completion) = history_nav.next(&history.stack) {
-          print!("\r\x1b[K"); // Clear line and move cursor to start
-          print!("$ {}", completion);
-          stdout.flush()?; // Push all changes to stdout immediately
-          input = completion.as_bytes().to_vec();
+        // Past the newest entry, this is the line that was being typed
+        let Some(completion) = history_nav.next(&history.stack) else {
+          continue;
+        };
+        input = completion.into_bytes();
+        cursor = input.len();
+        print!("\r\x1b[K"); // Clear line and move cursor to start
+        print!("{}{}", prompt, String::from_utf8_lossy(&input));
+        stdout.flush()?; // Push all changes to stdout immediately
+      }
+      // Right arrow "ESC [ C", left arrow "ESC [ D", Home "ESC [ H" and End "ESC [ F"
+      b'C' | b'D' | b'H' | b'F' if matches!(sequence_state, SequenceState::BracketReceived) => {
+        sequence_state = SequenceState::Normal;
+        let key = match buf[0] {
+          b'C' => EditingKey::Right,
+          b'D' => EditingKey::Left,
+          b'H' => EditingKey::Home,
+          _ => EditingKey::End,
+        };
+        edit_line(key, prompt, &mut input, &mut cursor)?;
+      }
+      // Numeric parameter, like "ESC [ 3"
+      digit @ b'0'..=b'9'
+        if matches!(
+          sequence_state,
+          SequenceState::BracketReceived | SequenceState::ParameterReceived(_)
+        ) =>
+      {
+        let parameter = match sequence_state {
+          SequenceState::ParameterReceived(parameter) => parameter,
+          _ => 0,
+        };
+        let parameter = parameter
+          .saturating_mul(10)
+          .saturating_add((digit - b'0') as u16);
+        sequence_state = SequenceState::ParameterReceived(parameter);
+      }
+      // Start of a paste "ESC [ 200 ~", pasted text is inserted as is, newlines included
+      b'~' if matches!(sequence_state, SequenceState::ParameterReceived(200)) => {
+        sequence_state = SequenceState::Normal;
+        let pasted = read_paste(&mut stdin)?;
+        let at_end = cursor == input.len();
+        input.splice(cursor..cursor, pasted.iter().copied());
+        cursor += pasted.len();
+        if at_end {
+          print!("{}", display_text(&pasted));
+          stdout.flush()?;
         } else {
-          print!("\r\x1b[K"); // Clear line and move cursor to start
-          print!("$ ");
-          input.clear();
-          stdout.flush()?; // Push all changes to stdout immediately
+          redraw_line(prompt, &input, cursor)?;
+        }
+      }
+      // Home "ESC [ 1 ~" (or 7), Delete "ESC [ 3 ~" and End "ESC [ 4 ~" (or 8)
+      b'~' if matches!(sequence_state, SequenceState::ParameterReceived(_)) => {
+        let key = match sequence_state {
+          SequenceState::ParameterReceived(1 | 7) => Some(EditingKey::Home),
+          SequenceState::ParameterReceived(3) => Some(EditingKey::Delete),
+          SequenceState::ParameterReceived(4 | 8) => Some(EditingKey::End),
+          _ => None,
         };
+        sequence_state = SequenceState::Normal;
+        if let Some(key) = key {
+          edit_line(key, prompt, &mut input, &mut cursor)?;
+        }
+      }
+      // Unsupported sequences, like function keys, aren't typed text
+      _ if matches!(
+        sequence_state,
+        SequenceState::BracketReceived | SequenceState::ParameterReceived(_)
+      ) =>
+      {
+        sequence_state = SequenceState::Normal;
       }
       o => {
         sequence_state = SequenceState::Normal;
-        print!("{}", String::from_utf8_lossy(&[o]));
-        stdout.flush()?;
-        input.push(o);
+        // Multibyte chars are inserted and echoed once all of their bytes are read
+        let Some(char) = read_char(o, &mut stdin)? else {
+          continue;
+        };
+        let mut encoded = [0u8; 4];
+        let bytes = char.encode_utf8(&mut encoded).as_bytes();
+        input.splice(cursor..cursor, bytes.iter().copied());
+        cursor += bytes.len();
+        if cursor == input.len() {
+          print!("{}", char);
+          // Typed or pasted quickly, the following keys are echoed along with this one
+          if !keys_pending() {
+            stdout.flush()?;
+          }
+        } else {
+          redraw_line(prompt, &input, cursor)?;
+        }
       }
     }
   }
 
-  disable_raw_mode()?;
-
   Ok(Some(String::from_utf8(input)?))
 }
 
+/// Chars the commands starting with `prefix` all have after it, that can be completed before they
+/// differ. None when there are none, the commands already differ right after `prefix`. An empty
+/// prefix extends to the start shared by every command.
+fn common_prefix_extension(cmd_completions: &mut Trie, prefix: &str) -> Option<String> {
+  let lcp = match prefix.is_empty() {
+    true => cmd_completions.longest_common_prefix_all(),
+    false => cmd_completions.longest_common_prefix(prefix),
+  };
+  lcp
+    .strip_prefix(prefix)
+    .filter(|added| !added.is_empty())
+    .map(String::from)
+}
+
+/// Whether the terminal already sent more keys, typed or pasted quickly, which are yet to be read
+fn keys_pending() -> bool {
+  let mut available: libc::c_int = 0;
+  // SAFETY: FIONREAD only writes the number of bytes available to the int
+  let result = unsafe { libc::ioctl(libc::STDIN_FILENO, libc::FIONREAD, &mut available) };
+  result == 0 && available > 0
+}
+
+/// Completes the word at the end of the input with the files it's a prefix of, or the directories
+/// for `cd`. A single match is completed as a whole, quoted as needed, followed by a space (or a
+/// `/` for a directory). Otherwise the input is completed up to the matches' common prefix, and the
+/// next tab lists them. Without matches the bell rings, with `hint` a second tab then tells there
+/// are none.
+fn complete_argument(
+  prompt: &str,
+  word: &PartialWord,
+  input: &mut Vec<u8>,
+  tab_completions_ctx: &mut TabCompletionsCtx,
+  hint: bool,
+) -> io::Result<()> {
+  // Only directories can be changed to
+  let matches = match word.command.as_deref() {
+    Some("cd" | "pushd") => dir_completions(&word.value),
+    _ => file_completions(&word.value),
+  };
+  let (completion, complete) = match matches.as_slice() {
+    [] if hint && tab_completions_ctx.is_missed() => {
+      print!("\r\nno completions\r\n");
+      return redraw_line(prompt, input, input.len());
+    }
+    [] => {
+      tab_completions_ctx.miss();
+      AnsiCode::BEL.write();
+      return io::stdout().flush();
+    }
+    [path] => (path.clone(), true),
+    paths => {
+      tab_completions_ctx.enable();
+      tab_completions_ctx.set_completions(
+        paths
+          .iter()
+          .map(|path| path.rsplit('/').next().unwrap_or(path).to_string())
+          .collect(),
+      );
+      (common_prefix(paths), false)
+    }
+  };
+
+  if !complete && completion.len() <= word.value.len() {
+    AnsiCode::BEL.write();
+    return io::stdout().flush();
+  }
+
+  let is_dir = complete && Path::new(&completion).is_dir();
+  let mut replacement = match is_dir {
+    true => quote_completion(&format!("{}/", completion), word.open_quote),
+    false => quote_completion(&completion, word.open_quote),
+  };
+  if complete && !is_dir {
+    replacement.extend(word.open_quote);
+    replacement.push(' ');
+  }
+
+  input.truncate(word.start);
+  input.extend(replacement.as_bytes());
+  redraw_line(prompt, input, input.len())
+}
+
+/// Moves the cursor, or deletes the char under it, for one of the editing keys
+fn edit_line(
+  key: EditingKey,
+  prompt: &str,
+  input: &mut Vec<u8>,
+  cursor: &mut usize,
+) -> io::Result<()> {
+  match key {
+    EditingKey::Home => *cursor = 0,
+    EditingKey::End => *cursor = input.len(),
+    EditingKey::Left => *cursor = previous_char_start(input, *cursor),
+    EditingKey::Right => *cursor = next_char_start(input, *cursor),
+    EditingKey::WordLeft => *cursor = previous_word_start(input, *cursor),
+    EditingKey::WordRight => *cursor = next_word_end(input, *cursor),
+    EditingKey::Delete if *cursor < input.len() => {
+      input.drain(*cursor..next_char_start(input, *cursor));
+    }
+    EditingKey::Delete => return Ok(()),
+  }
+
+  redraw_line(prompt, input, *cursor)
+}
+
+/// Runs a command of vi's normal mode, in which the cursor is on a char rather than between two of
+/// them. Returns the submode to continue in, unknown keys are ignored.
+fn vi_command(
+  key: u8,
+  prompt: &str,
+  input: &mut Vec<u8>,
+  cursor: &mut usize,
+) -> io::Result<ViState> {
+  let last = previous_char_start(input, input.len());
+  let mut state = ViState::Normal;
+
+  match key {
+    b'h' => *cursor = previous_char_start(input, *cursor),
+    b'l' => *cursor = next_char_start(input, *cursor).min(last),
+    b'0' => *cursor = 0,
+    b'$' => *cursor = last,
+    b'w' => *cursor = next_word_start(input, *cursor).min(last),
+    b'b' => *cursor = previous_word_start(input, *cursor),
+    b'x' if *cursor < input.len() => {
+      input.drain(*cursor..next_char_start(input, *cursor));
+      *cursor = (*cursor).min(previous_char_start(input, input.len()));
+    }
+    b'i' => state = ViState::Insert,
+    b'a' => {
+      *cursor = next_char_start(input, *cursor);
+      state = ViState::Insert;
+    }
+    b'I' => {
+      *cursor = 0;
+      state = ViState::Insert;
+    }
+    b'A' => {
+      *cursor = input.len();
+      state = ViState::Insert;
+    }
+    _ => return Ok(state),
+  }
+
+  redraw_line(prompt, input, *cursor)?;
+  Ok(state)
+}
+
+/// Moves the cursor back over the last columns of the line and erases them, like the ones of a char
+/// deleted with backspace
+fn erase_columns(width: usize) -> String {
+  format!("\x1b[{}D{}\x1b[{}D", width, " ".repeat(width), width)
+}
+
+/// Reads the rest of the char starting with the given byte, which is a whole char when it's ASCII.
+/// Returns None for bytes that don't form a valid UTF-8 char, which are dropped.
+fn read_char(first: u8, stdin: &mut impl Read) -> io::Result<Option<char>> {
+  let length = match first {
+    0x00..=0x7F => 1,
+    0xC2..=0xDF => 2,
+    0xE0..=0xEF => 3,
+    0xF0..=0xF4 => 4,
+    // A continuation byte without its start, or a byte UTF-8 never uses
+    _ => return Ok(None),
+  };
+
+  let mut bytes = [first, 0, 0, 0];
+  stdin.read_exact(&mut bytes[1..length])?;
+  Ok(
+    std::str::from_utf8(&bytes[..length])
+      .ok()
+      .and_then(|char| char.chars().next()),
+  )
+}
+
+/// Start of the char before the cursor, skipping the continuation bytes of a multibyte char
+fn previous_char_start(input: &[u8], cursor: usize) -> usize {
+  let mut position = cursor.saturating_sub(1);
+  while position > 0 && is_continuation_byte(input[position]) {
+    position -= 1;
+  }
+  position
+}
+
+/// Start of the char after the one at the cursor, the input's length past the last char
+fn next_char_start(input: &[u8], cursor: usize) -> usize {
+  let mut position = (cursor + 1).min(input.len());
+  while position < input.len() && is_continuation_byte(input[position]) {
+    position += 1;
+  }
+  position
+}
+
+fn is_continuation_byte(byte: u8) -> bool {
+  byte & 0b1100_0000 == 0b1000_0000
+}
+
+/// Start of the word after the cursor, words being delimited by whitespace. The input's length when
+/// there's none.
+fn next_word_start(input: &[u8], cursor: usize) -> usize {
+  let mut position = cursor;
+  while position < input.len() && !input[position].is_ascii_whitespace() {
+    position += 1;
+  }
+  while position < input.len() && input[position].is_ascii_whitespace() {
+    position += 1;
+  }
+  position
+}
+
+/// Start of the word before the cursor, words being delimited by whitespace
+fn previous_word_start(input: &[u8], cursor: usize) -> usize {
+  let mut position = cursor;
+  while position > 0 && input[position - 1].is_ascii_whitespace() {
+    position -= 1;
+  }
+  while position > 0 && !input[position - 1].is_ascii_whitespace() {
+    position -= 1;
+  }
+  position
+}
+
+/// End of the word after the cursor, words being delimited by whitespace
+fn next_word_end(input: &[u8], cursor: usize) -> usize {
+  let mut position = cursor;
+  while position < input.len() && input[position].is_ascii_whitespace() {
+    position += 1;
+  }
+  while position < input.len() && !input[position].is_ascii_whitespace() {
+    position += 1;
+  }
+  position
+}
+
+/// Redraws the prompt and the input, then moves the terminal's cursor back to `cursor`
+fn redraw_line(prompt: &str, input: &[u8], cursor: usize) -> io::Result<()> {
+  print!("\r\x1b[K"); // Clear line and move cursor to start
+  print!("{}{}", prompt, display_text(input));
+
+  let after_cursor = display_width(&String::from_utf8_lossy(&input[cursor..]));
+  if after_cursor > 0 {
+    print!("\x1b[{}D", after_cursor);
+  }
+  io::stdout().flush()
+}
+
+/// Input as it's echoed, newlines (from pastes) also return the cursor to the line start as the
+/// terminal doesn't do it in raw mode
+fn display_text(input: &[u8]) -> String {
+  String::from_utf8_lossy(input).replace('\n', "\r\n")
+}
+
+/// Reads pasted text, up to the end of paste sequence. Terminals send pasted newlines as carriage
+/// returns, they are turned back into newlines.
+///
+/// Text is read several bytes at a time, but never past the end of the paste: the keys following
+/// it, maybe past a submitted line, are left to be read.
+fn read_paste(stdin: &mut impl Read) -> io::Result<Vec<u8>> {
+  let mut pasted = Vec::new();
+  let mut buf = [0u8; PASTE_END.len()];
+  while !pasted.ends_with(PASTE_END) {
+    // The end sequence may already be started by the last bytes read
+    let started = (1..PASTE_END.len())
+      .rev()
+      .find(|length| pasted.ends_with(&PASTE_END[..*length]))
+      .unwrap_or(0);
+    match stdin.read(&mut buf[..PASTE_END.len() - started]) {
+      Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+      Ok(read) => pasted.extend(&buf[..read]),
+      Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      Err(err) => return Err(err),
+    }
+  }
+  pasted.truncate(pasted.len() - PASTE_END.len());
+
+  let pasted = String::from_utf8_lossy(&pasted)
+    .replace("\r\n", "\n")
+    .replace('\r', "\n");
+  Ok(pasted.into_bytes())
+}
+
+/// Whether stdin is a terminal, otherwise the shell runs non-interactively (like with a script
+/// piped into it)
+pub fn is_interactive() -> bool {
+  // SAFETY: isatty only inspects the file descriptor
+  unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+/// Where the shell reads command lines, and here-documents' bodies, from. Lines read are counted,
+/// for `$LINENO`.
+#[derive(Debug, Default)]
+pub struct LineSource {
+  input: SourceInput,
+  line_number: usize,
+}
+
+#[derive(Debug)]
+enum SourceInput {
+  /// Typed at the terminal, with a prompt, completions and history. The lines of a pasted
+  /// snippet that weren't run yet are kept.
+  Terminal(VecDeque<String>),
+  /// Piped into stdin, read as is
+  Stdin,
+  /// Lines of a script file
+  Script(std::vec::IntoIter<String>),
+}
+
+/// No lines at all, until the shell is given its actual source
+impl Default for SourceInput {
+  fn default() -> Self {
+    Self::Script(Vec::new().into_iter())
+  }
+}
+
+impl LineSource {
+  pub fn terminal() -> Self {
+    Self::from(SourceInput::Terminal(VecDeque::new()))
+  }
+
+  pub fn stdin() -> Self {
+    Self::from(SourceInput::Stdin)
+  }
+
+  /// Source for a script's content. A `#!` first line is only meant for the kernel, it's skipped
+  /// (but still counted as a line).
+  pub fn script(content: &str) -> Self {
+    let mut lines = content.lines().map(String::from).collect::<Vec<String>>();
+    if let Some(first) = lines.first_mut().filter(|line| line.starts_with("#!")) {
+      first.clear();
+    }
+
+    Self::from(SourceInput::Script(lines.into_iter()))
+  }
+
+  fn from(input: SourceInput) -> Self {
+    Self {
+      input,
+      line_number: 0,
+    }
+  }
+
+  pub fn is_interactive(&self) -> bool {
+    matches!(self.input, SourceInput::Terminal(_))
+  }
+
+  /// Keeps the lines of an input read at the terminal with `read_input`, to be run one at a time
+  pub fn push_typed(&mut self, input: &str) {
+    if let SourceInput::Terminal(typed) = &mut self.input {
+      typed.extend(input.split('\n').map(String::from));
+    }
+  }
+
+  /// Next line typed at the terminal that wasn't run yet
+  pub fn next_typed_line(&mut self) -> Option<String> {
+    let SourceInput::Terminal(typed) = &mut self.input else {
+      return None;
+    };
+    let line = typed.pop_front()?;
+    self.count_line();
+    Some(line)
+  }
+
+  /// Abandons the typed lines that weren't run yet
+  pub fn clear_typed(&mut self) {
+    if let SourceInput::Terminal(typed) = &mut self.input {
+      typed.clear();
+    }
+  }
+
+  /// Reads the next line of a non interactive source, None at the end of the input. Lines typed
+  /// at the terminal are read with `read_input` instead, and taken with `next_typed_line`.
+  pub fn next_line(&mut self) -> crate::Result<Option<String>> {
+    let line = match &mut self.input {
+      SourceInput::Terminal(_) | SourceInput::Stdin => read_line()?,
+      SourceInput::Script(lines) => lines.next(),
+    };
+    if line.is_some() {
+      self.count_line();
+    }
+
+    Ok(line)
+  }
+
+  fn count_line(&mut self) {
+    self.line_number += 1;
+  }
+
+  /// Number of the last line read, 0 before the first one
+  pub fn line_number(&self) -> usize {
+    self.line_number
+  }
+}
+
+/// Reads a line from stdin as is, without raw mode, prompt or completions, for when it isn't a
+/// terminal. Bytes are read one at a time, so nothing past the line is consumed and commands
+/// reading stdin get the rest of it. Returns None at the end of the input.
+pub fn read_line() -> crate::Result<Option<String>> {
+  // Not `io::stdin()`, which reads ahead into its buffer. SAFETY: stdin stays open, and isn't
+  // closed when the file is dropped.
+  let mut stdin = ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDIN_FILENO) });
+  let mut buf = [0u8; 1];
+  let mut line: Vec<u8> = Vec::new();
+
+  loop {
+    match stdin.read(&mut buf) {
+      Ok(0) if line.is_empty() => return Ok(None),
+      // The last line may not end with a newline
+      Ok(0) => break,
+      Ok(_) if buf[0] == b'\n' => break,
+      Ok(_) => line.push(buf[0]),
+      Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      Err(err) => return Err(err.into()),
+    }
+  }
+
+  Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Reads the body of a here-document, line by line with a `> ` prompt, until a line made of just
+/// the delimiter. Lines pasted along with the command come first. Returns None if reading was
+/// interrupted. When not interactive, lines come from the source as is and the end of the input
+/// ends the body too.
+pub fn read_here_doc(
+  delimiter: &str,
+  strip_tabs: bool,
+  source: &mut LineSource,
+  cmd_completions: &mut Trie,
+  history: &History,
+  options: &ShellOptions,
+) -> crate::Result<Option<String>> {
+  let mut body = String::new();
+
+  loop {
+    let line = match source.is_interactive() {
+      true => match source.next_typed_line() {
+        Some(line) => Some(line),
+        None => {
+          source.count_line();
+          read_input("> ", cmd_completions, history, options)?
+        }
+      },
+      false => source.next_line()?,
+    };
+    let Some(line) = line else {
+      if source.is_interactive() {
+        return Ok(None);
+      }
+      eprintln!(
+        "shell: warning: here-document delimited by end-of-file (wanted `{}')",
+        delimiter
+      );
+      return Ok(Some(body));
+    };
+    let line = if strip_tabs {
+      line.trim_start_matches('\t')
+    } else {
+      line.as_str()
+    };
+
+    if line == delimiter {
+      return Ok(Some(body));
+    }
+
+    body.push_str(line);
+    body.push('\n');
+  }
+}
+
+/// Keeps the terminal in raw mode while alive, dropping it restores the original mode, even on an
+/// early return or a panic. Bracketed paste is enabled along with raw mode, so pastes can be told
+/// apart from typed input.
+struct RawModeGuard;
+
+impl RawModeGuard {
+  fn new() -> io::Result<Self> {
+    enable_raw_mode()?;
+    print!("{}", BRACKETED_PASTE_ON);
+    io::stdout().flush()?;
+    Ok(Self)
+  }
+}
+
+impl Drop for RawModeGuard {
+  fn drop(&mut self) {
+    // Nothing to do about a failure while dropping
+    print!("{}", BRACKETED_PASTE_OFF);
+    let _ = io::stdout().flush();
+    let _ = disable_raw_mode();
+  }
+}
+
+/// Terminal attributes from before raw mode was first enabled, restored when leaving it
+static ORIGINAL_TERMIOS: OnceLock<libc::termios> = OnceLock::new();
+
+/// Puts the terminal in raw mode: input is read byte by byte, without echo (so the shell can decide
+/// which chars to echo and which chars are special), and output isn't post-processed
 fn enable_raw_mode() -> io::Result<()> {
-  Command::new("stty")
-    .args(&[
-      "raw",   // Raw mode
-      "-echo", // Don't echo input, so the shell can decide which chars to echo and which chars are special
-      "min", "1", // Return after 1 character
-      "time", "0", // No timeout
-    ])
-    .status()?;
-  Ok(())
+  let mut termios = get_termios()?;
+  // Only the first time, later calls would save the raw attributes if restoring had failed
+  let _ = ORIGINAL_TERMIOS.set(termios);
+
+  // SAFETY: termios is a valid, initialized struct
+  unsafe { libc::cfmakeraw(&mut termios) };
+  // Return after 1 char, without timeout
+  termios.c_cc[libc::VMIN] = 1;
+  termios.c_cc[libc::VTIME] = 0;
+
+  set_termios(&termios)
+}
+
+/// Number of columns of the terminal, `COLUMNS` when it can't be queried, 80 without either
+fn terminal_width() -> usize {
+  // SAFETY: winsize is plain data, which the ioctl fills in
+  let mut size: libc::winsize = unsafe { mem::zeroed() };
+  if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != -1
+    && size.ws_col > 0
+  {
+    return size.ws_col as usize;
+  }
+
+  env::var("COLUMNS")
+    .ok()
+    .and_then(|columns| columns.parse().ok())
+    .filter(|columns| *columns > 0)
+    .unwrap_or(80)
 }
 
-fn disable_raw_mode() -> io::Result<()> {
-  Command::new("stty")
-    .arg("cooked") // Restore normal mode
-    .status()?;
+/// Restores the terminal attributes from before raw mode, so commands (and builtins like `read`)
+/// get the usual line editing and echo
+pub fn disable_raw_mode() -> io::Result<()> {
+  match ORIGINAL_TERMIOS.get() {
+    Some(termios) => set_termios(termios),
+    None => Ok(()),
+  }
+}
+
+fn get_termios() -> io::Result<libc::termios> {
+  // SAFETY: termios is plain data, which tcgetattr fills in
+  let mut termios: libc::termios = unsafe { mem::zeroed() };
+  if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } == -1 {
+    return Err(io::Error::last_os_error());
+  }
+
+  Ok(termios)
+}
+
+fn set_termios(termios: &libc::termios) -> io::Result<()> {
+  // SAFETY: termios is a valid struct, only read by tcsetattr
+  if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, termios) } == -1 {
+    return Err(io::Error::last_os_error());
+  }
+
   Ok(())
 }
 
-// TODO
-// [] handle edit in-between input with left and right arrow (maintain cursor position, char insertion based on it)
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_word_movement() {
+    let input = b"echo  hello world";
+    assert_eq!(previous_word_start(input, input.len()), 12);
+    assert_eq!(previous_word_start(input, 12), 6);
+    assert_eq!(previous_word_start(input, 5), 0);
+    assert_eq!(previous_word_start(input, 0), 0);
+
+    assert_eq!(next_word_end(input, 0), 4);
+    assert_eq!(next_word_end(input, 4), 11);
+    assert_eq!(next_word_end(input, 12), input.len());
+    assert_eq!(next_word_end(input, input.len()), input.len());
+
+    assert_eq!(next_word_start(input, 0), 6);
+    assert_eq!(next_word_start(input, 4), 6);
+    assert_eq!(next_word_start(input, 12), input.len());
+  }
+
+  #[test]
+  fn test_common_prefix_extension() {
+    let mut trie = Trie::new();
+    for word in ["日本語", "日本人", "café-menu", "café-mode", "über"] {
+      trie.insert(word);
+    }
+
+    assert_eq!(
+      common_prefix_extension(&mut trie, "日").as_deref(),
+      Some("本")
+    );
+    assert_eq!(common_prefix_extension(&mut trie, "日本"), None);
+    assert_eq!(
+      common_prefix_extension(&mut trie, "caf").as_deref(),
+      Some("é-m")
+    );
+    assert_eq!(common_prefix_extension(&mut trie, "café-m"), None);
+    assert_eq!(common_prefix_extension(&mut trie, "x"), None);
+    assert_eq!(common_prefix_extension(&mut trie, ""), None);
+
+    let mut trie = Trie::new();
+    trie.insert("ssh");
+    trie.insert("ssh-add");
+    assert_eq!(
+      common_prefix_extension(&mut trie, "").as_deref(),
+      Some("ssh")
+    );
+  }
+
+  #[test]
+  fn test_multibyte_chars() {
+    let mut rest = &b"\xa9\xf0\x9f\x98\x80"[..];
+    assert_eq!(read_char(b'a', &mut rest).unwrap(), Some('a'));
+    assert_eq!(read_char(0xc3, &mut rest).unwrap(), Some('é'));
+    assert_eq!(
+      read_char(0xf0, &mut &b"\x9f\x98\x80"[..]).unwrap(),
+      Some('😀')
+    );
+    assert_eq!(read_char(0x80, &mut rest).unwrap(), None);
+    assert_eq!(read_char(0xe2, &mut &b"ab"[..]).unwrap(), None);
+
+    let input = "aé😀b".as_bytes();
+    assert_eq!(previous_char_start(input, input.len()), 7);
+    assert_eq!(previous_char_start(input, 7), 3);
+    assert_eq!(previous_char_start(input, 3), 1);
+    assert_eq!(previous_char_start(input, 0), 0);
+    assert_eq!(next_char_start(input, 1), 3);
+    assert_eq!(next_char_start(input, 3), 7);
+    assert_eq!(next_char_start(input, 8), 8);
+
+    // Backspace erases as many columns as the deleted char took
+    let removed = &"a中"[previous_char_start("a中".as_bytes(), 4)..];
+    assert_eq!(removed, "中");
+    assert_eq!(erase_columns(display_width(removed)), "\x1b[2D  \x1b[2D");
+    assert_eq!(erase_columns(display_width("é")), "\x1b[1D \x1b[1D");
+  }
+
+  #[test]
+  fn test_read_paste() {
+    let mut stdin = &b"echo a\r\necho b\rtail\x1b[201~after"[..];
+    assert_eq!(read_paste(&mut stdin).unwrap(), b"echo a\necho b\ntail");
+    assert_eq!(stdin, b"after");
+
+    // Nothing past the end is read, even with an escape sequence just before it
+    let mut stdin = &b"a\x1b[D\x1b[201~\recho next\r"[..];
+    assert_eq!(read_paste(&mut stdin).unwrap(), b"a\x1b[D");
+    assert_eq!(stdin, b"\recho next\r");
+  }
+
+  #[test]
+  fn test_script_skips_shebang() {
+    let mut source = LineSource::script("#!/bin/shell\necho hi\n#!not first\n");
+    assert!(!source.is_interactive());
+
+    let mut lines = Vec::new();
+    while let Some(line) = source.next_line().unwrap() {
+      lines.push(line);
+    }
+    assert_eq!(lines, vec!["", "echo hi", "#!not first"]);
+    assert_eq!(source.line_number(), 3);
+  }
+
+  #[test]
+  fn test_typed_lines() {
+    let mut source = LineSource::terminal();
+    source.push_typed("cat <<EOF\nbody\nEOF");
+    assert_eq!(source.next_typed_line().as_deref(), Some("cat <<EOF"));
+    assert_eq!(source.next_typed_line().as_deref(), Some("body"));
+    assert_eq!(source.line_number(), 2);
+
+    source.clear_typed();
+    assert_eq!(source.next_typed_line(), None);
+    assert_eq!(source.line_number(), 2);
+
+    // Scripts are never typed
+    let mut source = LineSource::script("echo hi");
+    source.push_typed("echo typed");
+    assert_eq!(source.next_typed_line(), None);
+  }
+}
@@ -0,0 +1,392 @@
+use crate::signals;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ExitStatus};
+use std::thread;
+use std::time::Duration;
+
+/// How often `wait` checks whether the job it waits for has finished
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+  Running,
+  Stopped,
+  Done(ExitStatus),
+}
+
+/// Process group a spawned command is placed in. Every pipeline runs in its own group, so keyboard
+/// signals (Ctrl-C, Ctrl-Z) reach only the foreground one, and the shell isn't affected by them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessGroup {
+  /// Group to join, None for a new group led by the spawned process
+  pub pgid: Option<i32>,
+  /// Whether the group is given the terminal
+  pub foreground: bool,
+}
+
+/// A change in a process' state, as reported by `waitpid`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStatus {
+  Exited(ExitStatus),
+  Stopped,
+  Continued,
+}
+
+/// A pipeline running in the background, launched with a trailing `&`
+#[derive(Debug)]
+pub struct Job {
+  pub id: usize,
+  pub pid: u32,
+  /// Process group of the job, background jobs get their own so keyboard signals don't reach them
+  pub pgid: i32,
+  pub command: String,
+  pub state: JobState,
+}
+
+impl Job {
+  /// Human readable state, as shown by `jobs` and the completion notices
+  pub fn state_label(&self) -> String {
+    match self.state {
+      JobState::Running => "Running".to_string(),
+      JobState::Stopped => "Stopped".to_string(),
+      JobState::Done(status) => match (status.code(), status.signal()) {
+        (Some(0), _) => "Done".to_string(),
+        (Some(code), _) => format!("Exit {}", code),
+        (None, Some(libc::SIGTERM)) => "Terminated".to_string(),
+        (None, Some(libc::SIGKILL)) => "Killed".to_string(),
+        (None, Some(libc::SIGINT)) => "Interrupt".to_string(),
+        (None, Some(signal)) => format!("Signal {}", signal),
+        (None, None) => "Done".to_string(),
+      },
+    }
+  }
+
+  pub fn is_finished(&self) -> bool {
+    matches!(self.state, JobState::Done(_))
+  }
+
+  /// Whether the job runs in its own process group, instead of sharing the shell's
+  fn has_own_group(&self) -> bool {
+    // SAFETY: getpgrp has no memory safety requirements
+    self.pgid != unsafe { libc::getpgrp() }
+  }
+
+  /// Sends a signal to the job, to its whole process group when it has its own
+  pub fn signal(&self, signal: libc::c_int) -> io::Result<()> {
+    if self.has_own_group() {
+      signals::send_signal(-self.pgid, signal)
+    } else {
+      signals::send_signal(self.pid as i32, signal)
+    }
+  }
+}
+
+/// Background jobs table, owned by the shell for its whole lifetime
+#[derive(Debug, Default)]
+pub struct Jobs {
+  jobs: Vec<Job>,
+}
+
+impl Jobs {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Adds a background child to the table, returning the new job.
+  ///
+  /// Same as bash, ids start at 1 and continue from the highest id in use. The child is waited for
+  /// by pid from then on, its handle is dropped (which neither kills nor waits for it).
+  pub fn add(&mut self, child: Child, command: String) -> &Job {
+    self.push(child.id(), command, JobState::Running)
+  }
+
+  /// Adds a foreground process that was stopped (Ctrl-Z) to the table, returning the new job
+  pub fn add_stopped(&mut self, pid: u32, command: String) -> &Job {
+    self.push(pid, command, JobState::Stopped)
+  }
+
+  fn push(&mut self, pid: u32, command: String, state: JobState) -> &Job {
+    let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+    // SAFETY: getpgid has no memory safety requirements
+    let pgid = unsafe { libc::getpgid(pid as i32) };
+
+    self.jobs.push(Job {
+      id,
+      pid,
+      pgid,
+      command,
+      state,
+    });
+
+    self.jobs.last().unwrap()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Job> {
+    self.jobs.iter()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.jobs.is_empty()
+  }
+
+  /// Finds a job from a job spec: `%n` for job `n`, `%%` or `%+` for the current (most recent) job
+  /// and `%-` for the previous one
+  pub fn resolve(&self, spec: &str) -> Option<&Job> {
+    match spec {
+      "%%" | "%+" => self.jobs.last(),
+      "%-" => self.jobs.iter().rev().nth(1),
+      spec => {
+        let id = spec.strip_prefix('%')?.parse::<usize>().ok()?;
+        self.jobs.iter().find(|job| job.id == id)
+      }
+    }
+  }
+
+  /// Polls jobs without blocking, recording the ones that have finished, stopped or continued
+  pub fn reap(&mut self) {
+    let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+    for job in self.jobs.iter_mut() {
+      if job.is_finished() {
+        continue;
+      }
+
+      match wait_pid(job.pid as i32, flags) {
+        Ok(Some(WaitStatus::Exited(status))) => job.state = JobState::Done(status),
+        Ok(Some(WaitStatus::Stopped)) => job.state = JobState::Stopped,
+        Ok(Some(WaitStatus::Continued)) => job.state = JobState::Running,
+        Ok(None) | Err(_) => {}
+      }
+    }
+  }
+
+  /// Resumes a job in the foreground, giving it the terminal and waiting until it exits or is
+  /// stopped again. A job that exits is removed from the table.
+  pub fn foreground(&mut self, id: usize) -> io::Result<WaitStatus> {
+    let index = self
+      .jobs
+      .iter()
+      .position(|job| job.id == id)
+      .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    let job = &self.jobs[index];
+    let own_group = job.has_own_group();
+
+    if own_group {
+      set_terminal_foreground(job.pgid);
+    }
+    let result = job
+      .signal(libc::SIGCONT)
+      .and_then(|_| wait_pid(job.pid as i32, libc::WUNTRACED));
+    if own_group {
+      take_terminal();
+    }
+
+    // A blocking wait always reports a change
+    let status = result?.unwrap_or(WaitStatus::Continued);
+    match status {
+      WaitStatus::Exited(_) => {
+        self.jobs.remove(index);
+      }
+      WaitStatus::Stopped => self.jobs[index].state = JobState::Stopped,
+      WaitStatus::Continued => self.jobs[index].state = JobState::Running,
+    }
+
+    Ok(status)
+  }
+
+  /// Waits for a job to finish, then removes it from the table. Returns its exit status along with
+  /// its completion notice, or None when the wait was interrupted (Ctrl-C).
+  ///
+  /// The job is polled instead of waited for with a blocking call, which Ctrl-C couldn't interrupt
+  /// as the shell's signal handlers restart interrupted calls.
+  pub fn wait(&mut self, id: usize) -> io::Result<Option<(ExitStatus, String)>> {
+    let index = self
+      .jobs
+      .iter()
+      .position(|job| job.id == id)
+      .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+    loop {
+      let job = &mut self.jobs[index];
+      if let JobState::Done(status) = job.state {
+        let notice = self.format_job(index);
+        self.jobs.remove(index);
+        return Ok(Some((status, notice)));
+      }
+
+      match wait_pid(
+        job.pid as i32,
+        libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED,
+      )? {
+        Some(WaitStatus::Exited(status)) => job.state = JobState::Done(status),
+        Some(WaitStatus::Stopped) => job.state = JobState::Stopped,
+        Some(WaitStatus::Continued) => job.state = JobState::Running,
+        None if signals::take_interrupted() => return Ok(None),
+        None => thread::sleep(WAIT_POLL_INTERVAL),
+      }
+    }
+  }
+
+  /// Resumes a stopped job in the background
+  pub fn background(&mut self, id: usize) -> io::Result<&Job> {
+    let job = self
+      .jobs
+      .iter_mut()
+      .find(|job| job.id == id)
+      .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+    if job.state == JobState::Stopped {
+      job.signal(libc::SIGCONT)?;
+      job.state = JobState::Running;
+    }
+
+    Ok(job)
+  }
+
+  /// Formats every job as a `jobs` listing line
+  pub fn list(&self) -> Vec<String> {
+    (0..self.jobs.len())
+      .map(|index| self.format_job(index))
+      .collect()
+  }
+
+  /// Formats a job as a `jobs` listing line
+  pub fn format(&self, id: usize) -> Option<String> {
+    let index = self.jobs.iter().position(|job| job.id == id)?;
+    Some(self.format_job(index))
+  }
+
+  /// Removes the finished jobs from the table, returning their completion notices
+  pub fn drain_finished(&mut self) -> Vec<String> {
+    let notices = (0..self.jobs.len())
+      .filter(|index| self.jobs[*index].is_finished())
+      .map(|index| self.format_job(index))
+      .collect();
+
+    self.jobs.retain(|job| !job.is_finished());
+
+    notices
+  }
+
+  /// Formats a job like bash does with `jobs -l`, e.g. `[1]+  1234  Running  sleep 10 &`. The most
+  /// recent job is marked with `+` and the one before it with `-`.
+  fn format_job(&self, index: usize) -> String {
+    let job = &self.jobs[index];
+    let marker = match self.jobs.len() - index {
+      1 => '+',
+      2 => '-',
+      _ => ' ',
+    };
+    let background = if job.state == JobState::Running {
+      " &"
+    } else {
+      ""
+    };
+
+    format!(
+      "[{}]{}  {:<7} {:<22}{}{}",
+      job.id,
+      marker,
+      job.pid,
+      job.state_label(),
+      job.command,
+      background
+    )
+  }
+}
+
+/// Waits for a process to change state, as selected by the `waitpid` flags. Returns None when
+/// nothing changed with `WNOHANG`.
+pub fn wait_pid(pid: i32, flags: libc::c_int) -> io::Result<Option<WaitStatus>> {
+  let mut status: libc::c_int = 0;
+  // SAFETY: status is a valid pointer for the duration of the call
+  let result = unsafe { libc::waitpid(pid, &mut status, flags) };
+
+  match result {
+    -1 => Err(io::Error::last_os_error()),
+    0 => Ok(None),
+    _ if libc::WIFSTOPPED(status) => Ok(Some(WaitStatus::Stopped)),
+    _ if libc::WIFCONTINUED(status) => Ok(Some(WaitStatus::Continued)),
+    _ => Ok(Some(WaitStatus::Exited(ExitStatus::from_raw(status)))),
+  }
+}
+
+/// Makes the shell's process group the terminal's foreground one again
+pub fn take_terminal() {
+  // SAFETY: getpgrp has no memory safety requirements
+  set_terminal_foreground(unsafe { libc::getpgrp() });
+}
+
+/// Makes a process group the terminal's foreground one, so it receives keyboard signals (Ctrl-C,
+/// Ctrl-Z) and may read from it. Does nothing when stdin isn't a terminal.
+pub fn set_terminal_foreground(pgid: i32) {
+  // SAFETY: only signal sets and the terminal's process group are changed, with valid pointers
+  unsafe {
+    if libc::isatty(libc::STDIN_FILENO) == 0 {
+      return;
+    }
+
+    // Changing the foreground group from a background one raises SIGTTOU, blocking it lets the
+    // shell take the terminal back
+    let mut block: libc::sigset_t = std::mem::zeroed();
+    let mut previous: libc::sigset_t = std::mem::zeroed();
+    libc::sigemptyset(&mut block);
+    libc::sigaddset(&mut block, libc::SIGTTOU);
+    libc::pthread_sigmask(libc::SIG_BLOCK, &block, &mut previous);
+
+    libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+
+    libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::process::Command;
+
+  fn reap_until_finished(jobs: &mut Jobs) {
+    for _ in 0..100 {
+      jobs.reap();
+      if jobs.iter().all(|job| job.is_finished()) {
+        return;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    panic!("jobs did not finish in time");
+  }
+
+  #[test]
+  fn test_jobs_ids_and_completion() {
+    let mut jobs = Jobs::new();
+    let ok = Command::new("true").spawn().unwrap();
+    let failed = Command::new("false").spawn().unwrap();
+
+    assert_eq!(jobs.add(ok, "true".to_string()).id, 1);
+    assert_eq!(jobs.add(failed, "false".to_string()).id, 2);
+
+    reap_until_finished(&mut jobs);
+
+    let notices = jobs.drain_finished();
+    assert_eq!(notices.len(), 2);
+    assert!(notices[0].starts_with("[1]-"));
+    assert!(notices[0].contains("Done"));
+    assert!(notices[1].starts_with("[2]+"));
+    assert!(notices[1].contains("Exit 1"));
+    assert!(jobs.is_empty());
+  }
+
+  #[test]
+  fn test_wait_removes_the_job() {
+    let mut jobs = Jobs::new();
+    let child = Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+    let id = jobs.add(child, "sh -c 'exit 3'".to_string()).id;
+
+    let (status, notice) = jobs.wait(id).unwrap().unwrap();
+    assert_eq!(status.code(), Some(3));
+    assert!(notice.contains("Exit 3"));
+    assert!(jobs.is_empty());
+    assert!(jobs.wait(id).is_err());
+  }
+}
@@ -1,28 +1,41 @@
 #![allow(dead_code)]
 // Bash impl docs, see https://www.gnu.org/software/bash/manual/bash.html#Redirecting-Output
 
-use args::parse_args;
+use args::{expand_here_doc, parse_args, Pipeline};
 use command::Cmd;
-use std::env;
-use std::io::{self, Write};
-use std::process::Stdio;
+use std::process;
+use std::time::Instant;
+use std::{env, fs, mem};
+use std::{io, panic};
 
+mod aliases;
 mod ansi_codes;
 mod args;
+mod arithmetic;
 mod command;
+mod context;
 mod error;
+mod hash;
 mod history;
 mod input;
+mod jobs;
+mod options;
+mod prompt;
+mod shell_options;
+mod signals;
 mod tab_completions;
+mod traps;
 mod trie;
 mod utils;
+mod variables;
 mod writer;
 
 use crate::command::{CmdInput, ExecutionOutput};
-use crate::history::History;
-use crate::input::read_input;
-use crate::tab_completions::setup_cmd_completions;
-use crate::writer::{CmdOutput, CmdOutputWriter, Redirection};
+use crate::context::ShellContext;
+use crate::input::{read_here_doc, read_input, LineSource};
+use crate::jobs::{ProcessGroup, WaitStatus};
+use crate::variables::parse_assignment;
+use crate::writer::{CmdOutput, CmdOutputWriter, InputRedirection, Redirection};
 pub use error::Result;
 
 /// Main entry point for the shell implementation.
@@ -36,112 +49,743 @@ pub use error::Result;
 /// The shell runs in an infinite loop, continuously prompting for and processing user input
 /// until explicitly terminated (e.g., with the "exit" command).
 fn main() -> Result<()> {
-  let mut history = History::new();
+  // Keep the shell alive when Ctrl-C interrupts a running child
+  signals::install_handlers()?;
 
-  if let Ok(histfile) = env::var("HISTFILE") {
-    history.set_from_file(&histfile);
+  // Restore the terminal before the panic message is printed, the raw mode guard only restores it
+  // while unwinding, after the message
+  let default_hook = panic::take_hook();
+  panic::set_hook(Box::new(move |info| {
+    let _ = input::disable_raw_mode();
+    default_hook(info);
+  }));
+
+  let mut ctx = ShellContext::new();
+  // A command string (`-c`), a script given as argument, or one piped into stdin, is run without
+  // prompt, completions or history. The remaining args are the positional parameters.
+  let args = env::args().collect::<Vec<String>>();
+  let script = match args.get(1).map(|arg| arg.as_str()) {
+    Some("--version") => {
+      println!("{}", command::version());
+      return Ok(());
+    }
+    Some("-c") => {
+      let Some(command) = args.get(2) else {
+        eprintln!("shell: -c: option requires an argument");
+        process::exit(2);
+      };
+      // `$0` is the first arg after the command string, if any
+      ctx.variables.positional = match args.len() > 3 {
+        true => args[3..].to_vec(),
+        false => args[..1].to_vec(),
+      };
+      // Set when the shell runs a command substitution
+      ctx.inherit_subshell_state();
+      Some(command.clone())
+    }
+    Some(script) => match fs::read_to_string(script) {
+      Ok(content) => {
+        ctx.variables.positional = args[1..].to_vec();
+        Some(content)
+      }
+      Err(err) if err.kind() == io::ErrorKind::NotFound => {
+        eprintln!("shell: {}: No such file or directory", script);
+        process::exit(127);
+      }
+      Err(err) => {
+        eprintln!("shell: {}: {}", script, err);
+        process::exit(126);
+      }
+    },
+    None => {
+      ctx.variables.positional = args[..1].to_vec();
+      None
+    }
+  };
+
+  if let Some(script) = script {
+    let status = run_lines(&script, &mut ctx);
+    exit_shell(status, &mut ctx);
+  }
+
+  ctx.interactive = input::is_interactive();
+  ctx.source = match ctx.interactive {
+    true => LineSource::terminal(),
+    false => LineSource::stdin(),
+  };
+
+  if let (true, Ok(histfile)) = (ctx.source.is_interactive(), env::var("HISTFILE")) {
+    match ctx.history.set_from_file(&histfile) {
+      Ok(()) => {}
+      // Nothing was saved yet, the file is created on exit
+      Err(error::Error::IoError(err)) if err.kind() == io::ErrorKind::NotFound => {}
+      Err(err) => eprintln!("shell: {}: {}", histfile, err),
+    }
   }
 
   loop {
-    // Set up command completion for better user experience
-    let mut cmd_completions = setup_cmd_completions();
-
-    // Display the shell prompt
-    print!("$ ");
-    io::stdout().flush()?;
-    io::stderr().flush()?;
-
-    // Wait for user input
-    // let mut input = String::new();
-    // io::stdin().read_line(&mut input)?;
-    let input = match read_input(&mut cmd_completions, &history)? {
+    prepare_line(&mut ctx);
+
+    if !ctx.source.is_interactive() {
+      let Some(input) = ctx.source.next_line()? else {
+        // End of the input
+        exit_shell(ctx.variables.last_status, &mut ctx);
+      };
+
+      let status = run_line(&input, &mut ctx);
+      check_errexit(status, &mut ctx);
+      continue;
+    }
+
+    // Display the shell prompt and wait for user input
+    let prompt = prompt::render_prompt(ctx.variables.last_status);
+    let input = match read_input(&prompt, ctx.completions.get(), &ctx.history, &ctx.options)? {
       Some(input) => input,
-      None => continue,
+      // Interrupted with Ctrl-C
+      None => {
+        if let Some(handler) = ctx.traps.get("INT").cloned() {
+          execute_line(&handler, &mut ctx);
+        }
+        continue;
+      }
     };
 
-    // Push new command input into history stack
-    history.push(&input);
+    // A pasted snippet can span several lines, each of them is run as a typed line would be, and
+    // here-documents' bodies are read from the ones following them
+    ctx.source.push_typed(&input);
+    while let Some(line) = ctx.source.next_typed_line() {
+      // History references are replaced before the line is stored, the resolved line is shown
+      let line = match ctx.history.expand(&line) {
+        Ok(Some(expanded)) => {
+          println!("{}", expanded);
+          expanded
+        }
+        Ok(None) => line,
+        Err(err) => {
+          eprintln!("shell: {}", err);
+          ctx.variables.last_status = 1;
+          continue;
+        }
+      };
+
+      // Push new command input into history stack
+      ctx.history.push(&line);
 
-    // Skip empty input lines
-    if input.trim().is_empty() {
-      continue;
+      // The rest of a pasted snippet is abandoned too
+      let status = run_line(&line, &mut ctx);
+      if check_errexit(status, &mut ctx) {
+        ctx.source.clear_typed();
+      }
     }
+  }
+}
 
-    // Parse the input into a list of commands and their redirections
-    let cmds_list = parse_args(input.trim().to_string());
-    let len = cmds_list.len();
+/// With `set -e`, a failed line ends a non interactive shell, after running the `EXIT` trap. An
+/// interactive shell only abandons what it was running, returns whether to do so.
+fn check_errexit(status: i32, ctx: &mut ShellContext) -> bool {
+  if !ctx.options.errexit || status == 0 {
+    return false;
+  }
+
+  if !ctx.interactive {
+    exit_shell(status, ctx);
+  }
+  true
+}
+
+/// Work done before each line is read or run
+fn prepare_line(ctx: &mut ShellContext) {
+  // Interrupts handled while a child was running are done with
+  signals::take_interrupted();
 
-    // Variable to hold piped input between commands
-    let mut piped_stdin: Option<CmdInput> = None;
+  // Foreground processes were given the terminal, read the next line with it
+  jobs::take_terminal();
 
-    // Process each command in the pipeline
-    for (index, (cmd_args, redirection)) in cmds_list.iter().enumerate() {
-      // Check if this command's output should be piped to the next command
-      let is_piped = index < len - 1;
+  // Report background jobs that finished since the last prompt
+  ctx.jobs.reap();
+  for notice in ctx.jobs.drain_finished() {
+    println!("{}", notice);
+  }
+}
+
+/// Runs the lines of a script (`-c`, a script file or a sourced one) one after the other, reading
+/// the bodies of their here-documents from the lines that follow them. Returns the status of the
+/// last line run.
+pub fn run_lines(lines: &str, ctx: &mut ShellContext) -> i32 {
+  // A sourced script is read from until it ends, then the outer one carries on
+  let outer = mem::replace(&mut ctx.source, LineSource::script(lines));
+
+  loop {
+    prepare_line(ctx);
 
-      // Execute the command
-      let execution_output = match Cmd::from(cmd_args[0].clone()) {
-        Cmd::Unknown => {
-          println!("{}: command not found", input.trim());
-          ExecutionOutput::none()
+    match ctx.source.next_line() {
+      Ok(Some(line)) => {
+        let status = run_line(&line, ctx);
+        if check_errexit(status, ctx) {
+          break;
         }
-        command => command.exec(cmd_args.to_vec(), piped_stdin.take(), &mut history),
+      }
+      Ok(None) => break,
+      Err(err) => {
+        eprintln!("shell: {}", err);
+        ctx.variables.last_status = 1;
+        break;
+      }
+    }
+  }
+
+  ctx.source = outer;
+  ctx.variables.last_status
+}
+
+/// Runs a command line read from the terminal, piped input or a script, reading the bodies of its
+/// here-documents from the same place. Returns its exit status.
+pub fn run_line(line: &str, ctx: &mut ShellContext) -> i32 {
+  // Here-documents' bodies read since the last line are counted too
+  ctx.variables.line_number = ctx.source.line_number();
+
+  // Skip empty input lines
+  if line.trim().is_empty() {
+    return ctx.variables.last_status;
+  }
+
+  let line = &ctx.aliases.expand_line(line);
+
+  // Parse the input into a list of commands and their redirections
+  let mut pipeline = match parse_args(line.trim().to_string(), ctx) {
+    Ok(pipeline) => pipeline,
+    Err(err) => {
+      eprintln!("shell: {}", err);
+      ctx.variables.last_status = 2;
+      return 2;
+    }
+  };
+
+  // Here-documents' bodies follow the command line, in order
+  for cmd in pipeline.cmds.iter_mut() {
+    if let Some(InputRedirection::HereDoc {
+      delimiter,
+      strip_tabs,
+      expand,
+      content,
+    }) = &mut cmd.input
+    {
+      let body = read_here_doc(
+        delimiter,
+        *strip_tabs,
+        &mut ctx.source,
+        ctx.completions.get(),
+        &ctx.history,
+        &ctx.options,
+      );
+      let body = match body {
+        Ok(Some(body)) if *expand => expand_here_doc(&body, ctx).map(Some),
+        body => body,
       };
+      match body {
+        Ok(Some(body)) => *content = body,
+        // Interrupted, the line is abandoned
+        Ok(None) => return ctx.variables.last_status,
+        Err(err) => {
+          eprintln!("shell: {}", err);
+          ctx.variables.last_status = 1;
+          return 1;
+        }
+      }
+    }
+  }
+
+  execute_pipeline(pipeline, line, ctx);
+  ctx.variables.last_status
+}
+
+/// Runs a parsed command line, feeding each command's output to the next one in the pipeline
+fn execute_pipeline(pipeline: Pipeline, input: &str, ctx: &mut ShellContext) {
+  let len = pipeline.cmds.len();
+  let start = Instant::now();
+  let (start_user, start_system) = utils::cpu_times();
+
+  // Variable to hold piped input between commands
+  let mut piped_stdin: Option<CmdInput> = None;
+  let mut process_group = ProcessGroup {
+    pgid: None,
+    foreground: !pipeline.background,
+  };
+
+  // Process each command in the pipeline
+  for (index, cmd) in pipeline.cmds.iter().enumerate() {
+    let (cmd_args, redirection) = (&cmd.args, &cmd.redirection);
+    // Check if this command's output should be piped to the next command
+    let is_piped = index < len - 1;
+
+    if let Err(err) = redirection.check_noclobber(ctx.options.noclobber) {
+      eprintln!("shell: {}", err);
+      ctx.variables.last_status = 1;
+      piped_stdin = None;
+      continue;
+    }
+
+    if ctx.options.xtrace {
+      let line = cmd_args
+        .iter()
+        .map(|arg| utils::shell_quote(arg))
+        .collect::<Vec<String>>()
+        .join(" ");
+      eprintln!("+ {}", line);
+    }
+
+    // Leading `NAME=value` words are assignments, on their own they set shell variables
+    let assignments = cmd_args[..cmd.assignments]
+      .iter()
+      .filter_map(|arg| parse_assignment(arg))
+      .collect::<Vec<(&str, &str)>>();
+    let cmd_args = &cmd_args[assignments.len()..];
+    if cmd_args.is_empty() {
+      ctx.variables.last_status = 0;
+      for (name, value) in assignments {
+        if let Err(message) = ctx.variables.assign(name, value) {
+          eprintln!("shell: {}: {}", value, message);
+          ctx.variables.last_status = 1;
+        }
+      }
+      piped_stdin = None;
+      continue;
+    }
+
+    // Assignments before a command are only part of its environment
+    let saved_env = assignments
+      .into_iter()
+      .map(|(name, value)| {
+        let saved = env::var_os(name);
+        env::set_var(name, value);
+        (name, saved)
+      })
+      .collect::<Vec<_>>();
+
+    // Execute the command
+    let execution_output = match Cmd::from(cmd_args[0].clone()) {
+      Cmd::Unknown => {
+        println!("{}: command not found", input.trim());
+        ExecutionOutput::none().with_status(127)
+      }
+      command => {
+        // A command after a pipe that nothing was written to reads an empty input, not the
+        // shell's stdin
+        let piped_input = piped_stdin
+          .take()
+          .or_else(|| (index > 0).then(|| CmdInput::String(String::new())));
+        // Here-strings and here-documents replace any piped input
+        let cmd_input = match &cmd.input {
+          Some(InputRedirection::HereString { content }) => {
+            Some(CmdInput::String(format!("{}\n", content)))
+          }
+          Some(InputRedirection::HereDoc { content, .. }) => {
+            Some(CmdInput::String(content.clone()))
+          }
+          None => piped_input,
+        };
+        let exec = || {
+          command.exec(
+            cmd_args.to_vec(),
+            cmd_input,
+            redirection,
+            ctx,
+            process_group,
+          )
+        };
+        match command.runs_commands() {
+          true => exec_capturing_output(redirection, is_piped, exec),
+          false => exec(),
+        }
+      }
+    };
+
+    for (name, saved) in saved_env {
+      match saved {
+        Some(value) => env::set_var(name, value),
+        None => env::remove_var(name),
+      }
+    }
+
+    // The pipeline's first process leads its process group, the others join it
+    if let (None, Some(CmdOutput::Stream(child))) = (process_group.pgid, &execution_output.0) {
+      process_group.pgid = Some(child.id() as i32);
+    }
+
+    // Pipeline's exit status is the one of its last command
+    ctx.variables.last_status = execution_output.status();
 
-      // Handle the command output based on redirection and piping
-      match (execution_output, redirection) {
-        // First match arm: Handles piping between commands
-        // This arm matches when:
-        // 1. The command produced stdout output (Some(stdout))
-        // 2. There was no stderr output (None)
-        // 3. Either no redirection was specified or only stderr redirection was specified
-        // 4. This is not the last command in the pipeline (is_piped is true)
-        (ExecutionOutput(Some(stdout), None), Redirection::None | Redirection::Stderr { .. })
-          if is_piped =>
-        {
-          match stdout {
-            // If the output is a string, convert it to CmdInput::String for the next command
-            CmdOutput::Stdout(string) => {
-              // Pass the string output to the next command's stdin
-              piped_stdin = Some(CmdInput::String(string));
-            }
-            // If the output is binary data, convert it to CmdInput::Bytes for the next command
-            CmdOutput::StdoutBytes(bytes) => {
-              // Pass the binary output to the next command's stdin
-              piped_stdin = Some(CmdInput::Bytes(bytes));
-            }
-            CmdOutput::Stream(mut child) => {
-              let stdout = child.stdout.take().unwrap();
-              piped_stdin = Some(CmdInput::Pipe(Stdio::from(stdout)));
-            }
-            // Ignore other output types for piping
-            _ => {}
+    // Handle the command output based on redirection and piping
+    match (execution_output, redirection) {
+      // First match arm: Handles piping between commands
+      // This arm matches when:
+      // 1. The command produced stdout output (Some(stdout))
+      // 2. There was no stderr output (None)
+      // 3. Either no redirection was specified or only stderr redirection was specified
+      // 4. This is not the last command in the pipeline (is_piped is true)
+      (
+        ExecutionOutput(Some(stdout), None, _),
+        Redirection::None | Redirection::Stderr { .. } | Redirection::Fd { .. },
+      ) if is_piped => {
+        match stdout {
+          // If the output is a string, convert it to CmdInput::String for the next command
+          CmdOutput::Stdout(string) => {
+            // Pass the string output to the next command's stdin
+            piped_stdin = Some(CmdInput::String(string));
+          }
+          // If the output is binary data, convert it to CmdInput::Bytes for the next command
+          CmdOutput::StdoutBytes(bytes) => {
+            // Pass the binary output to the next command's stdin
+            piped_stdin = Some(CmdInput::Bytes(bytes));
           }
+          CmdOutput::Stream(mut child) => {
+            let stdout = child.stdout.take().unwrap();
+            piped_stdin = Some(CmdInput::Pipe(stdout));
+          }
+          // Read by the next command as it's written to
+          CmdOutput::Pipe(stdout) => {
+            piped_stdin = Some(CmdInput::Pipe(stdout));
+          }
+          // Ignore other output types for piping
+          _ => {}
         }
-        // Second match arm: Catch-all for all other cases
-        // This handles:
-        // 1. The last command in the pipeline (where output goes to terminal or file)
-        // 2. Commands with explicit stdout redirection
-        // 3. Commands that produced stderr output
-        (execution_output, redirection) => {
-          // Write the output according to the redirection rules
-          // This handles writing to files or the terminal based on redirection settings
-          let _ = write_execution_output(redirection.clone(), execution_output);
+      }
+      // Background pipeline's last command: forward its output without waiting for it, the child
+      // is kept in the jobs table instead
+      (ExecutionOutput(Some(CmdOutput::Stream(child)), stderr, _), redirection)
+        if pipeline.background && !is_piped =>
+      {
+        let writer = CmdOutputWriter::new(redirection.clone());
+        if let Some(stderr) = stderr {
+          writer.write_cmd_output(stderr);
+        }
+
+        let child = writer.write_background_output(child);
+        let command = input.trim().trim_end_matches('&').trim_end().to_string();
+        let job = ctx.jobs.add(child, command);
+        println!("[{}] {}", job.id, job.pid);
+      }
+      // Second match arm: Catch-all for all other cases
+      // This handles:
+      // 1. The last command in the pipeline (where output goes to terminal or file)
+      // 2. Commands with explicit stdout redirection
+      // 3. Commands that produced stderr output
+      (execution_output, redirection) => {
+        let pid = match &execution_output.0 {
+          Some(CmdOutput::Stream(child)) => Some(child.id()),
+          _ => None,
+        };
+
+        // Write the output according to the redirection rules
+        // This handles writing to files or the terminal based on redirection settings
+        let wait_status = write_execution_output(redirection.clone(), execution_output);
+
+        match (wait_status, pid) {
+          // A streamed child's status is only known once it exited
+          (Some(WaitStatus::Exited(status)), _) => {
+            ctx.variables.last_status = utils::status_code(status);
+          }
+          // Stopped with Ctrl-Z, keep it as a job to resume with `fg` or `bg`
+          (Some(WaitStatus::Stopped), Some(pid)) => {
+            let id = ctx.jobs.add_stopped(pid, input.trim().to_string()).id;
+            println!("{}", ctx.jobs.format(id).unwrap());
+            ctx.variables.last_status = 128 + libc::SIGTSTP;
+          }
+          _ => {}
         }
       }
     }
   }
+
+  // Background pipelines are still running, there's nothing to report yet
+  if pipeline.timed && !pipeline.background {
+    let (user, system) = utils::cpu_times();
+    eprintln!(
+      "\nreal\t{}\nuser\t{}\nsys\t{}",
+      utils::format_duration(start.elapsed()),
+      utils::format_duration(user.saturating_sub(start_user)),
+      utils::format_duration(system.saturating_sub(start_system))
+    );
+  }
 }
 
-fn write_execution_output(redirection: Redirection, execution_output: ExecutionOutput) {
+/// Parses and runs a command line that isn't typed at the prompt, like a trap's command
+pub fn execute_line(input: &str, ctx: &mut ShellContext) {
+  match parse_args(input.trim().to_string(), ctx) {
+    Ok(pipeline) => execute_pipeline(pipeline, input, ctx),
+    Err(err) => {
+      eprintln!("shell: {}", err);
+      ctx.variables.last_status = 2;
+    }
+  }
+}
+
+/// Exits the shell, after running the `EXIT` trap and saving the history to `HISTFILE`
+pub fn exit_shell(code: i32, ctx: &mut ShellContext) -> ! {
+  // Removed first, so an `exit` in the handler doesn't run it again
+  if let Some(handler) = ctx.traps.remove("EXIT") {
+    execute_line(&handler, ctx);
+  }
+
+  if let Ok(histfile) = env::var("HISTFILE") {
+    if let Err(err) = ctx.history.write_to_file(&histfile, true) {
+      eprintln!("shell: {}: {}", histfile, err);
+    }
+  }
+
+  process::exit(code)
+}
+
+/// Writes the output of a command, returning how its streamed child ended (if any)
+/// Runs a command whose commands write their output straight to the shell's streams, like `source`.
+/// The streams that are redirected or piped are captured, to be handled as the command's output.
+fn exec_capturing_output(
+  redirection: &Redirection,
+  is_piped: bool,
+  exec: impl FnOnce() -> ExecutionOutput,
+) -> ExecutionOutput {
+  let stdout = is_piped
+    || matches!(
+      redirection,
+      Redirection::Stdout { .. } | Redirection::Both { .. } | Redirection::Tee { .. }
+    );
+  let stderr = matches!(
+    redirection,
+    Redirection::Stderr { .. } | Redirection::Both { .. } | Redirection::Tee { .. }
+  );
+  if !stdout && !stderr {
+    return exec();
+  }
+
+  match writer::capture_output(stdout, stderr, exec) {
+    Ok((ExecutionOutput(_, own_stderr, status), captured_stdout, captured_stderr)) => {
+      let captured_stderr =
+        (!captured_stderr.is_empty()).then(|| CmdOutput::StderrBytes(captured_stderr));
+      ExecutionOutput(
+        stdout.then(|| CmdOutput::StdoutBytes(captured_stdout)),
+        own_stderr.or(captured_stderr),
+        status,
+      )
+    }
+    Err(err) => ExecutionOutput::stderr(utils::io_error_message(&err)),
+  }
+}
+
+fn write_execution_output(
+  redirection: Redirection,
+  execution_output: ExecutionOutput,
+) -> Option<WaitStatus> {
   let writer = CmdOutputWriter::new(redirection);
-  let ExecutionOutput(stdout, stderr) = execution_output;
+  let ExecutionOutput(stdout, stderr, _) = execution_output;
+  let mut wait_status = None;
 
   if let Some(stdout) = stdout {
-    writer.write_cmd_output(stdout);
+    wait_status = writer.write_cmd_output(stdout);
   }
 
   if let Some(stderr) = stderr {
     writer.write_cmd_output(stderr);
   }
+
+  wait_status
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_temp_dir;
+
+  #[test]
+  fn test_run_lines() {
+    let dir = test_temp_dir("run-lines");
+    let out = dir.join("out.txt");
+    let mut ctx = ShellContext::new();
+    ctx.variables.positional = vec!["shell".to_string()];
+
+    // Each line sees what the previous ones did, here-documents' bodies are the following lines
+    let script = format!(
+      "out={}\necho one > $out\nn=2\ncat <<EOF | cat >> $out\n$n\nEOF\nfalse\n",
+      out.display()
+    );
+    let status = run_lines(&script, &mut ctx);
+
+    assert_eq!(status, 1);
+    assert_eq!(fs::read_to_string(&out).unwrap(), "one\n2\n");
+    assert_eq!(ctx.variables.get("n").as_deref(), Some("2"));
+
+    // The status is the last line's, a sourced file runs in the same shell
+    let script = dir.join("script.sh");
+    fs::write(&script, "m=$1\necho sourced $m\ntrue\n").unwrap();
+    let status = run_line(&format!("source {} 3", script.display()), &mut ctx);
+    assert_eq!(status, 0);
+    assert_eq!(ctx.variables.get("m").as_deref(), Some("3"));
+    assert_eq!(run_line("echo $(true", &mut ctx), 2);
+
+    // Its commands' output is redirected or piped as the output of `source`. Other tests may print
+    // while it's captured, only the sourced line is looked for.
+    let line = format!("source {} 4 > {}", script.display(), out.display());
+    run_line(&line, &mut ctx);
+    assert!(fs::read_to_string(&out).unwrap().contains("sourced 4\n"));
+    let line = format!(
+      "source {} 5 | tr a-z A-Z > {}",
+      script.display(),
+      out.display()
+    );
+    run_line(&line, &mut ctx);
+    assert!(fs::read_to_string(&out).unwrap().contains("SOURCED 5\n"));
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_redirected_output_matches_terminal_output() {
+    let dir = test_temp_dir("redirected-output");
+    let mut ctx = ShellContext::new();
+
+    // Piped into `cat`, a builtin's output is passed on exactly as it would be printed
+    for line in ["echo hi", "echo -n hi", "echo", "type echo", "version"] {
+      let redirected = dir.join("redirected.txt");
+      let piped = dir.join("piped.txt");
+      run_line(&format!("{} > {}", line, redirected.display()), &mut ctx);
+      run_line(&format!("{} | cat > {}", line, piped.display()), &mut ctx);
+
+      let redirected = fs::read(redirected).unwrap();
+      assert_eq!(redirected.len(), fs::read(piped).unwrap().len(), "{}", line);
+      assert_eq!(
+        redirected.last() == Some(&b'\n'),
+        line != "echo -n hi",
+        "{}",
+        line
+      );
+    }
+
+    // Errors end with a newline too, as eprintln would print them
+    let errors = dir.join("errors.txt");
+    run_line(&format!("cd /nope 2> {}", errors.display()), &mut ctx);
+    run_line(&format!("cd /nope 2>> {}", errors.display()), &mut ctx);
+    assert_eq!(
+      fs::read_to_string(errors).unwrap(),
+      "cd: /nope: No such file or directory\n".repeat(2)
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_empty_pipe_input() {
+    let dir = test_temp_dir("empty-pipe");
+    let out = dir.join("out.txt");
+    let mut ctx = ShellContext::new();
+
+    // Nothing is written to the pipe by `false` or an assignment, `wc` reads an empty input
+    for line in ["false", "x=1", "echo hi > /dev/null"] {
+      run_line(&format!("{} | wc -c > {}", line, out.display()), &mut ctx);
+      assert_eq!(fs::read_to_string(&out).unwrap().trim(), "0", "{}", line);
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_line_number() {
+    let dir = test_temp_dir("line-number");
+    let out = dir.join("out.txt");
+    let mut ctx = ShellContext::new();
+
+    // Here-documents' bodies and delimiters are lines too
+    let script = format!(
+      "\nread x <<EOF\nbody\nEOF\necho $LINENO > {}",
+      out.display()
+    );
+    run_lines(&script, &mut ctx);
+    assert_eq!(fs::read_to_string(&out).unwrap(), "5\n");
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_umask() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = test_temp_dir("umask");
+    let out = dir.join("out.txt");
+    let mut ctx = ShellContext::new();
+    let saved = run_line(&format!("umask > {}", out.display()), &mut ctx);
+    assert_eq!(saved, 0);
+    let saved = fs::read_to_string(&out).unwrap().trim().to_string();
+
+    // The only test changing the mask, files created meanwhile by other tests don't check modes
+    assert_eq!(run_line("umask 027", &mut ctx), 0);
+    let file = dir.join("file.txt");
+    run_line(&format!("umask > {}", file.display()), &mut ctx);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "0027\n");
+    let mode = fs::metadata(&file).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+
+    for mode in ["9", "abc", "+7", "-7", "1000"] {
+      assert_eq!(
+        run_line(&format!("umask '{}' 2> /dev/null", mode), &mut ctx),
+        1
+      );
+    }
+    assert_eq!(run_line("umask 1 2 2> /dev/null", &mut ctx), 2);
+    run_line(&format!("umask > {}", out.display()), &mut ctx);
+    assert_eq!(fs::read_to_string(&out).unwrap(), "0027\n");
+
+    run_line(&format!("umask {}", saved), &mut ctx);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_type_reports_aliases_first() {
+    let dir = test_temp_dir("type-aliases");
+    let out = dir.join("out.txt");
+    let mut ctx = ShellContext::new();
+
+    // `sh` is both an alias and an executable, the alias wins
+    run_line("alias sh='echo aliased' ll='ls -la'", &mut ctx);
+    run_line(&format!("type sh ll > {}", out.display()), &mut ctx);
+    assert_eq!(
+      fs::read_to_string(&out).unwrap(),
+      "sh is aliased to 'echo aliased'\nll is aliased to 'ls -la'\n"
+    );
+
+    run_line(&format!("type -a sh > {}", out.display()), &mut ctx);
+    let lines = fs::read_to_string(&out).unwrap();
+    let lines = lines.lines().collect::<Vec<&str>>();
+    assert_eq!(lines[0], "sh is aliased to 'echo aliased'");
+    assert!(lines.len() > 1 && lines[1..].iter().all(|line| line.ends_with("/sh")));
+
+    run_line(&format!("type -t sh > {}", out.display()), &mut ctx);
+    assert_eq!(fs::read_to_string(&out).unwrap(), "alias\n");
+
+    // Running it runs the alias' value
+    run_line(&format!("sh > {}", out.display()), &mut ctx);
+    assert_eq!(fs::read_to_string(&out).unwrap(), "aliased\n");
+
+    run_line("unalias sh", &mut ctx);
+    run_line(&format!("type -t sh > {}", out.display()), &mut ctx);
+    assert_eq!(fs::read_to_string(&out).unwrap(), "file\n");
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_errexit_aborts_interactive_lines() {
+    let mut ctx = ShellContext::new();
+    ctx.interactive = true;
+
+    // The shell keeps running, only the rest of the lines are abandoned
+    let status = run_lines("set -e\nx=1\nfalse\nx=2", &mut ctx);
+    assert_eq!(status, 1);
+    assert_eq!(ctx.variables.get("x").as_deref(), Some("1"));
+
+    // Only the pipeline's last command status counts
+    assert_eq!(run_lines("false | cat\nx=4", &mut ctx), 0);
+    assert_eq!(ctx.variables.get("x").as_deref(), Some("4"));
+
+    ctx.options.errexit = false;
+    assert_eq!(run_lines("false\nx=3", &mut ctx), 0);
+    assert_eq!(ctx.variables.get("x").as_deref(), Some("3"));
+  }
 }
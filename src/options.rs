@@ -0,0 +1,173 @@
+use std::fmt;
+
+/// Flags parsed from a builtin's args, along with the remaining args (operands)
+#[derive(Debug, Default, PartialEq)]
+pub struct ParsedOptions {
+  // In the order they were given, a flag can be given more than once
+  flags: Vec<(char, Option<String>)>,
+  pub operands: Vec<String>,
+}
+
+impl ParsedOptions {
+  pub fn has(&self, flag: char) -> bool {
+    self.flags.iter().any(|(given, _)| *given == flag)
+  }
+
+  /// Value of a flag taking one, the last one if the flag is given more than once
+  pub fn value(&self, flag: char) -> Option<&str> {
+    self
+      .flags
+      .iter()
+      .rev()
+      .find(|(given, _)| *given == flag)
+      .and_then(|(_, value)| value.as_deref())
+  }
+
+  /// Every given flag in order, for flags overriding each other (like echo's `-e` and `-E`)
+  pub fn flags(&self) -> impl Iterator<Item = char> + '_ {
+    self.flags.iter().map(|(flag, _)| *flag)
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OptionsError {
+  InvalidOption(char),
+  MissingValue(char),
+}
+
+impl fmt::Display for OptionsError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::InvalidOption(flag) => write!(f, "-{}: invalid option", flag),
+      Self::MissingValue(flag) => write!(f, "-{}: option requires an argument", flag),
+    }
+  }
+}
+
+/// Parses the leading short flags of a builtin's args (without the builtin's name), like getopts.
+///
+/// `spec` lists the known flags, a flag followed by `:` takes a value (`"p:r"` accepts `-r`,
+/// `-p value` and `-pvalue`). Flags can be combined (`-ne`). Options end at the first arg not
+/// starting with `-`, at a lone `-`, or after `--`.
+pub fn parse_options(args: &[String], spec: &str) -> Result<ParsedOptions, OptionsError> {
+  parse(args, spec, false)
+}
+
+/// Same as `parse_options`, but args with unknown flags aren't an error: they end the options and
+/// are kept as operands, along with `--`. This is how echo treats its args. A flag taking a value
+/// can still be missing it.
+pub fn parse_known_options(args: &[String], spec: &str) -> Result<ParsedOptions, OptionsError> {
+  parse(args, spec, true)
+}
+
+fn parse(args: &[String], spec: &str, lenient: bool) -> Result<ParsedOptions, OptionsError> {
+  let takes_value = |flag: char| spec.contains(&format!("{}:", flag));
+  let is_known = |flag: char| flag != ':' && spec.contains(flag);
+
+  let mut parsed = ParsedOptions::default();
+  let mut args = args.iter();
+
+  while let Some(arg) = args.clone().next() {
+    if arg == "--" && !lenient {
+      args.next();
+      break;
+    }
+
+    let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) else {
+      break;
+    };
+    // An arg with unknown flags is the start of the operands in lenient mode
+    if lenient && !flags.chars().all(is_known) {
+      break;
+    }
+    args.next();
+
+    for (index, flag) in flags.char_indices() {
+      if !is_known(flag) {
+        return Err(OptionsError::InvalidOption(flag));
+      }
+      if !takes_value(flag) {
+        parsed.flags.push((flag, None));
+        continue;
+      }
+
+      // The value is the rest of this arg, or the next arg
+      let rest = &flags[index + flag.len_utf8()..];
+      let value = match rest.is_empty() {
+        true => args
+          .next()
+          .cloned()
+          .ok_or(OptionsError::MissingValue(flag))?,
+        false => rest.to_string(),
+      };
+      parsed.flags.push((flag, Some(value)));
+      break;
+    }
+  }
+
+  parsed.operands = args.cloned().collect();
+  Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  #[test]
+  fn test_combined_flags() {
+    let parsed = parse_options(&args(&["-ne", "-E", "hello", "-n"]), "neE").unwrap();
+    assert_eq!(parsed.flags().collect::<String>(), "neE");
+    assert!(parsed.has('n'));
+    assert!(!parsed.has('x'));
+    // Options end at the first operand
+    assert_eq!(parsed.operands, vec!["hello", "-n"]);
+  }
+
+  #[test]
+  fn test_end_of_options() {
+    let parsed = parse_options(&args(&["-a", "--", "-t", "ls"]), "at").unwrap();
+    assert!(parsed.has('a'));
+    assert!(!parsed.has('t'));
+    assert_eq!(parsed.operands, vec!["-t", "ls"]);
+
+    let parsed = parse_options(&args(&["-", "x"]), "a").unwrap();
+    assert_eq!(parsed.operands, vec!["-", "x"]);
+  }
+
+  #[test]
+  fn test_values() {
+    let parsed = parse_options(&args(&["-rp", "prompt> ", "-pagain", "name"]), "p:r").unwrap();
+    assert!(parsed.has('r'));
+    assert_eq!(parsed.value('p'), Some("again"));
+    assert_eq!(parsed.operands, vec!["name"]);
+
+    assert_eq!(
+      parse_options(&args(&["-p"]), "p:"),
+      Err(OptionsError::MissingValue('p'))
+    );
+    assert_eq!(
+      parse_options(&args(&["-ax"]), "a").unwrap_err().to_string(),
+      "-x: invalid option"
+    );
+  }
+
+  #[test]
+  fn test_known_options() {
+    let parsed = parse_known_options(&args(&["-n", "-x", "-e"]), "neE").unwrap();
+    assert_eq!(parsed.flags().collect::<String>(), "n");
+    assert_eq!(parsed.operands, vec!["-x", "-e"]);
+
+    let parsed = parse_known_options(&args(&["--", "a"]), "neE").unwrap();
+    assert_eq!(parsed.operands, vec!["--", "a"]);
+
+    // Operands aren't dropped silently when a value is missing
+    assert_eq!(
+      parse_known_options(&args(&["-n", "-p"]), "np:"),
+      Err(OptionsError::MissingValue('p'))
+    );
+  }
+}
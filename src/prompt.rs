@@ -0,0 +1,166 @@
+use crate::ansi_codes::{colors_enabled, Color};
+use crate::utils::git_branch;
+use std::env;
+use std::ffi::CStr;
+use std::path::Path;
+use std::{mem, ptr};
+
+/// Prompt shown when `PS1` isn't set
+const DEFAULT_PROMPT: &str = "$ ";
+
+/// Renders the prompt from `PS1`, supporting these bash escapes:
+/// - `\w` the current directory, with the home directory shortened to `~`
+/// - `\W` the current directory's name
+/// - `\u` the user name, `\h` the host name (up to the first `.`)
+/// - `\$` `#` for root, `$` otherwise
+/// - `\g` the current git branch, nothing outside of a repository (not a bash escape)
+/// - `\t` the current time, as `HH:MM:SS`
+/// - `\?` the last command's exit status, `\i` `✓` when it succeeded and `✗` otherwise (not bash
+///   escapes)
+/// - `\n` a newline, `\e` an escape char and `\\` a backslash
+///
+/// The current directory is shown in blue, the git branch in magenta and the status indicator in
+/// green or red, when stdout is a terminal and `NO_COLOR` isn't set.
+pub fn render_prompt(last_status: i32) -> String {
+  match env::var("PS1") {
+    Ok(ps1) => render(&ps1, last_status, colors_enabled(libc::STDOUT_FILENO)),
+    Err(_) => DEFAULT_PROMPT.to_string(),
+  }
+}
+
+fn render(ps1: &str, last_status: i32, colored: bool) -> String {
+  let paint = |color: Color, text: String| match colored {
+    true => color.paint(text),
+    false => text,
+  };
+
+  let mut prompt = String::new();
+  let mut chars = ps1.chars();
+  while let Some(char) = chars.next() {
+    if char != '\\' {
+      prompt.push(char);
+      continue;
+    }
+
+    match chars.next() {
+      Some('w') => prompt.push_str(&paint(Color::Blue, current_dir())),
+      Some('W') => {
+        let dir = current_dir();
+        let name = match dir.as_str() {
+          "/" | "~" => dir.as_str(),
+          dir => dir.rsplit('/').next().unwrap_or(dir),
+        };
+        prompt.push_str(&paint(Color::Blue, name.to_string()));
+      }
+      Some('u') => prompt.push_str(&env::var("USER").unwrap_or_default()),
+      Some('h') => prompt.push_str(&host_name()),
+      Some('g') => {
+        let dir = env::current_dir().unwrap_or_else(|_| Path::new("/").to_path_buf());
+        if let Some(branch) = git_branch(&dir) {
+          prompt.push_str(&paint(Color::Magenta, branch));
+        }
+      }
+      // Nothing when the local time can't be read
+      Some('t') => prompt.push_str(&local_time().unwrap_or_default()),
+      Some('?') => prompt.push_str(&last_status.to_string()),
+      Some('i') => prompt.push_str(&match last_status {
+        0 => paint(Color::Green, "✓".to_string()),
+        _ => paint(Color::Red, "✗".to_string()),
+      }),
+      Some('$') => prompt.push(if is_root() { '#' } else { '$' }),
+      Some('n') => prompt.push('\n'),
+      Some('e') => prompt.push('\x1b'),
+      Some('\\') => prompt.push('\\'),
+      // Non printing sequence markers, only needed by readline
+      Some('[' | ']') => {}
+      Some(char) => {
+        prompt.push('\\');
+        prompt.push(char);
+      }
+      None => prompt.push('\\'),
+    }
+  }
+
+  prompt
+}
+
+/// Current directory as `PWD` has it, with the home directory replaced by `~`
+fn current_dir() -> String {
+  let dir = env::var("PWD")
+    .ok()
+    .or_else(|| env::current_dir().ok().map(|dir| dir.display().to_string()))
+    .unwrap_or_default();
+
+  match env::var("HOME") {
+    Ok(home) if !home.is_empty() && home != "/" => match dir.strip_prefix(&home) {
+      Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{}", rest),
+      _ => dir,
+    },
+    _ => dir,
+  }
+}
+
+/// Current local time as `HH:MM:SS`
+fn local_time() -> Option<String> {
+  // SAFETY: time accepts a null pointer, localtime_r only writes to the given struct
+  let tm = unsafe {
+    let now = libc::time(ptr::null_mut());
+    let mut tm: libc::tm = mem::zeroed();
+    if libc::localtime_r(&now, &mut tm).is_null() {
+      return None;
+    }
+    tm
+  };
+
+  Some(format!(
+    "{:02}:{:02}:{:02}",
+    tm.tm_hour, tm.tm_min, tm.tm_sec
+  ))
+}
+
+fn is_root() -> bool {
+  // SAFETY: geteuid has no preconditions
+  unsafe { libc::geteuid() == 0 }
+}
+
+fn host_name() -> String {
+  let mut buf = [0 as libc::c_char; 256];
+  // SAFETY: the buffer's length is passed along, the last byte is kept as a nul terminator
+  if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() - 1) } != 0 {
+    return String::new();
+  }
+
+  // SAFETY: gethostname wrote a nul terminated string into the buffer
+  let name = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy();
+  name.split('.').next().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render_escapes() {
+    assert_eq!(render("\\$ ", 0, false).len(), 2);
+    assert_eq!(render("a\\\\b\\nc \\q", 0, false), "a\\b\nc \\q");
+    assert_eq!(render("\\[\\e[1m\\]>", 0, false), "\x1b[1m>");
+    assert_eq!(
+      render("\\w", 0, true),
+      format!("\x1b[34m{}\x1b[0m", current_dir())
+    );
+  }
+
+  #[test]
+  fn test_render_status_and_time() {
+    assert_eq!(render("\\? \\i", 0, false), "0 ✓");
+    assert_eq!(render("\\? \\i", 127, false), "127 ✗");
+    assert_eq!(render("\\i", 1, true), "\x1b[31m✗\x1b[0m");
+
+    let time = render("\\t", 0, false);
+    let parts = time.split(':').collect::<Vec<&str>>();
+    assert_eq!(parts.len(), 3, "{}", time);
+    assert!(parts
+      .iter()
+      .all(|part| part.len() == 2 && part.parse::<u8>().is_ok()));
+  }
+}
@@ -0,0 +1,83 @@
+/// Shell options, toggled with the `set` builtin
+#[derive(Debug, Default)]
+pub struct ShellOptions {
+  /// Aborts the command line when a command fails (`set -e`)
+  pub errexit: bool,
+  /// Prevents `>` from overwriting existing files, `>|` still does (`set -C`)
+  pub noclobber: bool,
+  /// Prints every command to stderr, prefixed with `+ `, before running it (`set -x`)
+  pub xtrace: bool,
+  /// Falls back to fuzzy matching (like `grp` for `grep`) when no command starts with the typed
+  /// text (`set -o fuzzycomplete`)
+  pub fuzzycomplete: bool,
+  /// Prints `no completions` when tab is pressed again on an argument nothing completes
+  /// (`set -o completionhint`)
+  pub completionhint: bool,
+  /// Key bindings used to edit the command line (`set -o emacs` or `set -o vi`)
+  pub editing_mode: EditingMode,
+}
+
+/// Key bindings of the line editor
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum EditingMode {
+  /// Keys are typed in, editing keys (arrows, Home, Delete...) move and delete
+  #[default]
+  Emacs,
+  /// Starts each line in insert mode, as emacs mode, Esc switches to normal mode where keys are
+  /// commands: `h`/`l` move, `i`/`a` insert, `x` deletes...
+  Vi,
+}
+
+impl ShellOptions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Maps a single letter flag (as in `set -e`) to the option's long name
+  pub fn name_of_flag(flag: char) -> Option<&'static str> {
+    match flag {
+      'e' => Some("errexit"),
+      'C' => Some("noclobber"),
+      'x' => Some("xtrace"),
+      _ => None,
+    }
+  }
+
+  /// Sets an option by its long name, as given to `set -o`. Returns false for unknown options.
+  pub fn set(&mut self, name: &str, value: bool) -> bool {
+    match name {
+      "errexit" => self.errexit = value,
+      "noclobber" => self.noclobber = value,
+      "xtrace" => self.xtrace = value,
+      "fuzzycomplete" => self.fuzzycomplete = value,
+      "completionhint" => self.completionhint = value,
+      // One of the editing modes is always on, turning one off turns the other on
+      "emacs" | "vi" => {
+        let mode = match (name, value) {
+          ("emacs", true) | ("vi", false) => EditingMode::Emacs,
+          _ => EditingMode::Vi,
+        };
+        self.editing_mode = mode;
+      }
+      _ => return false,
+    }
+
+    true
+  }
+
+  /// Every option with its state, in the format of `set -o`
+  pub fn list(&self) -> Vec<String> {
+    [
+      ("completionhint", self.completionhint),
+      ("emacs", self.editing_mode == EditingMode::Emacs),
+      ("errexit", self.errexit),
+      ("fuzzycomplete", self.fuzzycomplete),
+      ("noclobber", self.noclobber),
+      ("vi", self.editing_mode == EditingMode::Vi),
+      ("xtrace", self.xtrace),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{:<15}\t{}", name, if *value { "on" } else { "off" }))
+    .collect()
+  }
+}
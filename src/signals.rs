@@ -0,0 +1,101 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Signals known by name, as used by builtins like `kill`
+pub const SIGNALS: [(&str, libc::c_int); 16] = [
+  ("HUP", libc::SIGHUP),
+  ("INT", libc::SIGINT),
+  ("QUIT", libc::SIGQUIT),
+  ("KILL", libc::SIGKILL),
+  ("USR1", libc::SIGUSR1),
+  ("USR2", libc::SIGUSR2),
+  ("PIPE", libc::SIGPIPE),
+  ("ALRM", libc::SIGALRM),
+  ("TERM", libc::SIGTERM),
+  ("CHLD", libc::SIGCHLD),
+  ("CONT", libc::SIGCONT),
+  ("STOP", libc::SIGSTOP),
+  ("TSTP", libc::SIGTSTP),
+  ("TTIN", libc::SIGTTIN),
+  ("TTOU", libc::SIGTTOU),
+  ("WINCH", libc::SIGWINCH),
+];
+
+/// Parses a signal given by number (`9`) or name, with or without the `SIG` prefix (`KILL`,
+/// `SIGKILL`)
+pub fn parse_signal(signal: &str) -> Option<libc::c_int> {
+  if let Ok(number) = signal.parse::<libc::c_int>() {
+    return SIGNALS
+      .iter()
+      .any(|(_, known)| *known == number)
+      .then_some(number);
+  }
+
+  let name = signal.to_uppercase();
+  let name = name.strip_prefix("SIG").unwrap_or(&name);
+  SIGNALS
+    .iter()
+    .find(|(known, _)| *known == name)
+    .map(|(_, number)| *number)
+}
+
+/// Name of a signal, without the `SIG` prefix
+pub fn signal_name(signal: libc::c_int) -> Option<&'static str> {
+  SIGNALS
+    .iter()
+    .find(|(_, number)| *number == signal)
+    .map(|(name, _)| *name)
+}
+
+/// Sends a signal to a process
+pub fn send_signal(pid: i32, signal: libc::c_int) -> io::Result<()> {
+  // SAFETY: kill has no memory safety requirements
+  if unsafe { libc::kill(pid, signal) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+
+  Ok(())
+}
+
+extern "C" fn handle_sigint(_signal: libc::c_int) {
+  INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Ctrl-Z is meant for the foreground child, which is stopped, but not for the shell itself
+extern "C" fn handle_sigtstp(_signal: libc::c_int) {}
+
+/// Installs the shell's signal handlers.
+///
+/// While a child runs, the terminal is in cooked mode and Ctrl-C sends `SIGINT` to the terminal's
+/// foreground process group. That's the child's own group when the shell controls the terminal,
+/// otherwise the shell's. Catching it (instead of ignoring it) keeps the shell alive while
+/// children, whose caught signals are reset to the default on exec, still terminate. Same goes for
+/// Ctrl-Z and `SIGTSTP`, which stops children but not the shell.
+pub fn install_handlers() -> io::Result<()> {
+  install_handler(libc::SIGINT, handle_sigint)?;
+  install_handler(libc::SIGTSTP, handle_sigtstp)
+}
+
+fn install_handler(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) -> io::Result<()> {
+  // SAFETY: handlers only touch atomics, which is async-signal-safe
+  unsafe {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = handler as *const () as libc::sighandler_t;
+    // Restart interrupted syscalls (blocking reads, waits), instead of failing them with EINTR
+    action.sa_flags = libc::SA_RESTART;
+    libc::sigemptyset(&mut action.sa_mask);
+
+    if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+      return Err(io::Error::last_os_error());
+    }
+  }
+
+  Ok(())
+}
+
+/// Returns whether a `SIGINT` was received since the last call, resetting the flag
+pub fn take_interrupted() -> bool {
+  INTERRUPTED.swap(false, Ordering::SeqCst)
+}
@@ -1,5 +1,8 @@
 use crate::trie::Trie;
-use crate::utils::find_all_executables;
+use crate::utils::{display_width, find_all_executables, get_path};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use std::{env, fs, mem};
 
 pub fn setup_cmd_completions() -> Trie {
   let mut completions = Trie::new();
@@ -14,10 +17,300 @@ pub fn setup_cmd_completions() -> Trie {
   completions
 }
 
+/// Files matching a path prefix, like `src/ma` matching `src/main.rs`. Hidden files are only
+/// matched when the prefix's file name starts with a `.` too.
+pub fn file_completions(prefix: &str) -> Vec<String> {
+  // Split the prefix into the directory to list and the start of the file name
+  let (dir, name) = match prefix.rfind('/') {
+    Some(index) => (&prefix[..=index], &prefix[index + 1..]),
+    None => ("", prefix),
+  };
+
+  let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+    return Vec::new();
+  };
+
+  let mut completions = entries
+    .filter_map(|entry| entry.ok())
+    .filter_map(|entry| entry.file_name().into_string().ok())
+    .filter(|file_name| file_name.starts_with(name))
+    .filter(|file_name| !file_name.starts_with('.') || name.starts_with('.'))
+    .map(|file_name| format!("{}{}", dir, file_name))
+    .collect::<Vec<String>>();
+  completions.sort();
+
+  completions
+}
+
+/// Same as `file_completions`, only matching directories, for commands like `cd`
+pub fn dir_completions(prefix: &str) -> Vec<String> {
+  let mut completions = file_completions(prefix);
+  completions.retain(|path| Path::new(path).is_dir());
+  completions
+}
+
+/// Chars with a special meaning to the shell, escaped with a backslash in completed unquoted words
+const SPECIAL_CHARS: &str = " \t\n\\'\"$`&|;<>()*?[]{}!#";
+
+/// The word a command line ends with, the one being completed
+#[derive(Debug, PartialEq)]
+pub struct PartialWord {
+  /// Byte index where the word starts in the line
+  pub start: usize,
+  /// The word with its quotes and backslashes removed
+  pub value: String,
+  /// Quote opened in the word and not closed yet
+  pub open_quote: Option<char>,
+  /// Whether the word is a command name, the first word of the line or after a `|`
+  pub is_command: bool,
+  /// Name of the command the word is an argument of, None when it's the command name itself
+  pub command: Option<String>,
+}
+
+/// Splits the last word off a command line, following the quoting rules of the parser
+pub fn partial_word(line: &str) -> PartialWord {
+  let mut word = PartialWord {
+    start: 0,
+    value: String::new(),
+    open_quote: None,
+    is_command: true,
+    command: None,
+  };
+  // Whether the current word has started, with quotes (`""`) even when its value is empty
+  let mut started = false;
+
+  let mut chars = line.char_indices();
+  while let Some((index, char)) = chars.next() {
+    match (word.open_quote, char) {
+      (Some(quote), char) if char == quote => word.open_quote = None,
+      // Inside double quotes, a backslash only escapes the chars still special there
+      (Some('"'), '\\') => match chars.clone().next() {
+        Some((_, escaped @ ('"' | '\\' | '$' | '`'))) => {
+          chars.next();
+          word.value.push(escaped);
+        }
+        _ => word.value.push('\\'),
+      },
+      (Some(_), char) => word.value.push(char),
+      (None, '\'' | '"') => {
+        word.open_quote = Some(char);
+        started = true;
+      }
+      (None, '\\') => {
+        word.value.extend(chars.next().map(|(_, escaped)| escaped));
+        started = true;
+      }
+      (None, char) if char.is_whitespace() || char == '|' => {
+        if char == '|' {
+          word.is_command = true;
+          word.command = None;
+        } else if started && word.is_command {
+          word.is_command = false;
+          word.command = Some(word.value.clone());
+        }
+        word.start = index + char.len_utf8();
+        word.value.clear();
+        started = false;
+      }
+      (None, char) => {
+        word.value.push(char);
+        started = true;
+      }
+    }
+  }
+
+  word
+}
+
+/// Quotes a completion to be inserted as a word, inside the quote the user opened if any, so the
+/// line parses back to the completion as a single arg (like a file name with spaces)
+pub fn quote_completion(completion: &str, open_quote: Option<char>) -> String {
+  match open_quote {
+    // A single quote can't be escaped in single quotes, the quote is closed around it instead
+    Some('\'') => format!("'{}", completion.replace('\'', "'\\''")),
+    Some(quote) => {
+      let mut quoted = quote.to_string();
+      for char in completion.chars() {
+        if matches!(char, '"' | '\\' | '$' | '`') {
+          quoted.push('\\');
+        }
+        quoted.push(char);
+      }
+      quoted
+    }
+    None => {
+      let mut quoted = String::new();
+      for char in completion.chars() {
+        if SPECIAL_CHARS.contains(char) {
+          quoted.push('\\');
+        }
+        quoted.push(char);
+      }
+      quoted
+    }
+  }
+}
+
+/// Longest prefix shared by all the completions
+pub fn common_prefix(completions: &[String]) -> String {
+  let Some((first, rest)) = completions.split_first() else {
+    return String::new();
+  };
+
+  let mut prefix = first.as_str();
+  for completion in rest {
+    let length = prefix
+      .char_indices()
+      .zip(completion.chars())
+      .find(|((_, a), b)| a != b)
+      .map(|((index, _), _)| index)
+      .unwrap_or(prefix.len().min(completion.len()));
+    prefix = &prefix[..length];
+  }
+
+  prefix.to_string()
+}
+
+/// Lays the completions out in columns fitting the terminal width, filled top to bottom then left
+/// to right as bash lists them. Columns are as wide as the widest completion plus two spaces,
+/// except the last one. A completion wider than the terminal gets a row of its own.
+pub fn format_columns(completions: &[String], terminal_width: usize) -> Vec<String> {
+  let Some(widest) = completions.iter().map(|c| display_width(c)).max() else {
+    return Vec::new();
+  };
+
+  let column_width = widest + 2;
+  let columns = ((terminal_width + 2) / column_width).max(1);
+  let rows = completions.len().div_ceil(columns);
+
+  (0..rows)
+    .map(|row| {
+      let mut line = String::new();
+      let mut previous_width = None;
+      for completion in completions.iter().skip(row).step_by(rows) {
+        // Pads the previous completion to the column width, the last one isn't
+        if let Some(width) = previous_width {
+          line.push_str(&" ".repeat(column_width - width));
+        }
+        line.push_str(completion);
+        previous_width = Some(display_width(completion));
+      }
+      line
+    })
+    .collect()
+}
+
+/// Command completions cache, scanning every `PATH` directory is slow so the trie is only rebuilt
+/// when `PATH` changes or the cache is explicitly invalidated.
+#[derive(Default, Debug)]
+pub struct CmdCompletions {
+  trie: Option<Trie>,
+  // `PATH` value the cached trie was built from
+  path: Option<String>,
+  // Set by `invalidate`, the trie saved across sessions isn't trusted either
+  rescan: bool,
+}
+
+impl CmdCompletions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Get the completions trie, rebuilding it if it is stale
+  pub fn get(&mut self) -> &mut Trie {
+    let path = get_path();
+    if self.path != path {
+      self.trie = None;
+    }
+
+    self.path = path.clone();
+    let rescan = mem::take(&mut self.rescan);
+    self.trie.get_or_insert_with(|| {
+      let stamp = path_stamp(path.as_deref().unwrap_or_default());
+      let cached = if rescan {
+        None
+      } else {
+        load_cached_trie(&stamp)
+      };
+      cached.unwrap_or_else(|| {
+        let trie = setup_cmd_completions();
+        save_cached_trie(&stamp, &trie);
+        trie
+      })
+    })
+  }
+
+  /// Drops the cached trie, so the next `get` rescans `PATH` (e.g. after new executables are
+  /// installed into a directory already on `PATH`)
+  pub fn invalidate(&mut self) {
+    self.trie = None;
+    self.rescan = true;
+  }
+}
+
+/// File the completions trie is cached in across shell sessions, under `$XDG_CACHE_HOME` or
+/// `~/.cache`
+fn cache_file() -> Option<PathBuf> {
+  let cache_dir = match env::var("XDG_CACHE_HOME") {
+    Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+    _ => PathBuf::from(env::var("HOME").ok()?).join(".cache"),
+  };
+
+  Some(cache_dir.join("codecrafters-shell").join("completions"))
+}
+
+/// Identifies the executables a trie was built from: `PATH` along with the modification time of
+/// each of its directories, which changes when executables are added or removed
+fn path_stamp(path: &str) -> String {
+  let mut stamp = path.to_string();
+  for dir in path.split(':') {
+    let modified = fs::metadata(dir)
+      .and_then(|metadata| metadata.modified())
+      .ok()
+      .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+      .map(|duration| duration.as_nanos())
+      .unwrap_or_default();
+    stamp.push_str(&format!("\n{}", modified));
+  }
+
+  stamp
+}
+
+/// Loads the cached trie, if it was built for the same stamp. The cache file is the stamp's length
+/// (u32, little endian), the stamp and then the serialized trie.
+fn load_cached_trie(stamp: &str) -> Option<Trie> {
+  let bytes = fs::read(cache_file()?).ok()?;
+  let len = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+  let cached_stamp = bytes.get(4..4 + len)?;
+  if cached_stamp != stamp.as_bytes() {
+    return None;
+  }
+
+  Trie::from_bytes(&bytes[4 + len..])
+}
+
+/// Writes the trie to the cache file, failures only cost a rebuild next time so they're ignored
+fn save_cached_trie(stamp: &str, trie: &Trie) {
+  let Some(file) = cache_file() else {
+    return;
+  };
+
+  let mut bytes = (stamp.len() as u32).to_le_bytes().to_vec();
+  bytes.extend(stamp.as_bytes());
+  bytes.extend(trie.to_bytes());
+
+  if let Some(dir) = file.parent() {
+    let _ = fs::create_dir_all(dir);
+  }
+  let _ = fs::write(file, bytes);
+}
+
 #[derive(Default, Debug)]
 pub struct TabCompletionsCtx {
   enabled: bool,
   pub completions: Vec<String>,
+  // Whether the last tab found nothing to complete
+  missed: bool,
 }
 
 impl TabCompletionsCtx {
@@ -28,6 +321,7 @@ impl TabCompletionsCtx {
   pub fn reset(&mut self) {
     self.enabled = false;
     self.completions.clear();
+    self.missed = false;
   }
 
   pub fn enable(&mut self) {
@@ -41,4 +335,124 @@ impl TabCompletionsCtx {
   pub fn is_enabled(&self) -> bool {
     self.enabled
   }
+
+  /// Records that a tab found nothing to complete, until the next key other than tab
+  pub fn miss(&mut self) {
+    self.missed = true;
+  }
+
+  pub fn is_missed(&self) -> bool {
+    self.missed
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_temp_dir;
+
+  #[test]
+  fn test_file_completions() {
+    let dir = test_temp_dir("file_completions");
+    for name in ["main.rs", "mod.rs", ".hidden", "lib.rs"] {
+      fs::write(dir.join(name), "").unwrap();
+    }
+    let prefix = format!("{}/", dir.display());
+
+    assert_eq!(
+      file_completions(&format!("{}m", prefix)),
+      vec![format!("{}main.rs", prefix), format!("{}mod.rs", prefix)]
+    );
+    assert_eq!(file_completions(&prefix).len(), 3);
+    assert_eq!(
+      file_completions(&format!("{}.", prefix)),
+      vec![format!("{}.hidden", prefix)]
+    );
+    assert!(file_completions(&format!("{}missing/", prefix)).is_empty());
+  }
+
+  #[test]
+  fn test_partial_word() {
+    let word = partial_word("cat my\\ fi");
+    assert_eq!((word.start, word.value.as_str()), (4, "my fi"));
+    assert!(!word.is_command);
+
+    let word = partial_word("ls 'a b' \"it's \\\"x");
+    assert_eq!(word.start, 9);
+    assert_eq!(word.value, "it's \"x");
+    assert_eq!(word.open_quote, Some('"'));
+
+    assert!(partial_word("ech").is_command);
+    assert!(partial_word("ls | gr").is_command);
+    assert!(!partial_word("ls ").is_command);
+    assert_eq!(partial_word("ls ").value, "");
+
+    assert_eq!(partial_word("cd  src/a").command.as_deref(), Some("cd"));
+    assert_eq!(
+      partial_word("ls -l | cd a b").command.as_deref(),
+      Some("cd")
+    );
+    assert_eq!(partial_word("ls -l | c").command, None);
+  }
+
+  #[test]
+  fn test_dir_completions() {
+    let dir = test_temp_dir("dir_completions");
+    fs::create_dir(dir.join("src")).unwrap();
+    fs::write(dir.join("setup.rs"), "").unwrap();
+    let prefix = format!("{}/s", dir.display());
+
+    assert_eq!(file_completions(&prefix).len(), 2);
+    assert_eq!(
+      dir_completions(&prefix),
+      vec![format!("{}/src", dir.display())]
+    );
+  }
+
+  #[test]
+  fn test_quote_completion() {
+    assert_eq!(
+      quote_completion("my file (1).txt", None),
+      "my\\ file\\ \\(1\\).txt"
+    );
+    assert_eq!(
+      quote_completion("a \"b\" $c", Some('"')),
+      "\"a \\\"b\\\" \\$c"
+    );
+    assert_eq!(quote_completion("it's", Some('\'')), "'it'\\''s");
+  }
+
+  #[test]
+  fn test_common_prefix() {
+    let completions = ["src/main.rs", "src/mod.rs"].map(String::from);
+    assert_eq!(common_prefix(&completions), "src/m");
+    assert_eq!(common_prefix(&completions[..1]), "src/main.rs");
+    assert_eq!(common_prefix(&[]), "");
+  }
+
+  #[test]
+  fn test_format_columns() {
+    let completions = ["a", "bbb", "cc", "dddd", "e"].map(String::from);
+    // 3 columns of 6 fit in 16, the last one without its padding
+    assert_eq!(
+      format_columns(&completions, 16),
+      vec!["a     cc    e", "bbb   dddd"]
+    );
+    assert_eq!(
+      format_columns(&completions, 80),
+      vec!["a     bbb   cc    dddd  e"]
+    );
+    assert_eq!(
+      format_columns(&completions, 10),
+      vec!["a     dddd", "bbb   e", "cc"]
+    );
+
+    // Too wide for the terminal, each completion gets its own row
+    let completions = ["long_name", "other_long_name"].map(String::from);
+    assert_eq!(
+      format_columns(&completions, 8),
+      vec!["long_name", "other_long_name"]
+    );
+    assert!(format_columns(&[], 80).is_empty());
+  }
 }
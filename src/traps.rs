@@ -0,0 +1,39 @@
+use crate::utils::shell_quote;
+use std::collections::HashMap;
+
+/// Commands registered with the `trap` builtin, by event: `EXIT` or a signal name without the
+/// `SIG` prefix (like `INT`)
+#[derive(Debug, Default)]
+pub struct Traps {
+  handlers: HashMap<String, String>,
+}
+
+impl Traps {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn get(&self, event: &str) -> Option<&String> {
+    self.handlers.get(event)
+  }
+
+  pub fn set(&mut self, event: &str, command: &str) {
+    self.handlers.insert(event.to_string(), command.to_string());
+  }
+
+  pub fn remove(&mut self, event: &str) -> Option<String> {
+    self.handlers.remove(event)
+  }
+
+  /// Every handler as the `trap` command registering it, like `trap -- 'echo bye' EXIT`
+  pub fn list(&self) -> Vec<String> {
+    let mut handlers = self
+      .handlers
+      .iter()
+      .map(|(event, command)| format!("trap -- {} {}", shell_quote(command), event))
+      .collect::<Vec<String>>();
+    handlers.sort();
+
+    handlers
+  }
+}
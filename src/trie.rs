@@ -4,6 +4,8 @@ use std::ops::Deref;
 #[derive(Clone, Debug)]
 pub struct Trie {
   root: TrieNode,
+  // Number of words, kept up to date on insert and remove
+  len: usize,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -26,40 +28,88 @@ impl Trie {
   pub fn new() -> Self {
     Self {
       root: TrieNode::new(),
+      len: 0,
     }
   }
 
+  /// Number of words in the trie, prefixes that aren't words themselves aren't counted
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
   pub fn insert<T: AsRef<str>>(&mut self, word: T) {
     let str = word.as_ref();
-    let len = str.len();
+    if str.is_empty() {
+      return;
+    }
+
     let mut node = &mut self.root;
-    for (index, char) in str.char_indices() {
-      let is_end = index + 1 == len;
+    for char in str.chars() {
       // Get new or existing entry
       node = node.children.entry(char).or_default();
-      // Update is_end, after new or exiting entry is extracted
-      node.is_end = node.is_end || is_end;
     }
+    // Mark the last char's node as a word end (byte offsets can't be used for this, as multibyte
+    // chars never satisfy `index + 1 == len`)
+    if !node.is_end {
+      node.is_end = true;
+      self.len += 1;
+    }
+  }
+
+  /// Removes a word, pruning the nodes no other word goes through. Returns whether the word was in
+  /// the trie.
+  pub fn remove<T: AsRef<str>>(&mut self, word: T) -> bool {
+    let chars = word.as_ref().chars().collect::<Vec<char>>();
+    if chars.is_empty() || !Self::remove_from(&mut self.root, &chars) {
+      return false;
+    }
+
+    self.len -= 1;
+    true
+  }
+
+  fn remove_from(node: &mut TrieNode, chars: &[char]) -> bool {
+    let Some((char, rest)) = chars.split_first() else {
+      // Reached the word's last node
+      let was_end = node.is_end;
+      node.is_end = false;
+      return was_end;
+    };
+
+    let Some(child) = node.children.get_mut(char) else {
+      return false;
+    };
+    let removed = Self::remove_from(child, rest);
+
+    // Nodes left without words under them are dropped
+    if removed && !child.is_end && child.is_leaf() {
+      node.children.remove(char);
+    }
+
+    removed
   }
 
   pub fn search<T: AsRef<str>>(&mut self, word: T) -> bool {
     let str = word.as_ref();
-    let len = str.len();
+    if str.is_empty() {
+      return false;
+    }
+
     let mut node = &mut self.root;
-    for (index, char) in str.char_indices() {
+    for char in str.chars() {
       match node.children.get_mut(&char) {
         Some(n) => node = n,
         None => {
           return false;
         }
       }
-
-      if index + 1 == len && node.is_end {
-        return true;
-      }
     }
 
-    false
+    node.is_end
   }
 
   pub fn starts_with<T: AsRef<str>>(&mut self, prefix: T) -> bool {
@@ -113,6 +163,137 @@ impl Trie {
     }
   }
 
+  /// Iterates over every word in the trie, in sorted order. The traversal keeps its own stack
+  /// rather than recursing, so long words can't overflow the call stack.
+  pub fn words(&self) -> impl Iterator<Item = String> + '_ {
+    let mut stack = vec![(String::new(), &self.root)];
+
+    std::iter::from_fn(move || {
+      while let Some((word, node)) = stack.pop() {
+        // Children are pushed in reverse, so the smallest char is visited first
+        let mut children = node.children.iter().collect::<Vec<(&char, &TrieNode)>>();
+        children.sort_by(|(a, _), (b, _)| b.cmp(a));
+        for (char, child) in children {
+          stack.push((format!("{}{}", word, char), child));
+        }
+
+        // A word is yielded before the longer words it prefixes
+        if node.is_end {
+          return Some(word);
+        }
+      }
+
+      None
+    })
+  }
+
+  /// Words containing the query's chars in order (like `grp` in `grep`), best matches first.
+  /// Matches are ranked by `fuzzy_score`, then shorter words first.
+  pub fn fuzzy_completions<T: AsRef<str>>(&self, query: T) -> Vec<String> {
+    let query = query.as_ref();
+    if query.is_empty() {
+      return Vec::new();
+    }
+
+    let mut matches = self
+      .words()
+      .filter_map(|word| Self::fuzzy_score(&word, query).map(|score| (score, word)))
+      .collect::<Vec<(i32, String)>>();
+    // `words` is sorted, so the sort being stable keeps equally good matches alphabetical
+    matches.sort_by(|(a_score, a), (b_score, b)| {
+      b_score
+        .cmp(a_score)
+        .then(a.chars().count().cmp(&b.chars().count()))
+    });
+
+    matches.into_iter().map(|(_, word)| word).collect()
+  }
+
+  /// Scores how well a word matches the query as a subsequence, None if it doesn't. Chars matched
+  /// right after the previous match and a match at the word's start score higher, every skipped
+  /// char lowers the score.
+  fn fuzzy_score(word: &str, query: &str) -> Option<i32> {
+    let mut word_chars = word.chars().enumerate();
+    let mut previous: Option<usize> = None;
+    let mut score = 0;
+
+    for query_char in query.chars() {
+      let (index, _) = word_chars.find(|(_, char)| *char == query_char)?;
+
+      score += match previous {
+        Some(previous) if index == previous + 1 => 5,
+        None if index == 0 => 10,
+        _ => 0,
+      };
+      score -= (index - previous.map_or(0, |previous| previous + 1)) as i32;
+      previous = Some(index);
+    }
+
+    Some(score)
+  }
+
+  /// Serializes the trie into bytes, restored with `from_bytes`.
+  ///
+  /// Nodes are written depth first: the `is_end` flag (1 byte), the number of children (u32), then
+  /// every child as its char (u32) followed by the child's node. Numbers are little endian.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    Self::write_node(&self.root, &mut bytes);
+    bytes
+  }
+
+  fn write_node(node: &TrieNode, bytes: &mut Vec<u8>) {
+    bytes.push(node.is_end as u8);
+    bytes.extend((node.children.len() as u32).to_le_bytes());
+    for (char, child) in node.children.iter() {
+      bytes.extend((*char as u32).to_le_bytes());
+      Self::write_node(child, bytes);
+    }
+  }
+
+  /// Restores a trie serialized with `to_bytes`, None if the bytes are malformed
+  pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+    let mut bytes = bytes.iter().copied();
+    let mut len = 0;
+    let root = Self::read_node(&mut bytes, &mut len)?;
+
+    // Trailing bytes mean the data isn't a serialized trie
+    if bytes.next().is_some() {
+      return None;
+    }
+
+    Some(Self { root, len })
+  }
+
+  fn read_node(bytes: &mut impl Iterator<Item = u8>, words: &mut usize) -> Option<TrieNode> {
+    let is_end = match bytes.next()? {
+      0 => false,
+      1 => true,
+      _ => return None,
+    };
+    if is_end {
+      *words += 1;
+    }
+
+    let len = Self::read_u32(bytes)?;
+    let mut children = HashMap::new();
+    for _ in 0..len {
+      let char = char::from_u32(Self::read_u32(bytes)?)?;
+      children.insert(char, Self::read_node(bytes, words)?);
+    }
+
+    Some(TrieNode { children, is_end })
+  }
+
+  fn read_u32(bytes: &mut impl Iterator<Item = u8>) -> Option<u32> {
+    let mut buf = [0u8; 4];
+    for byte in buf.iter_mut() {
+      *byte = bytes.next()?;
+    }
+
+    Some(u32::from_le_bytes(buf))
+  }
+
   /// Finds the longest common prefix path starting from a given prefix.
   ///
   /// This function takes a prefix string and finds the longest unambiguous path in the trie
@@ -143,7 +324,7 @@ impl Trie {
   /// ```
   pub fn longest_common_prefix<T: AsRef<str>>(&mut self, prefix: T) -> String {
     let str = prefix.as_ref();
-    let mut new_prefix = String::from(str);
+    let new_prefix = String::from(str);
 
     // Handle empty prefix case
     if str.is_empty() {
@@ -152,55 +333,40 @@ impl Trie {
 
     // First phase: Navigate to the node corresponding to the input prefix
     // If we can't find the prefix in the trie, return empty string
-    let mut prefix_node = {
-      let mut node = &mut self.root;
-      for char in str.chars() {
-        match node.children.get_mut(&char) {
-          Some(n) => node = n,
-          None => {
-            // Prefix doesn't exist in the trie
-            return String::new();
-          }
-        };
-      }
-      node
-    };
+    let mut prefix_node = &self.root;
+    for char in str.chars() {
+      match prefix_node.children.get(&char) {
+        Some(n) => prefix_node = n,
+        None => {
+          // Prefix doesn't exist in the trie
+          return String::new();
+        }
+      };
+    }
 
     // Second phase: Continue traversing as long as there's only one unambiguous path
-    loop {
-      match prefix_node.children.len() {
-        // Case 1: Leaf node - we've reached the end of a word
-        // Case 2: Multiple children - we've reached a branching point
-        // In both cases, we return the current prefix as the LCP
-        n if n == 0 || n > 1 => {
-          return new_prefix;
-        }
-        // Case 3: Single child but current node is a word end
-        // This means we've reached a complete word that is also a prefix of a longer word
-        // We return the current prefix as the LCP to prioritize the complete word
-        1 if prefix_node.is_end => {
-          return new_prefix;
-        }
-        // Case 4: Single child and not a word end
-        // We can continue extending the prefix along this unambiguous path
-        1 => {
-          // Get the only child node and its character
-          let (char, node) = prefix_node.children.iter_mut().next().unwrap();
-          // Move to the child node
-          prefix_node = node;
-          // Extend the prefix with the new character
-          new_prefix.push(*char);
-          // Continue the loop to check the next level
-          continue;
-        }
-        // This case should never be reached due to the exhaustive matching above
-        _ => {
-          break;
-        }
-      }
+    Self::extend_unambiguous(prefix_node, new_prefix)
+  }
+
+  /// Longest prefix shared by every word, descending from the root while there's a single
+  /// unambiguous path (`ssh` for `ssh` and `ssh-add`). Empty for an empty trie, or when the words
+  /// don't all start with the same char.
+  pub fn longest_common_prefix_all(&self) -> String {
+    Self::extend_unambiguous(&self.root, String::new())
+  }
+
+  /// Extends the prefix leading to a node with the chars of its only child, and so on, until a
+  /// word ends or the path branches
+  fn extend_unambiguous(mut node: &TrieNode, mut prefix: String) -> String {
+    // A word ending here is a complete word that's also a prefix of longer words, it's returned
+    // as is to prioritize it. Leaves and branching points end the path too.
+    while node.children.len() == 1 && !node.is_end {
+      let (char, child) = node.children.iter().next().unwrap();
+      prefix.push(*char);
+      node = child;
     }
 
-    new_prefix
+    prefix
   }
 }
 
@@ -262,6 +428,75 @@ mod tests {
     assert!(t.starts_with("car"));
   }
 
+  #[test]
+  fn test_len_counts_words_only() {
+    let mut t = Trie::new();
+    assert!(t.is_empty());
+
+    t.insert("car");
+    t.insert("card");
+    t.insert("care");
+    // Duplicates and empty strings aren't new words
+    t.insert("car");
+    t.insert("");
+    // "ca" is only a prefix
+    assert_eq!(t.len(), 3);
+    assert!(!t.is_empty());
+
+    assert!(!t.remove("ca"));
+    assert!(!t.remove("cards"));
+    assert_eq!(t.len(), 3);
+
+    // Removing a word keeps the longer ones going through it
+    assert!(t.remove("car"));
+    assert!(!t.remove("car"));
+    assert_eq!(t.len(), 2);
+    assert!(!t.search("car"));
+    assert!(t.search("card"));
+
+    // Removing the last words prunes every node
+    assert!(t.remove("card"));
+    assert!(t.remove("care"));
+    assert!(t.is_empty());
+    assert!(!t.starts_with("c"));
+
+    // The count survives serialization
+    t.insert("a");
+    t.insert("ab");
+    assert_eq!(Trie::from_bytes(&t.to_bytes()).unwrap().len(), 2);
+  }
+
+  #[test]
+  fn test_words() {
+    let mut t = Trie::new();
+    assert_eq!(t.words().count(), 0);
+
+    for word in ["care", "car", "bat", "card", "ba", "car", "école"] {
+      t.insert(word);
+    }
+    assert_eq!(
+      t.words().collect::<Vec<String>>(),
+      vec!["ba", "bat", "car", "card", "care", "école"]
+    );
+  }
+
+  #[test]
+  fn test_fuzzy_completions() {
+    let mut t = Trie::new();
+    for word in ["grep", "egrep", "gpg-agent", "git-receive-pack", "ls"] {
+      t.insert(word);
+    }
+
+    // Early and contiguous matches rank first
+    assert_eq!(
+      t.fuzzy_completions("grp"),
+      vec!["grep", "egrep", "git-receive-pack"]
+    );
+    assert_eq!(t.fuzzy_completions("gpg")[0], "gpg-agent");
+    assert!(t.fuzzy_completions("xyz").is_empty());
+    assert!(t.fuzzy_completions("").is_empty());
+  }
+
   #[test]
   fn test_get_completions() {
     let mut t = Trie::new();
@@ -306,6 +541,33 @@ mod tests {
     assert!(empty_completions.is_empty());
   }
 
+  #[test]
+  fn test_bytes_round_trip() {
+    let mut t = Trie::new();
+    for word in ["car", "card", "care", "cat", "café", "dog"] {
+      t.insert(word);
+    }
+
+    let mut restored = Trie::from_bytes(&t.to_bytes()).unwrap();
+
+    // Prefixes that aren't words must stay that way
+    for word in [
+      "car", "card", "care", "cat", "café", "dog", "ca", "caf", "do", "cars",
+    ] {
+      assert_eq!(restored.search(word), t.search(word), "search {}", word);
+    }
+
+    let mut expected = t.get_completions("ca");
+    let mut completions = restored.get_completions("ca");
+    expected.sort();
+    completions.sort();
+    assert_eq!(completions, expected);
+
+    // Malformed data is rejected
+    assert!(Trie::from_bytes(&[]).is_none());
+    assert!(Trie::from_bytes(&t.to_bytes()[1..]).is_none());
+  }
+
   #[test]
   fn test_longest_common_prefix() {
     let mut t = Trie::new();
@@ -319,6 +581,25 @@ mod tests {
     assert_eq!(t.longest_common_prefix("xyz_foo_bar_"), "xyz_foo_bar_baz");
   }
 
+  #[test]
+  fn test_longest_common_prefix_all() {
+    let mut t = Trie::new();
+    assert_eq!(t.longest_common_prefix_all(), "");
+
+    t.insert("ssh");
+    t.insert("ssh-add");
+    assert_eq!(t.longest_common_prefix_all(), "ssh");
+
+    t.remove("ssh");
+    assert_eq!(t.longest_common_prefix_all(), "ssh-add");
+
+    t.insert("scp");
+    assert_eq!(t.longest_common_prefix_all(), "s");
+
+    t.insert("grep");
+    assert_eq!(t.longest_common_prefix_all(), "");
+  }
+
   #[test]
   fn test_lcp_empty_trie() {
     let mut t = Trie::new();
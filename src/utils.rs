@@ -1,18 +1,24 @@
 use is_executable::IsExecutable;
-use std::{env, fs, path::Path};
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::Duration;
+use std::{env, fs, io, mem, path::Path, ptr};
 
 pub fn get_path() -> Option<String> {
-  return match env::var("PATH") {
-    Ok(path) => Some(path),
-    Err(_) => {
-      return None;
-    }
-  };
+  env::var("PATH").ok()
 }
 
+/// `PATH` used by `command -p`, finding the standard utilities whatever `PATH` is set to
+pub const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin";
+
 pub fn find_command(command: &str) -> Option<String> {
-  let path = get_path()?;
+  find_command_in(command, &get_path()?)
+}
 
+/// Same as `find_command`, looking in the given `PATH`-like list of directories
+pub fn find_command_in(command: &str, path: &str) -> Option<String> {
   for dir in path.split(":") {
     let executable_path_str = format!("{}/{}", dir, command);
 
@@ -25,13 +31,40 @@ pub fn find_command(command: &str) -> Option<String> {
   None
 }
 
-pub fn find_all_executables() -> Vec<String> {
-  let mut executables = Vec::new();
+/// Same as `find_command`, but returns every `PATH` location holding an executable with the given
+/// name, in `PATH` order.
+pub fn find_all_command_paths(command: &str) -> Vec<String> {
   let path = match get_path() {
     Some(path) => path,
-    None => return executables,
+    None => return Vec::new(),
   };
 
+  path
+    .split(":")
+    .map(|dir| format!("{}/{}", dir, command))
+    .filter(|executable_path| {
+      let executable_path = Path::new(executable_path);
+      executable_path.exists() && executable_path.is_executable()
+    })
+    .collect()
+}
+
+pub fn find_all_executables() -> Vec<String> {
+  match get_path() {
+    Some(path) => find_all_executables_in(&path),
+    None => Vec::new(),
+  }
+}
+
+/// Lists the names of all executables in the given `PATH`-like list of directories.
+///
+/// Names are unique, a name found in multiple directories is only listed once, in the position of
+/// its earliest directory, to match command lookup precedence. Entries that can't be read are
+/// skipped.
+pub fn find_all_executables_in(path: &str) -> Vec<String> {
+  let mut executables = Vec::new();
+  let mut seen: HashSet<String> = HashSet::new();
+
   for dir in path.split(":") {
     let items = match fs::read_dir(dir) {
       Ok(items) => items,
@@ -39,9 +72,21 @@ pub fn find_all_executables() -> Vec<String> {
     };
 
     for item in items {
-      let item = item.ok().unwrap().path();
-      if item.is_executable() {
-        let file_name = item.file_name().unwrap().to_str().unwrap().to_string();
+      let item = match item {
+        Ok(item) => item.path(),
+        Err(_) => continue,
+      };
+
+      if !item.is_executable() {
+        continue;
+      }
+
+      let file_name = match item.file_name().and_then(|name| name.to_str()) {
+        Some(file_name) => file_name.to_string(),
+        None => continue,
+      };
+
+      if seen.insert(file_name.clone()) {
         executables.push(file_name);
       }
     }
@@ -50,31 +95,359 @@ pub fn find_all_executables() -> Vec<String> {
   executables
 }
 
+/// Interprets backslash escape sequences the way `echo -e` does.
+///
+/// Supported sequences are `\\`, `\a`, `\b`, `\e`, `\f`, `\n`, `\r`, `\t`, `\v`, `\0nnn` (octal)
+/// and `\xHH` (hex). Unknown sequences are kept as is, backslash included.
+///
+/// # Returns
+///
+/// A tuple of the interpreted string and a flag telling whether `\c` was encountered, in which case
+/// the string is cut at that point and no further output (not even a trailing newline) should be
+/// produced.
+pub fn interpret_escapes(input: &str) -> (String, bool) {
+  let mut output = String::new();
+  let mut chars = input.chars().peekable();
+
+  while let Some(char) = chars.next() {
+    if char != '\\' {
+      output.push(char);
+      continue;
+    }
+
+    match chars.next() {
+      Some('\\') => output.push('\\'),
+      Some('a') => output.push('\x07'),
+      Some('b') => output.push('\x08'),
+      Some('c') => return (output, true),
+      Some('e') | Some('E') => output.push('\x1b'),
+      Some('f') => output.push('\x0c'),
+      Some('n') => output.push('\n'),
+      Some('r') => output.push('\r'),
+      Some('t') => output.push('\t'),
+      Some('v') => output.push('\x0b'),
+      Some('0') => {
+        let mut value = 0u32;
+        for _ in 0..3 {
+          match chars.peek().and_then(|c| c.to_digit(8)) {
+            Some(digit) => {
+              value = value * 8 + digit;
+              chars.next();
+            }
+            None => break,
+          }
+        }
+        output.push(char::from_u32(value).unwrap_or_default());
+      }
+      Some('x') => {
+        let mut value = 0u32;
+        let mut digits = 0;
+        while digits < 2 {
+          match chars.peek().and_then(|c| c.to_digit(16)) {
+            Some(digit) => {
+              value = value * 16 + digit;
+              digits += 1;
+              chars.next();
+            }
+            None => break,
+          }
+        }
+
+        if digits == 0 {
+          // Not a hex escape, keep it literally
+          output.push_str("\\x");
+        } else {
+          output.push(char::from_u32(value).unwrap_or_default());
+        }
+      }
+      Some(other) => {
+        output.push('\\');
+        output.push(other);
+      }
+      None => output.push('\\'),
+    }
+  }
+
+  (output, false)
+}
+
+/// Expands a leading `~` to the home directory (`$HOME`) and `~user` to that user's home directory,
+/// as found in the passwd database. Paths that can't be expanded are returned as is, same as bash.
 pub fn expand_tilda(path: &&str) -> String {
-  match env::var("HOME") {
-    Ok(home_path) => path.replace("~", &home_path),
-    Err(_) => String::from(*path),
+  let Some(rest) = path.strip_prefix('~') else {
+    return String::from(*path);
+  };
+
+  let (user, rest) = match rest.find('/') {
+    Some(index) => rest.split_at(index),
+    None => (rest, ""),
+  };
+
+  let home = if user.is_empty() {
+    env::var("HOME").ok()
+  } else {
+    user_home_dir(user)
+  };
+
+  match home {
+    Some(home) => format!("{}{}", home, rest),
+    None => String::from(*path),
+  }
+}
+
+/// Resolves a path against `cwd` textually, without touching the filesystem: `.` components and
+/// repeated slashes are dropped, and `..` removes the component before it (`a/..` is the directory
+/// `a` was in, even if `a` is a symlink). The result is absolute, without trailing slash.
+pub fn canonicalize_logical(path: &str, cwd: &str) -> String {
+  let full_path = match path.starts_with('/') {
+    true => path.to_string(),
+    false => format!("{}/{}", cwd, path),
+  };
+
+  let mut components: Vec<&str> = Vec::new();
+  for component in full_path.split('/') {
+    match component {
+      "" | "." => {}
+      ".." => {
+        components.pop();
+      }
+      component => components.push(component),
+    }
   }
+
+  format!("/{}", components.join("/"))
 }
 
-/// Splits a vector of strings into groups based on a delimiter.
+/// Resolves a path against `cwd` as the filesystem does, following symlinks (`a/..` is the parent
+/// of the directory `a` points to). Fails if the path doesn't exist.
+pub fn canonicalize_physical(path: &str, cwd: &str) -> io::Result<String> {
+  let path = Path::new(cwd).join(path);
+  Ok(fs::canonicalize(path)?.display().to_string())
+}
+
+/// Looks up a user's home directory in the passwd database
+pub fn user_home_dir(user: &str) -> Option<String> {
+  let name = CString::new(user).ok()?;
+  // SAFETY: passwd is plain data, filled in by getpwnam_r
+  let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+  let mut buf = vec![0 as libc::c_char; 4096];
+  let mut result: *mut libc::passwd = ptr::null_mut();
+
+  // SAFETY: every pointer is valid for the duration of the call, with the buffer's actual length
+  let code = unsafe {
+    libc::getpwnam_r(
+      name.as_ptr(),
+      &mut passwd,
+      buf.as_mut_ptr(),
+      buf.len(),
+      &mut result,
+    )
+  };
+  if code != 0 || result.is_null() {
+    return None;
+  }
+
+  // SAFETY: on success pw_dir points to a nul terminated string inside buf
+  let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+  Some(dir.to_string_lossy().into_owned())
+}
+
+/// Message of an IO error without the `(os error N)` suffix, like `No such file or directory`
+pub fn io_error_message(err: &io::Error) -> String {
+  let message = err.to_string();
+  match message.find(" (os error") {
+    Some(end) => message[..end].to_string(),
+    None => message,
+  }
+}
+
+/// Exit status as seen by `$?`, the exit code or 128 plus the signal that terminated the process
+pub fn status_code(status: ExitStatus) -> i32 {
+  match (status.code(), status.signal()) {
+    (Some(code), _) => code,
+    (None, Some(signal)) => 128 + signal,
+    (None, None) => 0,
+  }
+}
+
+/// Formats a duration the way the `time` keyword reports it, as minutes and seconds (`0m1.234s`)
+pub fn format_duration(duration: Duration) -> String {
+  let millis = duration.as_millis();
+  format!(
+    "{}m{}.{:03}s",
+    millis / 60_000,
+    millis % 60_000 / 1000,
+    millis % 1000
+  )
+}
+
+/// User and system CPU time used so far by the shell and its waited for children
+pub fn cpu_times() -> (Duration, Duration) {
+  let to_duration =
+    |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+
+  let mut user = Duration::ZERO;
+  let mut system = Duration::ZERO;
+  for who in [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+    // SAFETY: rusage is plain data, which getrusage fills in
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    if unsafe { libc::getrusage(who, &mut usage) } == 0 {
+      user += to_duration(usage.ru_utime);
+      system += to_duration(usage.ru_stime);
+    }
+  }
+
+  (user, system)
+}
+
+/// Current git branch of the repository holding the directory, found by walking up to the nearest
+/// `.git` and reading its `HEAD`. A detached `HEAD` gives the commit's short hash. None outside of
+/// a repository.
+pub fn git_branch(dir: &Path) -> Option<String> {
+  let git_dir = dir.ancestors().find_map(|dir| {
+    let git = dir.join(".git");
+    if git.is_dir() {
+      return Some(git);
+    }
+
+    // Worktrees and submodules have a `.git` file pointing to the actual git dir
+    let content = fs::read_to_string(&git).ok()?;
+    let git_dir = content.strip_prefix("gitdir:")?.trim();
+    Some(dir.join(git_dir))
+  })?;
+
+  let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+  let head = head.trim();
+  match head.strip_prefix("ref:") {
+    Some(reference) => {
+      let reference = reference.trim();
+      let branch = reference.strip_prefix("refs/heads/").unwrap_or(reference);
+      Some(branch.to_string())
+    }
+    None => Some(head.chars().take(7).collect()),
+  }
+}
+
+/// Number of terminal columns a char takes: 2 for wide chars (CJK, Hangul, fullwidth forms,
+/// emoji), 0 for combining marks and control chars, 1 otherwise
+pub fn char_width(char: char) -> usize {
+  match char as u32 {
+    0x00..=0x1F | 0x7F..=0x9F => 0,
+    // Combining marks, zero width space and joiners, variation selectors
+    0x0300..=0x036F
+    | 0x1AB0..=0x1AFF
+    | 0x1DC0..=0x1DFF
+    | 0x200B..=0x200F
+    | 0x20D0..=0x20FF
+    | 0xFE00..=0xFE0F
+    | 0xFE20..=0xFE2F => 0,
+    0x1100..=0x115F
+    | 0x2E80..=0x303E
+    | 0x3041..=0x33FF
+    | 0x3400..=0x4DBF
+    | 0x4E00..=0x9FFF
+    | 0xA000..=0xA4CF
+    | 0xAC00..=0xD7A3
+    | 0xF900..=0xFAFF
+    | 0xFE30..=0xFE4F
+    | 0xFF00..=0xFF60
+    | 0xFFE0..=0xFFE6
+    | 0x1F300..=0x1F64F
+    | 0x1F900..=0x1F9FF
+    | 0x20000..=0x2FFFD
+    | 0x30000..=0x3FFFD => 2,
+    _ => 1,
+  }
+}
+
+/// Number of terminal columns a string takes, see `char_width`
+pub fn display_width(text: &str) -> usize {
+  text.chars().map(char_width).sum()
+}
+
+/// Quotes a word with single quotes when it would otherwise not be read back as a single word, as
+/// bash does when printing commands (`set -x`) and variables (`set`)
+pub fn shell_quote(word: &str) -> String {
+  let is_plain = !word.is_empty()
+    && word
+      .chars()
+      .all(|c| c.is_alphanumeric() || "_-+=/.,:@%^".contains(c));
+
+  if is_plain {
+    return word.to_string();
+  }
+
+  format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// Removes every trailing newline (and carriage return), as command substitution does with the
+/// captured output. Newlines inside the text are kept.
+pub fn trim_trailing_newlines(text: &str) -> &str {
+  text.trim_end_matches(['\n', '\r'])
+}
+
+/// Splits a value into fields on the `IFS` chars, as unquoted expansions and `read` do.
+///
+/// Runs of whitespace separators count as one, while each other separator ends a field, even an
+/// empty one (`a::b` with `IFS=:` is `a`, an empty field and `b`). Fields are pushed to `fields`,
+/// except the last one which is left in `field`: it's continued by text following an expansion.
+/// `field` can already hold the start of the first field.
+///
+/// Once there are `max_fields - 1` fields, the rest of the value, separators included, is the
+/// last field, like the last of `read`'s variables.
+pub fn split_fields(
+  value: &str,
+  ifs: &str,
+  max_fields: usize,
+  field: &mut String,
+  fields: &mut Vec<String>,
+) {
+  // Whether the current field was just ended by whitespace, which a following separator is part of
+  let mut after_whitespace = false;
+
+  for (index, char) in value.char_indices() {
+    let starts_field = field.is_empty() && !(ifs.contains(char) && char.is_whitespace());
+    if starts_field && fields.len() + 1 >= max_fields {
+      field.push_str(&value[index..]);
+      return;
+    }
+
+    if !ifs.contains(char) {
+      field.push(char);
+      after_whitespace = false;
+    } else if char.is_whitespace() {
+      if !field.is_empty() {
+        fields.push(mem::take(field));
+        after_whitespace = true;
+      }
+    } else {
+      if !(after_whitespace && field.is_empty()) {
+        fields.push(mem::take(field));
+      }
+      after_whitespace = false;
+    }
+  }
+}
+
+/// Splits a vector into groups at the given positions.
 ///
 /// # Arguments
 ///
-/// * `vec` - A vector of strings to be split into groups
-/// * `delimiter` - The string value that acts as a separator between groups
+/// * `vec` - A vector of items to be split into groups
+/// * `delimiters` - Indices of the items that act as separators between groups, only these are,
+///   an item with the same value elsewhere is kept in its group
 ///
 /// # Returns
 ///
-/// A vector of string vectors where each inner vector represents a group of strings
-/// that were separated by the delimiter in the original vector.
+/// A vector of vectors where each inner vector represents a group of items
+/// that were separated by the delimiters in the original vector.
 /// Empty groups (those with no elements between delimiters) are not included in the result.
-pub fn split_vec_by_delimiter(vec: Vec<String>, delimiter: &str) -> Vec<Vec<String>> {
+pub fn split_vec_at<T>(vec: Vec<T>, delimiters: &[usize]) -> Vec<Vec<T>> {
   let mut result = Vec::new();
   let mut current_group = Vec::new();
 
-  for item in vec {
-    if item == delimiter {
+  for (index, item) in vec.into_iter().enumerate() {
+    if delimiters.contains(&index) {
       if !current_group.is_empty() {
         result.push(current_group);
         current_group = Vec::new();
@@ -91,3 +464,167 @@ pub fn split_vec_by_delimiter(vec: Vec<String>, delimiter: &str) -> Vec<Vec<Stri
 
   result
 }
+
+/// Creates an empty directory, unique to this test process, under the system temp dir
+#[cfg(test)]
+pub fn test_temp_dir(name: &str) -> std::path::PathBuf {
+  let dir = env::temp_dir().join(format!("shell-test-{}-{}", std::process::id(), name));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+  dir
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::fs::PermissionsExt;
+
+  fn create_executable(dir: &Path, name: &str) {
+    let file_path = dir.join(name);
+    fs::write(&file_path, "#!/bin/sh\n").unwrap();
+    fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755)).unwrap();
+  }
+
+  #[test]
+  fn test_find_all_executables_dedupes_names() {
+    let first = test_temp_dir("executables-first");
+    let second = test_temp_dir("executables-second");
+    create_executable(&first, "dup");
+    create_executable(&second, "dup");
+    create_executable(&second, "only_second");
+    // Not executable, must not be listed
+    fs::write(second.join("plain"), "").unwrap();
+
+    let path = format!("{}:{}:/non/existent", first.display(), second.display());
+    let mut executables = find_all_executables_in(&path);
+    executables.sort();
+
+    assert_eq!(executables, vec!["dup", "only_second"]);
+
+    fs::remove_dir_all(first).unwrap();
+    fs::remove_dir_all(second).unwrap();
+  }
+
+  #[test]
+  fn test_interpret_escapes() {
+    assert_eq!(interpret_escapes("a\\tb\\n"), ("a\tb\n".to_string(), false));
+    assert_eq!(
+      interpret_escapes("back\\\\slash"),
+      ("back\\slash".to_string(), false)
+    );
+    assert_eq!(interpret_escapes("\\x41\\0102"), ("AB".to_string(), false));
+    assert_eq!(
+      interpret_escapes("keep \\q"),
+      ("keep \\q".to_string(), false)
+    );
+    assert_eq!(interpret_escapes("stop\\chere"), ("stop".to_string(), true));
+  }
+
+  #[test]
+  fn test_trim_trailing_newlines() {
+    assert_eq!(trim_trailing_newlines("/path\n\n\r\n"), "/path");
+    assert_eq!(trim_trailing_newlines("a\n\nb \n"), "a\n\nb ");
+    assert_eq!(trim_trailing_newlines("\n\n"), "");
+  }
+
+  #[test]
+  fn test_split_fields_with_max() {
+    let split = |value: &str, ifs: &str, max_fields: usize| {
+      let mut field = String::new();
+      let mut fields = Vec::new();
+      split_fields(value, ifs, max_fields, &mut field, &mut fields);
+      fields.push(field);
+      fields
+    };
+
+    assert_eq!(split("  a  b c  ", " \t\n", 2), vec!["a", "b c  "]);
+    assert_eq!(split("a b", " ", 3), vec!["a", "b"]);
+    assert_eq!(split("a::b", ":", 2), vec!["a", ":b"]);
+    assert_eq!(split("a::b", ":", 3), vec!["a", "", "b"]);
+    assert_eq!(split("one", " ", 1), vec!["one"]);
+  }
+
+  #[test]
+  fn test_format_duration() {
+    assert_eq!(format_duration(Duration::from_millis(1234)), "0m1.234s");
+    assert_eq!(format_duration(Duration::from_millis(61_005)), "1m1.005s");
+    assert_eq!(format_duration(Duration::ZERO), "0m0.000s");
+  }
+
+  #[test]
+  fn test_canonicalize_logical() {
+    assert_eq!(
+      canonicalize_logical("a/./b/../c", "/home/user"),
+      "/home/user/a/c"
+    );
+    assert_eq!(canonicalize_logical("/usr//bin/", "/home"), "/usr/bin");
+    assert_eq!(canonicalize_logical("//", "/home"), "/");
+    assert_eq!(canonicalize_logical("../../..", "/home/user"), "/");
+    assert_eq!(canonicalize_logical("", "/tmp/"), "/tmp");
+    assert_eq!(canonicalize_logical("./", "/"), "/");
+  }
+
+  #[test]
+  fn test_canonicalize_physical() {
+    let dir = test_temp_dir("canonicalize");
+    fs::create_dir(dir.join("real")).unwrap();
+    std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+    let dir = fs::canonicalize(dir).unwrap().display().to_string();
+
+    // `..` goes up from where the link points to, unlike the logical resolution
+    assert_eq!(canonicalize_physical("link/..", &dir).unwrap(), dir);
+    assert_eq!(
+      canonicalize_physical("link/", &dir).unwrap(),
+      format!("{}/real", dir)
+    );
+    assert_eq!(canonicalize_logical("link/..", &dir), dir);
+    assert!(canonicalize_physical("missing", &dir).is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_git_branch() {
+    let repo = test_temp_dir("git-branch");
+    let nested = repo.join("src/deep");
+    fs::create_dir_all(&nested).unwrap();
+    fs::create_dir(repo.join(".git")).unwrap();
+
+    fs::write(repo.join(".git/HEAD"), "ref: refs/heads/feature/x\n").unwrap();
+    assert_eq!(git_branch(&nested).as_deref(), Some("feature/x"));
+
+    fs::write(repo.join(".git/HEAD"), "0123456789abcdef\n").unwrap();
+    assert_eq!(git_branch(&repo).as_deref(), Some("0123456"));
+
+    // A worktree's `.git` file points to its git dir
+    let worktree = test_temp_dir("git-branch-worktree");
+    fs::write(
+      worktree.join(".git"),
+      format!("gitdir: {}\n", repo.join(".git").display()),
+    )
+    .unwrap();
+    assert_eq!(git_branch(&worktree).as_deref(), Some("0123456"));
+
+    fs::remove_dir_all(repo).unwrap();
+    fs::remove_dir_all(worktree).unwrap();
+  }
+
+  #[test]
+  fn test_display_width() {
+    assert_eq!(char_width('a'), 1);
+    assert_eq!(char_width('é'), 1);
+    assert_eq!(char_width('中'), 2);
+    assert_eq!(char_width('😀'), 2);
+    assert_eq!(char_width('\u{301}'), 0);
+    assert_eq!(display_width("ab中文😀e\u{301}"), 9);
+  }
+
+  #[test]
+  fn test_expand_tilda_user() {
+    let root_home = user_home_dir("root").unwrap();
+    assert_eq!(expand_tilda(&"~root"), root_home);
+    assert_eq!(expand_tilda(&"~root/dir"), format!("{}/dir", root_home));
+    assert_eq!(expand_tilda(&"~no-such-user/dir"), "~no-such-user/dir");
+    assert_eq!(expand_tilda(&"a~b"), "a~b");
+  }
+}
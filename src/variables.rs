@@ -0,0 +1,341 @@
+use crate::arithmetic;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::hash::{BuildHasher, Hasher};
+use std::process;
+
+/// Field separators used when `IFS` is unset
+const DEFAULT_IFS: &str = " \t\n";
+
+/// Values available to `$` expansion, on top of the environment variables.
+#[derive(Debug, Default)]
+pub struct Variables {
+  /// Exit status of the last executed command, exposed as `$?`
+  pub last_status: i32,
+  /// Positional parameters, `$0` (the shell or script name) then the arguments `$1`, `$2`...
+  pub positional: Vec<String>,
+  /// Number of the input line being run, exposed as `$LINENO`. Counted from 1 in each script,
+  /// sourced ones included.
+  pub line_number: usize,
+  /// Exposed as `$$`, the shells running command substitutions keep their parent's
+  pid: ShellPid,
+  /// Exposed as `$PPID`, as in bash it stays the shell's parent at startup
+  parent_pid: ParentPid,
+  /// Shell variables, set with `NAME=value`. Unlike environment variables, child processes don't
+  /// inherit them until they're exported.
+  shell: HashMap<String, String>,
+  /// Variables declared as integers (`declare -i`), their assigned values are evaluated
+  /// arithmetically
+  integer: HashSet<String>,
+  /// State of the generator behind `$RANDOM`, seeded on first use unless `RANDOM=seed` did
+  random: Cell<Option<u64>>,
+}
+
+/// Pid of the shell's parent, read when the shell's variables are created
+#[derive(Debug)]
+struct ParentPid(libc::pid_t);
+
+impl Default for ParentPid {
+  fn default() -> Self {
+    // SAFETY: getppid has no requirements and always succeeds
+    Self(unsafe { libc::getppid() })
+  }
+}
+
+/// Pid of the shell, read when the shell's variables are created
+#[derive(Debug)]
+struct ShellPid(u32);
+
+impl Default for ShellPid {
+  fn default() -> Self {
+    Self(process::id())
+  }
+}
+
+impl Variables {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Takes the `$$` of the shell whose command substitution this shell runs
+  pub fn inherit_pid(&mut self, pid: u32) {
+    self.pid = ShellPid(pid);
+  }
+
+  /// Resolves a variable by name, special parameters (like `?`) take precedence over the shell
+  /// variables, which take precedence over the environment.
+  pub fn get(&self, name: &str) -> Option<String> {
+    if let Ok(index) = name.parse::<usize>() {
+      return self.positional.get(index).cloned();
+    }
+
+    match name {
+      "?" => Some(self.last_status.to_string()),
+      "$" => Some(self.pid.0.to_string()),
+      "PPID" => Some(self.parent_pid.0.to_string()),
+      "LINENO" => Some(self.line_number.to_string()),
+      "RANDOM" => Some(self.next_random().to_string()),
+      "#" => Some(self.args().len().to_string()),
+      "@" => Some(self.args().join(" ")),
+      // Joined with the first `IFS` char, without separator when `IFS` is empty
+      "*" => {
+        let ifs = self.ifs();
+        let separator = ifs.chars().next().map(String::from).unwrap_or_default();
+        Some(self.args().join(&separator))
+      }
+      name => self
+        .shell
+        .get(name)
+        .cloned()
+        .or_else(|| env::var(name).ok()),
+    }
+  }
+
+  /// Positional parameters after `$0`, the ones `$@` expands to
+  pub fn args(&self) -> &[String] {
+    self.positional.get(1..).unwrap_or_default()
+  }
+
+  /// Replaces the positional parameters after `$0`, as `set -- args` does
+  pub fn set_args(&mut self, args: &[String]) {
+    self.positional.truncate(1);
+    self.positional.extend_from_slice(args);
+  }
+
+  /// Drops the first `count` positional parameters, the next ones are renumbered from `$1`. False,
+  /// leaving them as is, when there are fewer than `count`.
+  pub fn shift(&mut self, count: usize) -> bool {
+    if count > self.args().len() {
+      return false;
+    }
+
+    self.positional.drain(1..1 + count);
+    true
+  }
+
+  /// Chars separating fields, when splitting expansions and the lines read by `read`
+  pub fn ifs(&self) -> String {
+    self.get("IFS").unwrap_or_else(|| DEFAULT_IFS.to_string())
+  }
+
+  /// Next value of `$RANDOM`, between 0 and 32767. A linear congruential generator, seeded from the
+  /// system's entropy, the sequence only repeats after a `RANDOM=seed` assignment.
+  fn next_random(&self) -> u16 {
+    let state = self
+      .random
+      .get()
+      .unwrap_or_else(|| RandomState::new().build_hasher().finish());
+    let state = state
+      .wrapping_mul(6364136223846793005)
+      .wrapping_add(1442695040888963407);
+    self.random.set(Some(state));
+
+    // The high bits of an LCG are the most random ones
+    (state >> 49) as u16
+  }
+
+  /// Sets a variable, in the environment if it's already exported, as a shell variable otherwise.
+  /// Assigning to `RANDOM` reseeds its generator instead, `PPID` and `LINENO` are read-only.
+  pub fn set(&mut self, name: &str, value: &str) {
+    if matches!(name, "PPID" | "LINENO") {
+      return;
+    }
+
+    if name == "RANDOM" {
+      self
+        .random
+        .set(Some(value.trim().parse().unwrap_or_default()));
+    } else if env::var_os(name).is_some() {
+      env::set_var(name, value);
+    } else {
+      self.shell.insert(name.to_string(), value.to_string());
+    }
+  }
+
+  /// Sets a variable as `NAME=value` does: the value of an integer variable is the result of its
+  /// arithmetic evaluation. Returns the evaluation's error message if it fails.
+  pub fn assign(&mut self, name: &str, value: &str) -> Result<(), String> {
+    if !self.integer.contains(name) {
+      self.set(name, value);
+      return Ok(());
+    }
+
+    let value = arithmetic::evaluate(value, self)?;
+    self.set(name, &value.to_string());
+    Ok(())
+  }
+
+  pub fn is_integer(&self, name: &str) -> bool {
+    self.integer.contains(name)
+  }
+
+  /// Gives or removes the integer attribute, existing values aren't evaluated again
+  pub fn set_integer(&mut self, name: &str, integer: bool) {
+    if integer {
+      self.integer.insert(name.to_string());
+    } else {
+      self.integer.remove(name);
+    }
+  }
+
+  pub fn is_exported(&self, name: &str) -> bool {
+    env::var_os(name).is_some()
+  }
+
+  /// Moves a variable into the environment, with the given value or with its shell variable's
+  /// value. Without either, there is nothing to export.
+  pub fn export(&mut self, name: &str, value: Option<&str>) {
+    let shell_value = self.shell.remove(name);
+    if let Some(value) = value.map(String::from).or(shell_value) {
+      env::set_var(name, value);
+    }
+  }
+
+  /// Moves an exported variable back to the shell variables, child processes no longer inherit it
+  pub fn unexport(&mut self, name: &str) {
+    if let Ok(value) = env::var(name) {
+      env::remove_var(name);
+      self.shell.insert(name.to_string(), value);
+    }
+  }
+
+  /// Names of the integer variables, including the ones without a value yet
+  pub fn integer_variables(&self) -> impl Iterator<Item = &String> {
+    self.integer.iter()
+  }
+
+  /// Shell variables, not including the environment ones
+  pub fn shell_variables(&self) -> impl Iterator<Item = (&String, &String)> {
+    self.shell.iter()
+  }
+}
+
+/// Splits a `NAME=value` word into the name and value, None if it isn't an assignment
+pub fn parse_assignment(word: &str) -> Option<(&str, &str)> {
+  let (name, value) = word.split_once('=')?;
+  is_valid_identifier(name).then_some((name, value))
+}
+
+/// Whether the name can be assigned to: letters, digits and underscores, not starting with a digit
+pub fn is_valid_identifier(name: &str) -> bool {
+  let mut chars = name.chars();
+  chars
+    .next()
+    .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+    && chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_shell_variables_and_export() {
+    let mut variables = Variables::new();
+    variables.set("SHELL_TEST_VAR", "shell");
+    assert_eq!(variables.get("SHELL_TEST_VAR").unwrap(), "shell");
+    assert!(env::var("SHELL_TEST_VAR").is_err());
+
+    variables.export("SHELL_TEST_VAR", None);
+    assert_eq!(env::var("SHELL_TEST_VAR").unwrap(), "shell");
+    assert_eq!(variables.shell_variables().count(), 0);
+
+    // Exported variables stay in the environment when set again
+    variables.set("SHELL_TEST_VAR", "updated");
+    assert_eq!(env::var("SHELL_TEST_VAR").unwrap(), "updated");
+    env::remove_var("SHELL_TEST_VAR");
+  }
+
+  #[test]
+  fn test_integer_variables() {
+    let mut variables = Variables::new();
+    variables.set("INT_TEST_BASE", "4");
+    variables.set_integer("INT_TEST_N", true);
+    variables
+      .assign("INT_TEST_N", "INT_TEST_BASE * 2 + 1")
+      .unwrap();
+    assert_eq!(variables.get("INT_TEST_N").unwrap(), "9");
+    assert!(variables.assign("INT_TEST_N", "1 +").is_err());
+    assert_eq!(variables.get("INT_TEST_N").unwrap(), "9");
+
+    variables.set_integer("INT_TEST_N", false);
+    variables.assign("INT_TEST_N", "1 + 1").unwrap();
+    assert_eq!(variables.get("INT_TEST_N").unwrap(), "1 + 1");
+
+    variables.export("INT_TEST_N", None);
+    assert!(variables.is_exported("INT_TEST_N"));
+    variables.unexport("INT_TEST_N");
+    assert!(!variables.is_exported("INT_TEST_N"));
+    assert_eq!(variables.get("INT_TEST_N").unwrap(), "1 + 1");
+  }
+
+  #[test]
+  fn test_positional_parameters() {
+    let mut variables = Variables::new();
+    variables.positional = vec!["shell".to_string()];
+    variables.set_args(&["a".to_string(), "b".to_string(), "c".to_string()]);
+    assert_eq!(variables.get("#").unwrap(), "3");
+    assert_eq!(variables.get("0").unwrap(), "shell");
+
+    assert!(variables.shift(1));
+    assert_eq!(variables.get("1").unwrap(), "b");
+    assert_eq!(variables.get("@").unwrap(), "b c");
+
+    // Shifting past the end leaves the parameters as they are
+    assert!(!variables.shift(3));
+    assert_eq!(variables.args(), ["b", "c"]);
+    assert!(variables.shift(2));
+    assert_eq!(variables.get("#").unwrap(), "0");
+    assert_eq!(variables.get("1"), None);
+    assert!(!variables.shift(1));
+    assert!(variables.shift(0));
+    assert_eq!(variables.get("0").unwrap(), "shell");
+  }
+
+  #[test]
+  fn test_random() {
+    let variables = Variables::new();
+    let values: Vec<u32> = (0..8)
+      .map(|_| variables.get("RANDOM").unwrap().parse().unwrap())
+      .collect();
+    assert!(values.iter().all(|value| *value <= 32767));
+    assert!(values.windows(2).any(|pair| pair[0] != pair[1]));
+
+    // The same seed gives the same sequence
+    let mut variables = Variables::new();
+    variables.set("RANDOM", "42");
+    let first = [variables.get("RANDOM"), variables.get("RANDOM")];
+    variables.set("RANDOM", "42");
+    assert_eq!([variables.get("RANDOM"), variables.get("RANDOM")], first);
+    assert_eq!(variables.shell_variables().count(), 0);
+  }
+
+  #[test]
+  fn test_process_variables() {
+    let mut variables = Variables::new();
+    variables.line_number = 3;
+    assert_eq!(variables.get("LINENO").unwrap(), "3");
+    assert_eq!(variables.get("$").unwrap(), process::id().to_string());
+    // SAFETY: getppid has no requirements and always succeeds
+    let parent_pid = unsafe { libc::getppid() };
+    assert_eq!(variables.get("PPID").unwrap(), parent_pid.to_string());
+
+    // Read-only, assigning them doesn't change what they expand to
+    variables.set("LINENO", "10");
+    variables.set("PPID", "1");
+    assert_eq!(variables.get("LINENO").unwrap(), "3");
+    assert_eq!(variables.shell_variables().count(), 0);
+  }
+
+  #[test]
+  fn test_parse_assignment() {
+    assert_eq!(parse_assignment("FOO=bar"), Some(("FOO", "bar")));
+    assert_eq!(parse_assignment("_a1=x=y"), Some(("_a1", "x=y")));
+    assert_eq!(parse_assignment("EMPTY="), Some(("EMPTY", "")));
+    assert_eq!(parse_assignment("1A=b"), None);
+    assert_eq!(parse_assignment("=b"), None);
+    assert_eq!(parse_assignment("echo"), None);
+  }
+}
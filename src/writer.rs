@@ -1,8 +1,21 @@
-use std::io::{BufReader, Read};
-use std::process::Child;
-use std::sync::Arc;
+use crate::ansi_codes::{colors_enabled, Color};
+use crate::jobs::{wait_pid, WaitStatus};
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ChildStdout};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::{fs::OpenOptions, io, io::Write, thread};
 
+/// How long to wait, after a streamed child exits, for its output to be forwarded
+const READERS_GRACE_PERIOD: Duration = Duration::from_millis(200);
+/// Size of the reads from a child's stream, and most of the output batched into a single write
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
 #[derive(Debug)]
 pub enum CmdOutput {
   Stdout(String),
@@ -10,18 +23,98 @@ pub enum CmdOutput {
   StdoutBytes(Vec<u8>),
   StderrBytes(Vec<u8>),
   Stream(Child),
+  /// Stdout read from a pipe as it's written to, like a piped input passed through by a builtin
+  Pipe(ChildStdout),
 }
 
 #[derive(Debug, Clone)]
 pub enum Redirection {
-  // Redirect stdout output into a file
-  Stdout { file_path: String, append: bool },
-  // Redirect stderr output into a file
-  Stderr { file_path: String, append: bool },
+  // Redirect stdout output into a file, `force` is set by `>|` to overwrite it even with noclobber
+  Stdout {
+    file_path: String,
+    append: bool,
+    force: bool,
+  },
+  // Redirect stderr output into a file, `force` is set by `2>|`
+  Stderr {
+    file_path: String,
+    append: bool,
+    force: bool,
+  },
+  // Redirect both stdout and stderr output into the same file (`&>` or `&>>`)
+  Both {
+    file_path: String,
+    append: bool,
+  },
+  // Print both stdout and stderr output to the terminal, while also writing them into a file
+  Tee {
+    file_path: String,
+    append: bool,
+  },
+  // Open a file on a file descriptor other than stdout and stderr (like `3> file`), set up in the
+  // spawned child. Output of the command itself goes to the terminal. `force` is set by `3>|`
+  Fd {
+    fd: i32,
+    file_path: String,
+    append: bool,
+    force: bool,
+  },
   // print to terminal console
   None,
 }
 
+/// Input fed to a command's stdin, rather than the terminal or the previous command's output
+#[derive(Debug, Clone)]
+pub enum InputRedirection {
+  // Feed a string, plus a newline (`<<< word`)
+  HereString {
+    content: String,
+  },
+  // Feed the lines following the command line, up to a line with just the delimiter
+  // (`<< delimiter`). `<<-` sets `strip_tabs`, removing the lines' leading tabs. With an unquoted
+  // delimiter, `expand` is set: variables and substitutions are expanded in the lines
+  HereDoc {
+    delimiter: String,
+    strip_tabs: bool,
+    expand: bool,
+    content: String,
+  },
+}
+
+impl Redirection {
+  /// Checks that the redirection is allowed to open its file. With noclobber set, `>` (whatever
+  /// the file descriptor) refuses to overwrite an existing regular file, unless forced with `>|`.
+  pub fn check_noclobber(&self, noclobber: bool) -> Result<(), String> {
+    if let Redirection::Stdout {
+      file_path,
+      append: false,
+      force: false,
+    }
+    | Redirection::Stderr {
+      file_path,
+      append: false,
+      force: false,
+    }
+    | Redirection::Fd {
+      file_path,
+      append: false,
+      force: false,
+      ..
+    }
+    | Redirection::Both {
+      file_path,
+      append: false,
+    } = self
+    {
+      if noclobber && fs::metadata(file_path).is_ok_and(|metadata| metadata.is_file()) {
+        return Err(format!("{}: cannot overwrite existing file", file_path));
+      }
+    }
+
+    Ok(())
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct CmdOutputWriter {
   redirection: Redirection,
@@ -34,159 +127,230 @@ impl CmdOutputWriter {
 }
 
 impl CmdOutputWriter {
-  pub fn output(&self, buf: &[u8]) -> bool {
-    let mut written_to_stdout = false;
-    let mut print_to_stdout = || {
+  /// Writes a chunk of stdout output, returning whether it was printed to the terminal. Fails when
+  /// the shell's own stdout is closed, like a pipe whose reader exited.
+  pub fn output(&self, buf: &[u8]) -> io::Result<bool> {
+    let print_to_stdout = || -> io::Result<bool> {
       if buf.is_empty() {
-        return;
+        return Ok(false);
       }
 
-      print!("{}", String::from_utf8_lossy(buf));
-      // print!(
-      //   "{}",
-      //   String::from_utf8_lossy(buf).trim_end_matches([' ', '\n', '\r'])
-      // );
-      io::stdout().flush().unwrap();
-      written_to_stdout = true;
+      write_stream(&mut io::stdout(), String::from_utf8_lossy(buf).as_bytes())?;
+      Ok(true)
     };
 
     match self.redirection.clone() {
-      Redirection::Stdout { file_path, append } => {
-        let file = OpenOptions::new()
-          .write(true)
-          .append(append)
-          .truncate(!append)
-          .create(true)
-          .open(&file_path);
-
-        let write = match file {
-          Ok(mut file) => file.write_all(buf),
-          Err(_) => {
-            eprintln!("Error opening file {}", file_path);
-            return written_to_stdout;
-          }
-        };
-
-        match write {
-          Ok(_) => {}
-          Err(_) => eprintln!("Error writing to {}", file_path),
-        }
+      Redirection::Stdout {
+        file_path, append, ..
+      }
+      | Redirection::Both { file_path, append } => {
+        write_to_file(&file_path, append, buf);
+        Ok(false)
+      }
+      Redirection::Tee { file_path, append } => {
+        write_to_file(&file_path, append, buf);
+        print_to_stdout()
       }
       _ => print_to_stdout(),
     }
-
-    written_to_stdout
   }
 
   pub fn output_string<T: AsRef<str>>(&self, string: T) {
     let string = string.as_ref();
     match self.redirection.clone() {
-      Redirection::Stdout { file_path, append } => {
-        let file = OpenOptions::new()
-          .write(true)
-          .append(append)
-          .truncate(!append)
-          .create(true)
-          .open(&file_path);
-
-        let write = match file {
-          Ok(mut file) => write!(file, "{}", string),
-          Err(_) => {
-            eprintln!("Error opening file {}", file_path);
-            return;
-          }
-        };
-
-        match write {
-          Ok(_) => {}
-          Err(_) => eprintln!("Error writing to {}", file_path),
-        }
+      Redirection::Stdout {
+        file_path, append, ..
+      }
+      | Redirection::Both { file_path, append } => {
+        write_to_file(&file_path, append, string.as_bytes())
+      }
+      // A closed stdout only means nobody reads the output anymore
+      Redirection::Tee { file_path, append } => {
+        let _ = write_stream(&mut io::stdout(), string.as_bytes());
+        write_to_file(&file_path, append, string.as_bytes());
+      }
+      _ => {
+        let _ = write_stream(&mut io::stdout(), string.as_bytes());
       }
-      _ => print!("{}", string),
     }
   }
-  pub fn output_error(&self, buf: &[u8]) -> bool {
-    let mut written_to_stderr = false;
-    let mut print_to_stderr = || {
+
+  /// Same as `output`, for stderr output
+  pub fn output_error(&self, buf: &[u8]) -> io::Result<bool> {
+    let print_to_stderr = || -> io::Result<bool> {
       if buf.is_empty() {
-        return;
+        return Ok(false);
       }
-      // eprint!("{}", String::from_utf8_lossy(buf).trim_end_matches([' ', '\n', '\r']));
-      eprint!("{}", String::from_utf8_lossy(buf));
-      io::stderr().flush().unwrap();
-      written_to_stderr = true;
+
+      write_stream(&mut io::stderr(), String::from_utf8_lossy(buf).as_bytes())?;
+      Ok(true)
     };
 
     match self.redirection.clone() {
-      Redirection::Stderr { file_path, append } => {
-        let file = OpenOptions::new()
-          .write(true)
-          .append(append)
-          .truncate(!append)
-          .create(true)
-          .open(&file_path);
-
-        let write = match file {
-          Ok(mut file) => file.write_all(buf),
-          Err(_) => {
-            eprintln!("Error opening file {}", file_path);
-            return written_to_stderr;
-          }
-        };
-
-        match write {
-          Ok(_) => {}
-          Err(_) => eprintln!("Error writing to {}", file_path),
-        }
+      Redirection::Stderr {
+        file_path, append, ..
+      }
+      | Redirection::Both { file_path, append } => {
+        write_to_file(&file_path, append, buf);
+        Ok(false)
+      }
+      Redirection::Tee { file_path, append } => {
+        write_to_file(&file_path, append, buf);
+        print_to_stderr()
       }
       _ => print_to_stderr(),
     }
-
-    written_to_stderr
   }
 
   pub fn output_error_string<T: AsRef<str>>(&self, string: T) {
     let string = string.as_ref();
+    // Files get the same line as the terminal, so appended errors stay on separate lines
+    let line = format!("{}\n", string);
     match self.redirection.clone() {
-      Redirection::Stderr { file_path, append } => {
-        let file = OpenOptions::new()
-          .write(true)
-          .append(append)
-          .truncate(!append)
-          .create(true)
-          .open(&file_path);
-
-        let write = match file {
-          Ok(mut file) => file.write_all(string.as_bytes()),
-          Err(_) => {
-            eprintln!("Error opening file {}", file_path);
-            return;
-          }
-        };
+      Redirection::Stderr {
+        file_path, append, ..
+      }
+      | Redirection::Both { file_path, append } => {
+        write_to_file(&file_path, append, line.as_bytes())
+      }
+      // A closed stderr is ignored, like when writing command outputs
+      Redirection::Tee { file_path, append } => {
+        let _ = write_stream(&mut io::stderr(), line.as_bytes());
+        write_to_file(&file_path, append, line.as_bytes());
+      }
+      _ if colors_enabled(libc::STDERR_FILENO) => {
+        let painted = format!("{}\n", Color::Red.paint(string));
+        let _ = write_stream(&mut io::stderr(), painted.as_bytes());
+      }
+      _ => {
+        let _ = write_stream(&mut io::stderr(), line.as_bytes());
+      }
+    }
+  }
 
-        match write {
-          Ok(_) => {}
-          Err(_) => eprintln!("Error writing to {}", file_path),
+  /// Returns a writer suited for output written in multiple chunks, like a streamed child's.
+  ///
+  /// Every chunk is written through a separate open of the redirection file, so with truncation on
+  /// each write only the last chunk would be kept. Instead the file is truncated once here (unless
+  /// appending) and the returned writer appends every chunk.
+  fn for_chunks(&self) -> Self {
+    let redirection = match self.redirection.clone() {
+      Redirection::Stdout {
+        file_path,
+        append,
+        force,
+      } => {
+        if !append {
+          write_to_file(&file_path, false, &[]);
+        }
+        Redirection::Stdout {
+          file_path,
+          append: true,
+          force,
         }
       }
-      _ => eprintln!("{}", string),
-    }
+      Redirection::Stderr {
+        file_path,
+        append,
+        force,
+      } => {
+        if !append {
+          write_to_file(&file_path, false, &[]);
+        }
+        Redirection::Stderr {
+          file_path,
+          append: true,
+          force,
+        }
+      }
+      Redirection::Both { file_path, append } => {
+        if !append {
+          write_to_file(&file_path, false, &[]);
+        }
+        Redirection::Both {
+          file_path,
+          append: true,
+        }
+      }
+      Redirection::Tee { file_path, append } => {
+        if !append {
+          write_to_file(&file_path, false, &[]);
+        }
+        Redirection::Tee {
+          file_path,
+          append: true,
+        }
+      }
+      redirection @ (Redirection::Fd { .. } | Redirection::None) => redirection,
+    };
+
+    Self::new(redirection)
   }
 
   fn create_redirection_file(&self) {
     match self.redirection.clone() {
-      Redirection::Stdout { file_path, .. } | Redirection::Stderr { file_path, .. } => {
-        let file = OpenOptions::new().create(true).write(true).open(&file_path);
+      Redirection::Stdout { file_path, .. }
+      | Redirection::Stderr { file_path, .. }
+      | Redirection::Both { file_path, .. }
+      | Redirection::Tee { file_path, .. } => {
+        let file = OpenOptions::new()
+          .create(true)
+          .truncate(false)
+          .write(true)
+          .open(&file_path);
 
         if let Err(err) = file {
           eprintln!("Error opening file {}: {}", file_path, err);
         }
       }
-      Redirection::None => {}
+      Redirection::Fd { .. } | Redirection::None => {}
     }
   }
 
-  pub fn write_cmd_output(&self, cmd_output: CmdOutput) {
+  /// Spawns threads forwarding the child's stdout and stderr through this writer as they are
+  /// produced.
+  ///
+  /// When both streams go to the same file, only whole lines are written (a last line without a
+  /// newline once the stream ends), one thread at a time, so the streams' lines don't get mixed.
+  fn stream_child_output(&self, child: &mut Child) -> (JoinHandle<()>, JoinHandle<()>) {
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let writer = Arc::new(self.clone());
+    let writer_stdout = Arc::clone(&writer);
+    let writer_stderr = Arc::clone(&writer);
+    let line_buffered = matches!(self.redirection, Redirection::Both { .. });
+    let lock = Arc::new(Mutex::new(()));
+    let lock_stdout = Arc::clone(&lock);
+
+    let stdout_handle = thread::spawn(move || {
+      forward_stream(stdout, line_buffered, &lock_stdout, |chunk| {
+        writer_stdout.output(chunk).map(|_| ())
+      })
+    });
+
+    let stderr_handle = thread::spawn(move || {
+      forward_stream(stderr, line_buffered, &lock, |chunk| {
+        writer_stderr.output_error(chunk).map(|_| ())
+      })
+    });
+
+    (stdout_handle, stderr_handle)
+  }
+
+  /// Forwards the output of a child running in the background, without waiting for it to exit.
+  ///
+  /// Returns the child, which the caller is responsible for reaping.
+  pub fn write_background_output(&self, mut child: Child) -> Child {
+    let _handles = self.for_chunks().stream_child_output(&mut child);
+    self.create_redirection_file();
+
+    child
+  }
+
+  /// Writes the output of a command. For a streamed child, waits for it to exit or be stopped
+  /// (Ctrl-Z) and returns that.
+  pub fn write_cmd_output(&self, cmd_output: CmdOutput) -> Option<WaitStatus> {
+    let mut wait_status = None;
     match cmd_output {
       CmdOutput::Stdout(string) => self.output_string(string),
       CmdOutput::StdoutBytes(bytes) => {
@@ -196,79 +360,453 @@ impl CmdOutputWriter {
       CmdOutput::StderrBytes(bytes) => {
         let _ = self.output_error(&bytes);
       }
+      CmdOutput::Pipe(stdout) => {
+        let writer = self.for_chunks();
+        forward_stream(stdout, false, &Mutex::new(()), |chunk| {
+          writer.output(chunk).map(|_| ())
+        });
+      }
       CmdOutput::Stream(mut child) => {
-        // let end_lf = Arc::new(AtomicBool::new(false));
-        // let end_lf_stdout = Arc::clone(&end_lf);
-        // let end_lf_stderr = Arc::clone(&end_lf_stdout);
-        //
-        // let was_written = Arc::new(AtomicBool::new(false));
-        // let was_written_stdout = Arc::clone(&was_written);
-        // let was_written_stderr = Arc::clone(&was_written_stdout);
-
-        // INFO: Inner block to stop the treads when the job handle goes out of scope, so that new
-        // line insertion's order can be guaranteed
-        {
-          let stdout = child.stdout.take().unwrap();
-          let stderr = child.stderr.take().unwrap();
-          let writer = Arc::new(self.clone());
-          let writer_stdout = Arc::clone(&writer);
-          let writer_stderr = Arc::clone(&writer);
-
-          let _stdout_handle = thread::spawn(move || {
-            let mut reader = BufReader::new(stdout);
-            let mut buf = [0u8; 4096];
-            loop {
-              let size = reader.read(&mut buf).unwrap();
-              if size == 0 {
-                break;
-              }
-
-              // if buf[size - 1] == b'\n' {
-              //   end_lf_stdout.store(true, Ordering::SeqCst);
-              // }
-
-              let _written = writer_stdout.output(&buf[..size]);
-              // was_written_stdout.store(written, Ordering::SeqCst);
-            }
-          });
-
-          let _stderr_handle = thread::spawn(move || {
-            let mut reader = BufReader::new(stderr);
-            let mut buf = [0u8; 4096];
-            loop {
-              let size = reader.read(&mut buf).unwrap();
-              if size == 0 {
-                break;
-              }
-
-              // if buf[size - 1] == b'\n' {
-              //   end_lf_stderr.store(true, Ordering::SeqCst);
-              // }
-
-              let _written = writer_stderr.output_error(&buf[..size]);
-              // was_written_stderr.store(written, Ordering::SeqCst);
-            }
-          });
-
-          child.wait().unwrap();
-          // NOTE: don't join the thread handles, as we don't want to wait for thread to complete when program already has
-          // stdout_handle.join().unwrap();
-          // stderr_handle.join().unwrap();
+        let (stdout_handle, stderr_handle) = self.for_chunks().stream_child_output(&mut child);
+
+        let status = wait_pid(child.id() as i32, libc::WUNTRACED).unwrap();
+        // Let the last chunks be written before the next command's output (or before the shell
+        // exits). Not waited on indefinitely, as processes the child left running in the background
+        // can keep its pipes open.
+        if let Some(WaitStatus::Exited(_)) = status {
+          let deadline = Instant::now() + READERS_GRACE_PERIOD;
+          while !(stdout_handle.is_finished() && stderr_handle.is_finished())
+            && Instant::now() < deadline
+          {
+            thread::sleep(Duration::from_millis(1));
+          }
         }
-
-        // if was_written.load(Ordering::SeqCst) && !end_lf.load(Ordering::SeqCst) {
-        //   println!();
-        // }
-        // println!();
+        match status {
+          // Ctrl-C is echoed as `^C` without a line break, start the next prompt on a new line
+          Some(WaitStatus::Exited(status)) if status.signal() == Some(libc::SIGINT) => println!(),
+          // Same for Ctrl-Z and `^Z`
+          Some(WaitStatus::Stopped) => println!(),
+          _ => {}
+        }
+        wait_status = status;
       }
     }
 
     self.create_redirection_file();
+
+    wait_status
+  }
+}
+
+/// Opens the file of an `Fd` redirection, creating it and truncating it unless appending
+pub fn open_fd_redirection(file_path: &str, append: bool) -> io::Result<File> {
+  OpenOptions::new()
+    .write(true)
+    .append(append)
+    .truncate(!append)
+    .create(true)
+    .open(file_path)
+}
+
+/// Reads a child's stream until it's closed, passing what's read to `write` while holding `lock`.
+/// When `line_buffered`, partial lines are held back until their newline is read.
+///
+/// Output is batched: while more of it is already waiting to be read, it's added to the same write,
+/// up to `STREAM_CHUNK_SIZE`. Once the child pauses, what was read is written right away, partial
+/// line (like a prompt) included, so interactive output isn't held back.
+///
+/// A failed write (like a broken pipe) ends the stream: it's closed on this side, so the child gets
+/// a broken pipe too on its next write instead of blocking.
+fn forward_stream(
+  mut stream: impl Read + AsRawFd,
+  line_buffered: bool,
+  lock: &Mutex<()>,
+  mut write: impl FnMut(&[u8]) -> io::Result<()>,
+) {
+  let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+  let mut pending: Vec<u8> = Vec::new();
+  loop {
+    let size = match stream.read(&mut buf) {
+      Ok(0) => break,
+      Ok(size) => size,
+      Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      Err(_) => break,
+    };
+    pending.extend_from_slice(&buf[..size]);
+
+    if pending.len() < STREAM_CHUNK_SIZE && has_input(&stream) {
+      continue;
+    }
+
+    let written = if !line_buffered {
+      let _guard = lock.lock().unwrap();
+      let written = write(&pending);
+      pending.clear();
+      written
+    } else {
+      match pending.iter().rposition(|byte| *byte == b'\n') {
+        Some(last_newline) => {
+          let rest = pending.split_off(last_newline + 1);
+          let _guard = lock.lock().unwrap();
+          let written = write(&pending);
+          pending = rest;
+          written
+        }
+        None => Ok(()),
+      }
+    };
+    if written.is_err() {
+      return;
+    }
+  }
+
+  if !pending.is_empty() {
+    let _guard = lock.lock().unwrap();
+    let _ = write(&pending);
+  }
+}
+
+/// Whether reading the stream wouldn't block, as some of its output (or its end) is waiting
+fn has_input(stream: &impl AsRawFd) -> bool {
+  let mut poll_fd = libc::pollfd {
+    fd: stream.as_raw_fd(),
+    events: libc::POLLIN,
+    revents: 0,
+  };
+
+  // SAFETY: a single valid pollfd is passed, and a zero timeout returns right away
+  unsafe { libc::poll(&mut poll_fd, 1, 0) > 0 }
+}
+
+/// Writes to the shell's stdout or stderr right away
+fn write_stream(stream: &mut impl Write, buf: &[u8]) -> io::Result<()> {
+  stream.write_all(buf)?;
+  stream.flush()
+}
+
+/// Runs `run` with the shell's own stdout and/or stderr sent to sockets, for the output that commands
+/// run by the shell itself (like a sourced file's) write straight to them. Returns `run`'s result,
+/// along with what was written to each captured stream.
+pub fn capture_output<T>(
+  stdout: bool,
+  stderr: bool,
+  run: impl FnOnce() -> T,
+) -> io::Result<(T, Vec<u8>, Vec<u8>)> {
+  io::stdout().flush()?;
+  let stdout_capture = stdout
+    .then(|| StreamCapture::start(libc::STDOUT_FILENO))
+    .transpose()?;
+  let stderr_capture = match stderr
+    .then(|| StreamCapture::start(libc::STDERR_FILENO))
+    .transpose()
+  {
+    Ok(capture) => capture,
+    Err(err) => {
+      stdout_capture.map(StreamCapture::finish);
+      return Err(err);
+    }
+  };
+
+  let result = run();
+  let _ = io::stdout().flush();
+
+  let captured_stdout = stdout_capture
+    .map(StreamCapture::finish)
+    .unwrap_or_default();
+  let captured_stderr = stderr_capture
+    .map(StreamCapture::finish)
+    .unwrap_or_default();
+  Ok((result, captured_stdout, captured_stderr))
+}
+
+/// One of the shell's standard streams, temporarily sent to a socket
+struct StreamCapture {
+  fd: RawFd,
+  /// Where the stream went before
+  saved: OwnedFd,
+  /// Reads the socket as it's written to, so writers never wait for it to be emptied
+  reading: JoinHandle<Vec<u8>>,
+}
+
+impl StreamCapture {
+  fn start(fd: RawFd) -> io::Result<Self> {
+    // SAFETY: the standard streams stay open for the shell's whole life
+    let saved = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    let (mut reader, writer) = UnixStream::pair()?;
+    dup2(writer.as_raw_fd(), fd)?;
+
+    let reading = thread::spawn(move || {
+      let mut bytes = Vec::new();
+      let _ = reader.read_to_end(&mut bytes);
+      bytes
+    });
+    Ok(Self { fd, saved, reading })
+  }
+
+  /// Puts the stream back, which closes the socket, and returns what was written to it
+  fn finish(self) -> Vec<u8> {
+    let _ = dup2(self.saved.as_raw_fd(), self.fd);
+    self.reading.join().unwrap_or_default()
+  }
+}
+
+fn dup2(source: RawFd, target: RawFd) -> io::Result<()> {
+  // SAFETY: dup2 only makes `target` refer to what `source` does, both are open descriptors
+  match unsafe { libc::dup2(source, target) } {
+    -1 => Err(io::Error::last_os_error()),
+    _ => Ok(()),
+  }
+}
+
+/// Writes the buffer to the file, creating it if needed. With `append` the buffer is added after the
+/// existing content as is (bash adds no separator either), otherwise the file is truncated first.
+fn write_to_file(file_path: &str, append: bool, buf: &[u8]) {
+  let file = OpenOptions::new()
+    .write(true)
+    .append(append)
+    .truncate(!append)
+    .create(true)
+    .open(file_path);
+
+  let write = match file {
+    Ok(mut file) => file.write_all(buf),
+    Err(_) => {
+      eprintln!("Error opening file {}", file_path);
+      return;
+    }
+  };
+
+  if write.is_err() {
+    eprintln!("Error writing to {}", file_path);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_temp_dir;
+  use std::process::{Command, Stdio};
+  use std::time::Duration;
+
+  fn stdout_writer(file_path: &str, append: bool) -> CmdOutputWriter {
+    CmdOutputWriter::new(Redirection::Stdout {
+      file_path: file_path.to_string(),
+      append,
+      force: false,
+    })
+  }
+
+  #[test]
+  fn test_repeated_appends() {
+    let dir = test_temp_dir("writer-appends");
+    let file_path = dir.join("out.txt").display().to_string();
+
+    stdout_writer(&file_path, true).output_string("a\n");
+    stdout_writer(&file_path, true).output_string("b\n");
+    stdout_writer(&file_path, true).output(b"c\n").unwrap();
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "a\nb\nc\n");
+
+    // Truncating write replaces previous content
+    stdout_writer(&file_path, false).output_string("d\n");
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "d\n");
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_noclobber() {
+    let dir = test_temp_dir("writer-noclobber");
+    let file_path = dir.join("out.txt").display().to_string();
+    fs::write(&file_path, "kept").unwrap();
+
+    let stderr = |append, force| Redirection::Stderr {
+      file_path: file_path.clone(),
+      append,
+      force,
+    };
+    let fd = |append, force| Redirection::Fd {
+      fd: 3,
+      file_path: file_path.clone(),
+      append,
+      force,
+    };
+    for redirection in [stderr(false, false), fd(false, false)] {
+      assert!(redirection.check_noclobber(true).is_err());
+      assert!(redirection.check_noclobber(false).is_ok());
+    }
+    // Appending, or forced with `>|`
+    for redirection in [
+      stderr(true, false),
+      stderr(false, true),
+      fd(true, false),
+      fd(false, true),
+    ] {
+      assert!(redirection.check_noclobber(true).is_ok());
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_builtin_error_redirected_to_file() {
+    let dir = test_temp_dir("writer-stderr");
+    let file_path = dir.join("err.txt").display().to_string();
+    let stderr_writer = |append| {
+      CmdOutputWriter::new(Redirection::Stderr {
+        file_path: file_path.clone(),
+        append,
+        force: false,
+      })
+    };
+
+    // Like `cd /nope 2> err.txt`, then `2>> err.txt`
+    let error = || CmdOutput::Stderr("cd: /nope: No such file or directory".to_string());
+    stderr_writer(false).write_cmd_output(error());
+    stderr_writer(true).write_cmd_output(error());
+    assert_eq!(
+      fs::read_to_string(&file_path).unwrap(),
+      "cd: /nope: No such file or directory\n".repeat(2)
+    );
+
+    // Stdout isn't redirected along
+    stderr_writer(false).write_cmd_output(CmdOutput::Stdout(String::new()));
+    assert_eq!(fs::read_to_string(&file_path).unwrap().lines().count(), 2);
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_tee_writes_both_streams_to_file() {
+    let dir = test_temp_dir("writer-tee");
+    let file_path = dir.join("out.txt").display().to_string();
+    let writer = CmdOutputWriter::new(Redirection::Tee {
+      file_path: file_path.clone(),
+      append: false,
+    })
+    .for_chunks();
+
+    writer.output(b"out\n").unwrap();
+    writer.output_error(b"err\n").unwrap();
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "out\nerr\n");
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_streamed_output_of_both_streams() {
+    let dir = test_temp_dir("writer-both");
+    let file_path = dir.join("all.log").display().to_string();
+    fs::write(&file_path, "stale content\n").unwrap();
+
+    let child = Command::new("sh")
+      .args([
+        "-c",
+        "for i in 1 2 3; do echo out $i; echo err $i >&2; done",
+      ])
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .unwrap();
+    CmdOutputWriter::new(Redirection::Both {
+      file_path: file_path.clone(),
+      append: false,
+    })
+    .write_cmd_output(CmdOutput::Stream(child));
+
+    // Lines of both streams are kept whole, in each stream's order
+    let content = fs::read_to_string(&file_path).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines.len(), 6);
+    for stream in ["out", "err"] {
+      let stream_lines: Vec<&&str> = lines.iter().filter(|l| l.starts_with(stream)).collect();
+      assert_eq!(
+        stream_lines,
+        [1, 2, 3]
+          .map(|i| format!("{} {}", stream, i))
+          .iter()
+          .collect::<Vec<_>>()
+      );
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_streamed_output_keeps_all_chunks() {
+    let dir = test_temp_dir("writer-stream");
+    let file_path = dir.join("out.txt").display().to_string();
+    fs::write(&file_path, "stale content\n").unwrap();
+
+    let child = Command::new("seq")
+      .args(["1", "20000"])
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .unwrap();
+    stdout_writer(&file_path, false).write_cmd_output(CmdOutput::Stream(child));
+
+    // Reader threads aren't joined, give them a moment to flush the last chunks
+    let expected: String = (1..=20000).map(|n| format!("{}\n", n)).collect();
+    for _ in 0..100 {
+      if fs::read_to_string(&file_path).unwrap() == expected {
+        break;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), expected);
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_streamed_child_exit_status() {
+    let spawn = |program: &str, args: &[&str]| {
+      Command::new(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap()
+    };
+    let status_of = |child: Child| match CmdOutputWriter::new(Redirection::None)
+      .write_cmd_output(CmdOutput::Stream(child))
+    {
+      Some(WaitStatus::Exited(status)) => crate::utils::status_code(status),
+      status => panic!("unexpected wait status: {:?}", status),
+    };
+
+    assert_eq!(status_of(spawn("false", &[])), 1);
+    assert_eq!(status_of(spawn("true", &[])), 0);
+    assert_eq!(status_of(spawn("sh", &["-c", "exit 42"])), 42);
+  }
+
+  #[test]
+  fn test_forward_stream_batches_waiting_output() {
+    // Less than a pipe's capacity, so it's all written before the child exits
+    let mut child = Command::new("seq")
+      .args(["1", "10000"])
+      .stdout(Stdio::piped())
+      .spawn()
+      .unwrap();
+    let stdout = child.stdout.take().unwrap();
+    child.wait().unwrap();
+
+    let mut writes = 0;
+    let mut forwarded = Vec::new();
+    forward_stream(stdout, false, &Mutex::new(()), |chunk| {
+      writes += 1;
+      forwarded.extend_from_slice(chunk);
+      Ok(())
+    });
+
+    assert_eq!(writes, 1);
+    let forwarded = String::from_utf8(forwarded).unwrap();
+    assert_eq!(forwarded.lines().count(), 10000);
+    assert!(forwarded.ends_with("9999\n10000\n"));
   }
 }
 
 // TODO
 // - [ ] open the stdin while writing from child stream, that way ctrl-c or any other keystroke can be listened to.
-// - [ ] kill waiting child process on ctrl-c
-// - [ ] refactor write_cmd_output, stream case, to see if arc usage can minimized
-// - [ ] clean up end_lf, was written, print_to_stdout and print_to_stderr (unwanted code or abstractions)
+// - [ ] refactor stream_child_output to see if arc usage can minimized
+// - [ ] clean up print_to_stdout and print_to_stderr (unwanted code or abstractions)